@@ -1,5 +1,8 @@
 use google_calendar3::{
-    api::{Calendar, CalendarList, Event, Events},
+    api::{
+        Calendar, CalendarList, Colors, Event, Events, FreeBusyRequest, FreeBusyRequestItem,
+        TimePeriod,
+    },
     CalendarHub,
 };
 use google_tasks1::{
@@ -8,44 +11,340 @@ use google_tasks1::{
     hyper_util::{self, client::legacy::connect::HttpConnector},
     Result, TasksHub,
 };
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
+
 use tokio::time::timeout;
 
-use crate::oauth::APPLICATION_SECRET;
+use crate::{metrics::ApiMetrics, oauth::APPLICATION_SECRET};
 
 const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+const TOKEN_FILE_NAME: &str = "google_oauth2_token.json";
+const SCOPES: &[&str] = &[
+    "https://www.googleapis.com/auth/calendar",
+    "https://www.googleapis.com/auth/calendar.events",
+    "https://www.googleapis.com/auth/tasks",
+];
+// enough to list and read everything, for runs which never write
+const READ_ONLY_SCOPES: &[&str] = &[
+    "https://www.googleapis.com/auth/calendar.readonly",
+    "https://www.googleapis.com/auth/tasks.readonly",
+];
 
 pub(super) type SyncToken = String;
 
-pub(crate) struct GoogleClient {
-    calendarhub: CalendarHub<HttpsConnector<HttpConnector>>,
-    taskshub: TasksHub<HttpsConnector<HttpConnector>>,
+/// Directory for persistent state (oauth tokens, the pending write journal), created on first use.
+pub(super) fn state_dir() -> std::path::PathBuf {
+    let dirs = directories::ProjectDirs::from("", "", "orgmode-google-fuse")
+        .expect("Failed to get project directories");
+    let dir = dirs
+        .state_dir()
+        .unwrap_or(std::path::Path::new("~/.local/state/orgmode-google-fuse"))
+        .to_path_buf();
+    std::fs::create_dir_all(&dir).expect("Failed to create state directory");
+    dir
 }
 
-impl GoogleClient {
-    pub async fn new() -> Self {
-        let dirs = directories::ProjectDirs::from("", "", "orgmode-google-fuse")
-            .expect("Failed to get project directories");
-        let authdir = dirs
-            .state_dir()
-            .unwrap_or(std::path::Path::new("~/.local/state/orgmode-google-fuse"));
-        std::fs::create_dir_all(authdir).expect("Failed to create state directory");
-        let auth = yup_oauth2::InstalledFlowAuthenticator::builder(
-            APPLICATION_SECRET.clone(),
-            yup_oauth2::InstalledFlowReturnMethod::HTTPRedirect,
-        )
-        .persist_tokens_to_disk(authdir.join("google_oauth2_token.json"))
-        .build()
-        .await
-        .unwrap();
+/// How the user hands the OAuth consent back to us.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum AuthFlow {
+    /// Google redirects the browser to a local web server
+    #[default]
+    Redirect,
+    /// paste the code shown by Google into the terminal, for headless machines
+    Interactive,
+}
 
-        auth.token(&[
-            "https://www.googleapis.com/auth/calendar",
-            "https://www.googleapis.com/auth/calendar.events",
-            "https://www.googleapis.com/auth/tasks",
-        ])
-        .await
-        .expect("Failed to get OAuth token");
+#[derive(clap::Args, Debug, Clone, Copy, Default)]
+pub(crate) struct AuthOptions {
+    /// how to receive the OAuth consent when signing in
+    #[arg(long, value_enum, default_value_t)]
+    auth_flow: AuthFlow,
+    /// port for the local web server of the redirect flow (random by default)
+    #[arg(long)]
+    auth_port: Option<u16>,
+}
+
+impl AuthOptions {
+    fn return_method(self) -> yup_oauth2::InstalledFlowReturnMethod {
+        match (self.auth_flow, self.auth_port) {
+            (AuthFlow::Interactive, _) => yup_oauth2::InstalledFlowReturnMethod::Interactive,
+            (AuthFlow::Redirect, Some(port)) => {
+                yup_oauth2::InstalledFlowReturnMethod::HTTPPortRedirect(port)
+            }
+            (AuthFlow::Redirect, None) => yup_oauth2::InstalledFlowReturnMethod::HTTPRedirect,
+        }
+    }
+}
+
+/// How a failed request should be handled by its caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ClientError {
+    /// The stored credentials were rejected, so retrying won't help until the user signs in again.
+    Auth,
+    /// Anything else (timeouts, network errors, server errors), which may succeed on retry.
+    Transient,
+}
+
+#[derive(Default)]
+struct AuthState {
+    // whether the user may be asked to sign in; otherwise asking fails the request instead
+    interactive: AtomicBool,
+    // whether a request has needed the user to sign in since the last successful sign-in
+    needed: AtomicBool,
+    // whether stdin and stdout are gone, with the process detached from its terminal
+    detached: AtomicBool,
+}
+
+/// Presents the sign-in URL only when we're ready to wait for the user; a background request
+/// finding its token revoked is failed (and recorded) instead of hanging on the installed flow.
+struct ReauthDelegate(Arc<AuthState>);
+
+impl yup_oauth2::authenticator_delegate::InstalledFlowDelegate for ReauthDelegate {
+    fn present_user_url<'a>(
+        &'a self,
+        url: &'a str,
+        need_code: bool,
+    ) -> Pin<Box<dyn Future<Output = std::result::Result<String, String>> + Send + 'a>> {
+        Box::pin(async move {
+            if !self.0.interactive.load(Ordering::Acquire) {
+                self.0.needed.store(true, Ordering::Release);
+                return Err("re-authentication required".to_owned());
+            }
+            // there's no one to show the URL to, or to redirect or paste a code back
+            if self.0.detached.load(Ordering::Acquire) {
+                self.0.needed.store(true, Ordering::Release);
+                tracing::error!(
+                    %url,
+                    "Signing in to Google again needs a terminal; restart from a terminal to \
+                     sign in"
+                );
+                return Err("re-authentication needs a terminal".to_owned());
+            }
+            if !need_code {
+                println!(
+                    "Please direct your browser to {} and follow the instructions displayed there.",
+                    url
+                );
+                return Ok(String::new());
+            }
+            println!(
+                "Please direct your browser to {}, follow the instructions and enter the code \
+                 displayed here: ",
+                url
+            );
+            let mut code = String::new();
+            tokio::io::AsyncBufReadExt::read_line(
+                &mut tokio::io::BufReader::new(tokio::io::stdin()),
+                &mut code,
+            )
+            .await
+            .map_err(|e| format!("couldn't read code: {}", e))?;
+            Ok(code.trim_end().to_owned())
+        })
+    }
+}
+
+// the HTTP status of a failed request, whether or not it came with a JSON error body
+fn status(e: &google_tasks1::Error) -> Option<u16> {
+    match e {
+        google_tasks1::Error::BadRequest(body) => body["error"]["code"]
+            .as_u64()
+            .and_then(|code| u16::try_from(code).ok()),
+        google_tasks1::Error::Failure(res) => Some(res.status().as_u16()),
+        _ => None,
+    }
+}
+
+/// Whether Google has invalidated the sync token a listing was made with (410 Gone), so the
+/// calendar must be listed in full to get a new one.
+pub(crate) fn is_full_sync_required(e: &google_tasks1::Error) -> bool {
+    status(e) == Some(410)
+}
+
+/// Whether `e` says the calendar or tasklist asked about doesn't exist (any more).
+pub(crate) fn is_not_found(e: &google_tasks1::Error) -> bool {
+    status(e) == Some(404)
+}
+
+// attempts at a call Google rate limits before giving up on it (it stays pending until next sync)
+const MAX_ATTEMPTS: u32 = 3;
+const INITIAL_BACKOFF: std::time::Duration = std::time::Duration::from_millis(500);
+const RECONNECT_AFTER: u32 = 3;
+
+// whether `e` failed to reach Google at all, rather than being an answer from it
+fn is_connection_error(e: &google_tasks1::Error) -> bool {
+    match e {
+        google_tasks1::Error::HttpError(_) => true,
+        google_tasks1::Error::Io(e) => e.kind() == std::io::ErrorKind::TimedOut,
+        _ => false,
+    }
+}
+// don't hold up other writes any longer than this, however long Google asks us to wait
+const MAX_RETRY_WAIT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Notes when Google turns a call away with 429 Too Many Requests or 503 Service Unavailable,
+/// and how long its `Retry-After` header asks us to wait.
+#[derive(Clone, Default)]
+struct RetryAfter(Arc<Mutex<Option<std::time::Duration>>>);
+
+impl RetryAfter {
+    /// How long to wait before retrying: whatever Google asked for, but at least `backoff`; or
+    /// `None` if the call wasn't rate limited, or Google asked for too long.
+    fn wait(&self, backoff: std::time::Duration) -> Option<std::time::Duration> {
+        self.0
+            .lock()
+            .unwrap()
+            .map(|asked| asked.max(backoff))
+            .filter(|&wait| wait <= MAX_RETRY_WAIT)
+    }
+}
+
+impl google_tasks1::Delegate for RetryAfter {
+    fn http_failure(
+        &mut self,
+        response: &google_tasks1::common::Response,
+        _err: Option<&serde_json::Value>,
+    ) -> google_tasks1::common::Retry {
+        let status = response.status().as_u16();
+        if status == 429 || status == 503 {
+            let asked = response
+                .headers()
+                .get(google_tasks1::hyper::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after)
+                .unwrap_or_default();
+            *self.0.lock().unwrap() = Some(asked);
+        }
+        // `call` does the retrying, so that each attempt gets its own timeout
+        google_tasks1::common::Retry::Abort
+    }
+}
+
+// a `Retry-After` value, either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<std::time::Duration> {
+    let value = value.trim();
+    match value.parse() {
+        Ok(secs) => Some(std::time::Duration::from_secs(secs)),
+        Err(_) => {
+            let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+            Some(
+                (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+                    .to_std()
+                    .unwrap_or_default(),
+            )
+        }
+    }
+}
+
+/// The calls the syncing and write paths make to Google, so that they can be run against a fake.
+#[allow(clippy::result_large_err)]
+pub(crate) trait GoogleApi: Sync {
+    /// Whether writes should only be logged rather than sent to Google.
+    fn dry_run(&self) -> bool;
+
+    fn classify(&self, e: &google_tasks1::Error) -> ClientError;
+
+    /// Sign in again, waiting for the user to follow the printed URL if the stored refresh token
+    /// no longer works.
+    fn reauthenticate(&self) -> impl Future<Output = bool> + Send;
+
+    fn list_calendars(&self) -> impl Future<Output = Result<CalendarList>> + Send;
+
+    fn list_events(&self, calendar_id: &str) -> impl Future<Output = Result<Events>> + Send;
+
+    fn list_events_with_sync_token(
+        &self,
+        calendar_id: &str,
+        sync_token: &SyncToken,
+    ) -> impl Future<Output = Result<Events>> + Send;
+
+    /// The times `calendar_id` is busy, for calendars whose events can't be seen; from four
+    /// weeks ago to eight weeks ahead.
+    fn freebusy_query(
+        &self,
+        calendar_id: &str,
+    ) -> impl Future<Output = Result<Vec<TimePeriod>>> + Send;
+
+    fn insert_event(
+        &self,
+        calendar_id: &str,
+        event: Event,
+    ) -> impl Future<Output = Result<Event>> + Send;
+
+    fn patch_event(
+        &self,
+        calendar_id: &str,
+        event_id: &str,
+        event: Event,
+    ) -> impl Future<Output = Result<Event>> + Send;
+
+    fn delete_event(
+        &self,
+        calendar_id: &str,
+        event_id: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    fn list_tasklists(&self) -> impl Future<Output = Result<TaskLists>> + Send;
+
+    fn get_tasklist(&self, tasklist_id: &str) -> impl Future<Output = Result<TaskList>> + Send;
+
+    fn list_tasks(&self, tasklist_id: &str) -> impl Future<Output = Result<Tasks>> + Send;
+
+    /// Tasks modified at or after `since`, including completed, hidden and deleted ones so that
+    /// the changes can be applied to an already-listed tasklist.
+    fn list_tasks_updated_since(
+        &self,
+        tasklist_id: &str,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> impl Future<Output = Result<Tasks>> + Send;
+
+    fn insert_task(
+        &self,
+        tasklist_id: &str,
+        task: Task,
+        new_parent: Option<&str>,
+        new_predecessor: Option<&str>,
+    ) -> impl Future<Output = Result<Task>> + Send;
+
+    fn patch_task(
+        &self,
+        tasklist_id: &str,
+        task_id: &str,
+        task: Task,
+    ) -> impl Future<Output = Result<Task>> + Send;
 
+    fn delete_task(
+        &self,
+        tasklist_id: &str,
+        task_id: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    fn move_task(
+        &self,
+        tasklist_id: &str,
+        task_id: &str,
+        new_parent: Option<&str>,
+        new_predecessor: Option<&str>,
+    ) -> impl Future<Output = Result<Task>> + Send;
+}
+
+type Authenticator = yup_oauth2::authenticator::Authenticator<HttpsConnector<HttpConnector>>;
+
+struct Hubs {
+    calendar: CalendarHub<HttpsConnector<HttpConnector>>,
+    tasks: TasksHub<HttpsConnector<HttpConnector>>,
+}
+
+impl Hubs {
+    // both hubs share a fresh connection pool
+    fn connect(auth: &Authenticator) -> Self {
         let client =
             hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
                 .build(
@@ -56,226 +355,695 @@ impl GoogleClient {
                         .enable_http2()
                         .build(),
                 );
-        let calendarhub = CalendarHub::new(client.clone(), auth.clone());
-        let taskshub = TasksHub::new(client, auth);
         Self {
-            calendarhub,
-            taskshub,
+            calendar: CalendarHub::new(client.clone(), auth.clone()),
+            tasks: TasksHub::new(client, auth.clone()),
         }
     }
+}
 
-    pub async fn list_calendars(&self) -> Result<CalendarList> {
-        timeout(TIMEOUT, self.calendarhub.calendar_list().list().doit())
-            .await
-            .unwrap_or_else(|e| Err(google_calendar3::Error::Io(e.into())))
-            .map(|(_res, calendar_list)| calendar_list)
+pub(crate) struct GoogleClient {
+    // swapped for a new pool after RECONNECT_AFTER connection errors in a row, since one left
+    // holding dead connections (after the machine sleeps, or the network changes) never recovers
+    hubs: Mutex<Arc<Hubs>>,
+    connection_errors: AtomicU32,
+    auth: Authenticator,
+    auth_state: Arc<AuthState>,
+    scopes: &'static [&'static str],
+    dry_run: bool,
+    metrics: ApiMetrics,
+}
+
+#[allow(clippy::result_large_err)]
+impl GoogleClient {
+    /// Fail any later sign-in rather than wait on a terminal that's gone, once the process has
+    /// detached from it.
+    pub(crate) fn detach(&self) {
+        self.auth_state.detached.store(true, Ordering::Release);
+    }
+
+    /// Sign in to Google, asking for access to change calendars and tasks only if `writable`.
+    ///
+    /// A token is stored for each set of scopes, and reused if it was granted at least the scopes
+    /// asked for, so the user is asked to consent once for read-only runs and once more the
+    /// first time writes are needed.
+    pub async fn new(auth_options: AuthOptions, dry_run: bool, writable: bool) -> Self {
+        let authdir = state_dir();
+        let auth_state = Arc::new(AuthState {
+            interactive: AtomicBool::new(true),
+            ..AuthState::default()
+        });
+        let auth = yup_oauth2::InstalledFlowAuthenticator::builder(
+            APPLICATION_SECRET.clone(),
+            auth_options.return_method(),
+        )
+        .persist_tokens_to_disk(authdir.join(TOKEN_FILE_NAME))
+        .flow_delegate(Box::new(ReauthDelegate(auth_state.clone())))
+        .build()
+        .await
+        .unwrap();
+
+        let scopes = if writable { SCOPES } else { READ_ONLY_SCOPES };
+        tracing::info!("Requesting OAuth scopes {}", scopes.join(" "));
+        auth.token(scopes).await.expect("Failed to get OAuth token");
+        auth_state.interactive.store(false, Ordering::Release);
+
+        Self {
+            hubs: Mutex::new(Arc::new(Hubs::connect(&auth))),
+            connection_errors: AtomicU32::new(0),
+            auth,
+            auth_state,
+            scopes,
+            dry_run,
+            metrics: ApiMetrics::default(),
+        }
+    }
+
+    // the tasks of `tasklist_id`, following every page of them: changed at or since `updated_min`
+    // (deleted ones included), if given, so that none are missed after a long time offline.
+    // Completed and hidden tasks are always included: whether they're shown is up to
+    // `--show-completed` and `--show-hidden`, however they were fetched
+    async fn list_task_pages(&self, tasklist_id: &str, updated_min: Option<&str>) -> Result<Tasks> {
+        let mut items = Vec::new();
+        let mut page_token = None::<String>;
+        loop {
+            let (_res, mut page) = self
+                .call(|hubs, mut retry| {
+                    let page_token = page_token.clone();
+                    async move {
+                        let mut call = hubs
+                            .tasks
+                            .tasks()
+                            .list(tasklist_id)
+                            .max_results(100)
+                            .show_completed(true)
+                            .show_deleted(updated_min.is_some())
+                            .show_hidden(true);
+                        if let Some(updated_min) = updated_min {
+                            call = call.updated_min(updated_min);
+                        }
+                        if let Some(page_token) = &page_token {
+                            call = call.page_token(page_token);
+                        }
+                        call.delegate(&mut retry).doit().await
+                    }
+                })
+                .await?;
+            items.extend(page.items.take().unwrap_or_default());
+            match page.next_page_token.take() {
+                Some(next) => page_token = Some(next),
+                None => {
+                    page.items = Some(items);
+                    return Ok(page);
+                }
+            }
+        }
+    }
+
+    // every API call goes through here, to bound each attempt by TIMEOUT and count it, to
+    // retry when Google asks us to slow down, and to reconnect when the connections have gone bad
+    async fn call<T, F: Future<Output = Result<T>>>(
+        &self,
+        mut call: impl FnMut(Arc<Hubs>, RetryAfter) -> F,
+    ) -> Result<T> {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let retry = RetryAfter::default();
+            let hubs = self.hubs.lock().unwrap().clone();
+            let result = timeout(TIMEOUT, call(hubs, retry.clone()))
+                .await
+                .unwrap_or_else(|e| Err(google_tasks1::Error::Io(e.into())));
+            self.metrics.record(result.is_ok());
+            self.track_connection(&result);
+            match retry.wait(backoff) {
+                Some(wait) if result.is_err() && attempt < MAX_ATTEMPTS => {
+                    tracing::warn!("Google is rate limiting us; retrying in {:?}", wait);
+                    tokio::time::sleep(wait).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                _ => return result,
+            }
+        }
+    }
+
+    fn track_connection<T>(&self, result: &Result<T>) {
+        match result {
+            Err(e) if is_connection_error(e) => {
+                let errors = self.connection_errors.fetch_add(1, Ordering::AcqRel) + 1;
+                if errors >= RECONNECT_AFTER {
+                    tracing::warn!(
+                        "{} connection errors in a row; reconnecting to Google",
+                        errors
+                    );
+                    *self.hubs.lock().unwrap() = Arc::new(Hubs::connect(&self.auth));
+                    self.connection_errors.store(0, Ordering::Release);
+                }
+            }
+            // any answer from Google shows the connections are fine
+            _ => self.connection_errors.store(0, Ordering::Release),
+        }
+    }
+
+    pub fn metrics(&self) -> &ApiMetrics {
+        &self.metrics
     }
 
     #[allow(unused)]
     pub async fn get_calendar(&self, calendar_id: &str) -> Result<Calendar> {
-        timeout(
-            TIMEOUT,
-            self.calendarhub.calendars().get(calendar_id).doit(),
-        )
+        self.call(|hubs, mut retry| async move {
+            let call = hubs.calendar.calendars().get(calendar_id);
+            call.delegate(&mut retry).doit().await
+        })
         .await
-        .unwrap_or_else(|e| Err(google_calendar3::Error::Io(e.into())))
         .map(|(_res, calendar)| calendar)
     }
 
-    pub async fn list_events(&self, calendar_id: &str) -> Result<Events> {
-        timeout(
-            TIMEOUT,
-            self.calendarhub
-                .events()
-                .list(calendar_id)
-                .time_min(
-                    // a year ago
-                    chrono::Utc::now()
-                        .checked_sub_signed(chrono::Duration::days(365))
-                        .unwrap(),
-                )
-                .doit(),
-        )
+    #[allow(unused)]
+    pub async fn get_event(&self, calendar_id: &str, event_id: &str) -> Result<Event> {
+        self.call(|hubs, mut retry| async move {
+            let call = hubs.calendar.events().get(calendar_id, event_id);
+            call.delegate(&mut retry).doit().await
+        })
+        .await
+        .map(|(_res, event)| event)
+    }
+
+    #[allow(unused)]
+    pub async fn get_task(&self, tasklist_id: &str, task_id: &str) -> Result<Task> {
+        self.call(|hubs, mut retry| async move {
+            let call = hubs.tasks.tasks().get(tasklist_id, task_id);
+            call.delegate(&mut retry).doit().await
+        })
+        .await
+        .map(|(_res, task)| task)
+    }
+
+    /// The colours a calendar's or an event's colour id stands for.
+    pub async fn list_colors(&self) -> Result<Colors> {
+        self.call(|hubs, mut retry| async move {
+            let call = hubs.calendar.colors().get();
+            call.delegate(&mut retry).doit().await
+        })
+        .await
+        .map(|(_res, colors)| colors)
+    }
+}
+
+#[allow(clippy::result_large_err)]
+impl GoogleApi for GoogleClient {
+    fn classify(&self, e: &google_tasks1::Error) -> ClientError {
+        let rejected = matches!(e, google_tasks1::Error::MissingToken(_)) || status(e) == Some(401);
+        // a token request cut short by the timeout only shows up in the auth state
+        if rejected || self.auth_state.needed.load(Ordering::Acquire) {
+            ClientError::Auth
+        } else {
+            ClientError::Transient
+        }
+    }
+
+    async fn reauthenticate(&self) -> bool {
+        tracing::error!("Google rejected the stored credentials; re-authenticating…");
+        self.auth_state.interactive.store(true, Ordering::Release);
+        let result = self.auth.force_refreshed_token(self.scopes).await;
+        self.auth_state.interactive.store(false, Ordering::Release);
+        match result {
+            Ok(_) => {
+                self.auth_state.needed.store(false, Ordering::Release);
+                tracing::info!("Re-authenticated with Google");
+                true
+            }
+            Err(e) => {
+                tracing::error!(
+                    error = %e,
+                    "Failed to re-authenticate; please delete {} and restart to sign in again",
+                    state_dir().join(TOKEN_FILE_NAME).display()
+                );
+                false
+            }
+        }
+    }
+
+    fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    async fn list_calendars(&self) -> Result<CalendarList> {
+        self.call(|hubs, mut retry| async move {
+            let call = hubs.calendar.calendar_list().list();
+            call.delegate(&mut retry).doit().await
+        })
+        .await
+        .map(|(_res, calendar_list)| calendar_list)
+    }
+
+    async fn list_events(&self, calendar_id: &str) -> Result<Events> {
+        self.call(|hubs, mut retry| async move {
+            let call = hubs.calendar.events().list(calendar_id).time_min(
+                // a year ago
+                chrono::Utc::now()
+                    .checked_sub_signed(chrono::Duration::days(365))
+                    .unwrap(),
+            );
+            call.delegate(&mut retry).doit().await
+        })
         .await
-        .unwrap_or_else(|e| Err(google_calendar3::Error::Io(e.into())))
         .map(|(_res, events)| events)
     }
 
-    pub async fn list_events_with_sync_token(
+    async fn list_events_with_sync_token(
         &self,
         calendar_id: &str,
         sync_token: &SyncToken,
     ) -> Result<Events> {
-        timeout(
-            TIMEOUT,
-            self.calendarhub
+        self.call(|hubs, mut retry| async move {
+            let call = hubs
+                .calendar
                 .events()
                 .list(calendar_id)
-                .sync_token(sync_token)
-                .doit(),
-        )
+                .sync_token(sync_token);
+            call.delegate(&mut retry).doit().await
+        })
         .await
-        .unwrap_or_else(|e| Err(google_calendar3::Error::Io(e.into())))
         .map(|(_res, events)| events)
     }
 
-    #[allow(unused)]
-    pub async fn get_event(&self, calendar_id: &str, event_id: &str) -> Result<Event> {
-        timeout(
-            TIMEOUT,
-            self.calendarhub.events().get(calendar_id, event_id).doit(),
-        )
+    async fn freebusy_query(&self, calendar_id: &str) -> Result<Vec<TimePeriod>> {
+        // freeBusy refuses spans as long as the year `list_events` covers
+        let now = chrono::Utc::now();
+        let request = FreeBusyRequest {
+            items: Some(vec![FreeBusyRequestItem {
+                id: Some(calendar_id.to_owned()),
+            }]),
+            time_min: Some(now - chrono::Duration::weeks(4)),
+            time_max: Some(now + chrono::Duration::weeks(8)),
+            ..FreeBusyRequest::default()
+        };
+        self.call(|hubs, mut retry| {
+            let request = request.clone();
+            async move {
+                let call = hubs.calendar.freebusy().query(request);
+                call.delegate(&mut retry).doit().await
+            }
+        })
         .await
-        .unwrap_or_else(|e| Err(google_calendar3::Error::Io(e.into())))
-        .map(|(_res, event)| event)
+        .map(|(_res, response)| {
+            response
+                .calendars
+                .and_then(|mut calendars| calendars.remove(calendar_id))
+                .and_then(|calendar| calendar.busy)
+                .unwrap_or_default()
+        })
     }
 
-    pub async fn insert_event(&self, calendar_id: &str, event: Event) -> Result<Event> {
-        timeout(
-            TIMEOUT,
-            self.calendarhub.events().insert(event, calendar_id).doit(),
-        )
+    async fn insert_event(&self, calendar_id: &str, event: Event) -> Result<Event> {
+        self.call(|hubs, mut retry| {
+            let event = event.clone();
+            async move {
+                let call = hubs.calendar.events().insert(event, calendar_id);
+                call.delegate(&mut retry).doit().await
+            }
+        })
         .await
-        .unwrap_or_else(|e| Err(google_calendar3::Error::Io(e.into())))
         .map(|(_res, event)| event)
     }
 
-    pub async fn patch_event(
-        &self,
-        calendar_id: &str,
-        event_id: &str,
-        event: Event,
-    ) -> Result<Event> {
-        timeout(
-            TIMEOUT,
-            self.calendarhub
-                .events()
-                .patch(event, calendar_id, event_id)
-                .doit(),
-        )
+    async fn patch_event(&self, calendar_id: &str, event_id: &str, event: Event) -> Result<Event> {
+        self.call(|hubs, mut retry| {
+            let event = event.clone();
+            async move {
+                let call = hubs.calendar.events().patch(event, calendar_id, event_id);
+                call.delegate(&mut retry).doit().await
+            }
+        })
         .await
-        .unwrap_or_else(|e| Err(google_calendar3::Error::Io(e.into())))
         .map(|(_res, event)| event)
     }
 
-    pub async fn delete_event(&self, calendar_id: &str, event_id: &str) -> Result<()> {
-        timeout(
-            TIMEOUT,
-            self.calendarhub
-                .events()
-                .delete(calendar_id, event_id)
-                .doit(),
-        )
+    async fn delete_event(&self, calendar_id: &str, event_id: &str) -> Result<()> {
+        self.call(|hubs, mut retry| async move {
+            let call = hubs.calendar.events().delete(calendar_id, event_id);
+            call.delegate(&mut retry).doit().await
+        })
         .await
-        .unwrap_or_else(|e| Err(google_calendar3::Error::Io(e.into())))
         .map(|_res| ())
     }
 
-    pub async fn list_tasklists(&self) -> Result<TaskLists> {
-        timeout(TIMEOUT, self.taskshub.tasklists().list().doit())
-            .await
-            .unwrap_or_else(|e| Err(google_tasks1::Error::Io(e.into())))
-            .map(|(_res, tasklists)| tasklists)
+    async fn list_tasklists(&self) -> Result<TaskLists> {
+        self.call(|hubs, mut retry| async move {
+            let call = hubs.tasks.tasklists().list();
+            call.delegate(&mut retry).doit().await
+        })
+        .await
+        .map(|(_res, tasklists)| tasklists)
     }
 
-    pub async fn get_tasklist(&self, tasklist_id: &str) -> Result<TaskList> {
-        timeout(TIMEOUT, self.taskshub.tasklists().get(tasklist_id).doit())
-            .await
-            .unwrap_or_else(|e| Err(google_tasks1::Error::Io(e.into())))
-            .map(|(_res, tasklist)| tasklist)
-    }
-
-    pub async fn list_tasks(&self, tasklist_id: &str) -> Result<Tasks> {
-        timeout(
-            TIMEOUT,
-            self.taskshub
-                .tasks()
-                .list(tasklist_id)
-                .max_results(100)
-                .show_deleted(false)
-                .show_hidden(false)
-                .doit(),
-        )
+    async fn get_tasklist(&self, tasklist_id: &str) -> Result<TaskList> {
+        self.call(|hubs, mut retry| async move {
+            let call = hubs.tasks.tasklists().get(tasklist_id);
+            call.delegate(&mut retry).doit().await
+        })
         .await
-        .unwrap_or_else(|e| Err(google_tasks1::Error::Io(e.into())))
-        .map(|(_res, tasks)| tasks)
+        .map(|(_res, tasklist)| tasklist)
     }
 
-    #[allow(unused)]
-    pub async fn get_task(&self, tasklist_id: &str, task_id: &str) -> Result<Task> {
-        timeout(
-            TIMEOUT,
-            self.taskshub.tasks().get(tasklist_id, task_id).doit(),
-        )
-        .await
-        .unwrap_or_else(|e| Err(google_tasks1::Error::Io(e.into())))
-        .map(|(_res, task)| task)
+    async fn list_tasks(&self, tasklist_id: &str) -> Result<Tasks> {
+        self.list_task_pages(tasklist_id, None).await
+    }
+
+    async fn list_tasks_updated_since(
+        &self,
+        tasklist_id: &str,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Tasks> {
+        self.list_task_pages(tasklist_id, Some(&since.to_rfc3339()))
+            .await
     }
 
-    pub async fn insert_task(
+    async fn insert_task(
         &self,
         tasklist_id: &str,
         task: Task,
         new_parent: Option<&str>,
         new_predecessor: Option<&str>,
     ) -> Result<Task> {
-        timeout(TIMEOUT, {
-            let mut call = self.taskshub.tasks().insert(task, tasklist_id);
-            if let Some(new_parent) = new_parent {
-                call = call.parent(new_parent)
+        self.call(|hubs, mut retry| {
+            let task = task.clone();
+            async move {
+                let mut call = hubs.tasks.tasks().insert(task, tasklist_id);
+                if let Some(new_parent) = new_parent {
+                    call = call.parent(new_parent)
+                }
+                if let Some(new_predecessor) = new_predecessor {
+                    call = call.previous(new_predecessor)
+                }
+                call.delegate(&mut retry).doit().await
             }
-            if let Some(new_predecessor) = new_predecessor {
-                call = call.previous(new_predecessor)
-            }
-            call.doit()
         })
         .await
-        .unwrap_or_else(|e| Err(google_tasks1::Error::Io(e.into())))
         .map(|(_res, task)| task)
     }
 
-    pub async fn patch_task(&self, tasklist_id: &str, task_id: &str, task: Task) -> Result<Task> {
-        timeout(
-            TIMEOUT,
-            self.taskshub
-                .tasks()
-                .patch(task, tasklist_id, task_id)
-                .doit(),
-        )
+    async fn patch_task(&self, tasklist_id: &str, task_id: &str, task: Task) -> Result<Task> {
+        self.call(|hubs, mut retry| {
+            let task = task.clone();
+            async move {
+                let call = hubs.tasks.tasks().patch(task, tasklist_id, task_id);
+                call.delegate(&mut retry).doit().await
+            }
+        })
         .await
-        .unwrap_or_else(|e| Err(google_tasks1::Error::Io(e.into())))
         .map(|(_res, task)| task)
     }
 
-    pub async fn delete_task(&self, tasklist_id: &str, task_id: &str) -> Result<()> {
-        timeout(
-            TIMEOUT,
-            self.taskshub.tasks().delete(tasklist_id, task_id).doit(),
-        )
+    async fn delete_task(&self, tasklist_id: &str, task_id: &str) -> Result<()> {
+        self.call(|hubs, mut retry| async move {
+            let call = hubs.tasks.tasks().delete(tasklist_id, task_id);
+            call.delegate(&mut retry).doit().await
+        })
         .await
-        .unwrap_or_else(|e| Err(google_tasks1::Error::Io(e.into())))
         .map(|_res| ())
     }
 
-    pub(crate) async fn move_task(
+    async fn move_task(
         &self,
         tasklist_id: &str,
         task_id: &str,
         new_parent: Option<&str>,
         new_predecessor: Option<&str>,
     ) -> Result<Task> {
-        timeout(TIMEOUT, {
-            let mut call = self.taskshub.tasks().move_(tasklist_id, task_id);
+        self.call(|hubs, mut retry| async move {
+            let mut call = hubs.tasks.tasks().move_(tasklist_id, task_id);
             if let Some(new_parent) = new_parent {
                 call = call.parent(new_parent)
             }
             if let Some(new_predecessor) = new_predecessor {
                 call = call.previous(new_predecessor)
             }
-            call.doit()
+            call.delegate(&mut retry).doit().await
         })
         .await
-        .unwrap_or_else(|e| Err(google_tasks1::Error::Io(e.into())))
         .map(|(_res, task)| task)
     }
 }
+
+/// An in-memory stand-in for Google which records every write it is sent.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct MockClient {
+    pub(crate) writes: std::sync::Mutex<Vec<crate::write::WriteCommand>>,
+}
+
+#[cfg(test)]
+impl GoogleApi for MockClient {
+    fn dry_run(&self) -> bool {
+        false
+    }
+
+    fn classify(&self, _e: &google_tasks1::Error) -> ClientError {
+        ClientError::Transient
+    }
+
+    async fn reauthenticate(&self) -> bool {
+        false
+    }
+
+    async fn list_calendars(&self) -> Result<CalendarList> {
+        Ok(CalendarList::default())
+    }
+
+    async fn list_events(&self, _calendar_id: &str) -> Result<Events> {
+        Ok(Events::default())
+    }
+
+    async fn list_events_with_sync_token(
+        &self,
+        _calendar_id: &str,
+        _sync_token: &SyncToken,
+    ) -> Result<Events> {
+        Ok(Events::default())
+    }
+
+    async fn freebusy_query(&self, _calendar_id: &str) -> Result<Vec<TimePeriod>> {
+        Ok(Vec::new())
+    }
+
+    async fn insert_event(&self, calendar_id: &str, event: Event) -> Result<Event> {
+        use crate::write::{CalendarEventInsert, CalendarEventWrite, WriteCommand};
+
+        let new = Event {
+            id: Some(format!("mock-{}", self.writes.lock().unwrap().len())),
+            ..event.clone()
+        };
+        self.writes
+            .lock()
+            .unwrap()
+            .push(WriteCommand::CalendarEvent {
+                calendar_id: calendar_id.to_owned(),
+                cmd: CalendarEventWrite::Insert(CalendarEventInsert::Insert {
+                    event: Box::new(event),
+                }),
+            });
+        Ok(new)
+    }
+
+    async fn patch_event(&self, calendar_id: &str, event_id: &str, event: Event) -> Result<Event> {
+        use crate::write::{CalendarEventModify, CalendarEventWrite, WriteCommand};
+
+        self.writes
+            .lock()
+            .unwrap()
+            .push(WriteCommand::CalendarEvent {
+                calendar_id: calendar_id.to_owned(),
+                cmd: CalendarEventWrite::Modify {
+                    event_id: event_id.to_owned(),
+                    modification: CalendarEventModify::Patch {
+                        event: Box::new(event.clone()),
+                    },
+                },
+            });
+        Ok(Event {
+            id: Some(event_id.to_owned()),
+            ..event
+        })
+    }
+
+    async fn delete_event(&self, calendar_id: &str, event_id: &str) -> Result<()> {
+        use crate::write::{CalendarEventModify, CalendarEventWrite, WriteCommand};
+
+        self.writes
+            .lock()
+            .unwrap()
+            .push(WriteCommand::CalendarEvent {
+                calendar_id: calendar_id.to_owned(),
+                cmd: CalendarEventWrite::Modify {
+                    event_id: event_id.to_owned(),
+                    modification: CalendarEventModify::Delete,
+                },
+            });
+        Ok(())
+    }
+
+    async fn list_tasklists(&self) -> Result<TaskLists> {
+        Ok(TaskLists::default())
+    }
+
+    async fn get_tasklist(&self, tasklist_id: &str) -> Result<TaskList> {
+        Ok(TaskList {
+            id: Some(tasklist_id.to_owned()),
+            ..TaskList::default()
+        })
+    }
+
+    async fn list_tasks(&self, _tasklist_id: &str) -> Result<Tasks> {
+        Ok(Tasks::default())
+    }
+
+    async fn list_tasks_updated_since(
+        &self,
+        _tasklist_id: &str,
+        _since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Tasks> {
+        Ok(Tasks::default())
+    }
+
+    async fn insert_task(
+        &self,
+        tasklist_id: &str,
+        task: Task,
+        new_parent: Option<&str>,
+        new_predecessor: Option<&str>,
+    ) -> Result<Task> {
+        use crate::write::{TaskInsert, TaskWrite, WriteCommand};
+
+        let new = Task {
+            id: Some(format!("mock-{}", self.writes.lock().unwrap().len())),
+            parent: new_parent.map(str::to_owned),
+            ..task.clone()
+        };
+        self.writes.lock().unwrap().push(WriteCommand::Task {
+            tasklist_id: tasklist_id.to_owned(),
+            cmd: TaskWrite::Insert(TaskInsert::Insert {
+                task: Box::new(task),
+                new_parent: new_parent.map(str::to_owned),
+                new_predecessor: new_predecessor.map(str::to_owned),
+                new_successor: None,
+            }),
+        });
+        Ok(new)
+    }
+
+    async fn patch_task(&self, tasklist_id: &str, task_id: &str, task: Task) -> Result<Task> {
+        use crate::write::{TaskModify, TaskWrite, WriteCommand};
+
+        self.writes.lock().unwrap().push(WriteCommand::Task {
+            tasklist_id: tasklist_id.to_owned(),
+            cmd: TaskWrite::Modify {
+                task_id: task_id.to_owned(),
+                modification: TaskModify::Patch {
+                    task: Box::new(task.clone()),
+                },
+            },
+        });
+        Ok(Task {
+            id: Some(task_id.to_owned()),
+            ..task
+        })
+    }
+
+    async fn delete_task(&self, tasklist_id: &str, task_id: &str) -> Result<()> {
+        use crate::write::{TaskModify, TaskWrite, WriteCommand};
+
+        self.writes.lock().unwrap().push(WriteCommand::Task {
+            tasklist_id: tasklist_id.to_owned(),
+            cmd: TaskWrite::Modify {
+                task_id: task_id.to_owned(),
+                modification: TaskModify::Delete,
+            },
+        });
+        Ok(())
+    }
+
+    async fn move_task(
+        &self,
+        tasklist_id: &str,
+        task_id: &str,
+        new_parent: Option<&str>,
+        new_predecessor: Option<&str>,
+    ) -> Result<Task> {
+        use crate::write::{TaskWrite, WriteCommand};
+
+        self.writes.lock().unwrap().push(WriteCommand::Task {
+            tasklist_id: tasklist_id.to_owned(),
+            cmd: TaskWrite::Move {
+                task_id: task_id.to_owned(),
+                new_parent: new_parent.map(str::to_owned),
+                new_predecessor: new_predecessor.map(str::to_owned),
+                new_successor: None,
+            },
+        });
+        Ok(Task {
+            id: Some(task_id.to_owned()),
+            parent: new_parent.map(str::to_owned),
+            ..Task::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use google_tasks1::{hyper::header::RETRY_AFTER, Delegate};
+
+    use super::RetryAfter;
+
+    #[test]
+    fn retry_after() {
+        let fail = |status: u16, retry_after: Option<&str>| {
+            let mut response = google_tasks1::hyper::Response::builder().status(status);
+            if let Some(retry_after) = retry_after {
+                response = response.header(RETRY_AFTER, retry_after);
+            }
+            let response = response
+                .body(google_tasks1::common::to_body::<String>(None))
+                .unwrap();
+            let mut retry = RetryAfter::default();
+            retry.http_failure(&response, None);
+            retry.wait(Duration::from_millis(500))
+        };
+        // Google's wait overrides a shorter backoff, and vice versa
+        assert_eq!(fail(429, Some("5")), Some(Duration::from_secs(5)));
+        assert_eq!(fail(503, None), Some(Duration::from_millis(500)));
+        assert_eq!(
+            fail(429, Some("Thu, 01 Jan 1970 00:00:00 GMT")),
+            Some(Duration::from_millis(500))
+        );
+        // too long to wait, or not worth retrying at all
+        assert_eq!(fail(429, Some("3600")), None);
+        assert_eq!(fail(404, None), None);
+    }
+
+    #[test]
+    fn full_sync_required() {
+        let gone = google_tasks1::Error::BadRequest(serde_json::json!({
+            "error": {
+                "code": 410,
+                "errors": [{ "domain": "calendar", "reason": "fullSyncRequired" }],
+                "message": "Sync token is no longer valid, a full sync is required."
+            }
+        }));
+        assert!(super::is_full_sync_required(&gone));
+        let bad = google_tasks1::Error::BadRequest(serde_json::json!({ "error": { "code": 400 } }));
+        assert!(!super::is_full_sync_required(&bad));
+    }
+
+    #[test]
+    fn connection_errors() {
+        let timed_out = google_tasks1::Error::Io(std::io::ErrorKind::TimedOut.into());
+        assert!(super::is_connection_error(&timed_out));
+        let bad = google_tasks1::Error::BadRequest(serde_json::json!({ "error": { "code": 400 } }));
+        assert!(!super::is_connection_error(&bad));
+    }
+}
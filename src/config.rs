@@ -0,0 +1,142 @@
+use std::{ffi::OsString, path::PathBuf};
+
+use clap::{error::ErrorKind, parser::ValueSource, ArgMatches, Command, CommandFactory};
+
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// The command line, with the flags from the config file given before it.
+///
+/// The config file is `--config` if given, else `config.toml` in the project config directory
+/// if it exists. Its keys are the long flags (`calendar-format = "compact"`, `read-only-calendar
+/// = ["id"]`, `dry-run = true`) or `mount`, and a flag given on the command line replaces the
+/// file's value entirely, so the precedence is command line, then config file, then defaults.
+pub(crate) fn args_with_config<P: CommandFactory>() -> Vec<OsString> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let command = P::command();
+    // errors (and `--help`) are left for the real parse to report
+    let Ok(matches) = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)
+    else {
+        return args;
+    };
+    let (path, explicit) = match matches.get_one::<PathBuf>("config") {
+        Some(path) => (path.clone(), true),
+        None => match directories::ProjectDirs::from("", "", "orgmode-google-fuse") {
+            Some(dirs) => (dirs.config_dir().join(CONFIG_FILE_NAME), false),
+            None => return args,
+        },
+    };
+    let config = match std::fs::read_to_string(&path) {
+        Ok(config) => config,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => return args,
+        Err(e) => fail(command, format!("failed to read {}: {}", path.display(), e)),
+    };
+    let config_args = config
+        .parse::<toml::Table>()
+        .map_err(|e| e.to_string())
+        .and_then(|table| config_args(&command, &matches, &table))
+        .unwrap_or_else(|e| fail(command.clone(), format!("in {}: {}", path.display(), e)));
+    tracing::debug!("Read {} flags from {}", config_args.len(), path.display());
+    let mut args = args.into_iter();
+    args.next()
+        .into_iter()
+        .chain(config_args)
+        .chain(args)
+        .collect()
+}
+
+fn fail(mut command: Command, message: String) -> ! {
+    command.error(ErrorKind::InvalidValue, message).exit()
+}
+
+// the arguments given by `table`, other than those already on the command line
+fn config_args(
+    command: &Command,
+    matches: &ArgMatches,
+    table: &toml::Table,
+) -> Result<Vec<OsString>, String> {
+    let mut args = Vec::new();
+    for (key, value) in table {
+        let long = key.replace('_', "-");
+        let arg = command
+            .get_arguments()
+            .find(|arg| match arg.get_long() {
+                Some(name) => name == long,
+                None => arg.get_id() == key.as_str(),
+            })
+            .filter(|arg| arg.get_id() != "config")
+            .ok_or_else(|| format!("unknown key `{}`", key))?;
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let values = match value {
+            toml::Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                toml::Value::Boolean(true) if arg.get_long().is_some() => {
+                    args.push(format!("--{}", long).into());
+                    continue;
+                }
+                toml::Value::Boolean(false) => continue,
+                toml::Value::String(value) => value.clone(),
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Float(value) => value.to_string(),
+                _ => return Err(format!("unsupported value for `{}`", key)),
+            };
+            args.push(match arg.get_long() {
+                Some(_) => format!("--{}={}", long, value).into(),
+                None => value.into(),
+            });
+        }
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{Arg, ArgAction, Command};
+
+    use super::config_args;
+
+    #[test]
+    fn command_line_overrides_config() {
+        let command = Command::new("test")
+            .arg(Arg::new("mount"))
+            .arg(Arg::new("tz").long("tz"))
+            .arg(
+                Arg::new("dry_run")
+                    .long("dry-run")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("read_only_calendar")
+                    .long("read-only-calendar")
+                    .action(ArgAction::Append),
+            );
+        let matches = command
+            .clone()
+            .get_matches_from(["test", "--read-only-calendar", "cli"]);
+        let table = r#"
+            mount = "/mnt/org"
+            tz = "Europe/London"
+            dry_run = true
+            read-only-calendar = ["config"]
+        "#
+        .parse::<toml::Table>()
+        .unwrap();
+        assert_eq!(
+            config_args(&command, &matches, &table).unwrap(),
+            vec!["--dry-run", "/mnt/org", "--tz=Europe/London"]
+        );
+
+        let table = "poll = 5".parse::<toml::Table>().unwrap();
+        assert_eq!(
+            config_args(&command, &matches, &table).unwrap_err(),
+            "unknown key `poll`"
+        );
+    }
+}
@@ -0,0 +1,55 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+    os::fd::{AsRawFd, FromRawFd},
+};
+
+/// The running half of a `--daemon` process, which detaches once told it's ready.
+pub(crate) struct Detach(File);
+
+/// Fork, leaving the original process waiting for the child to [`Detach::ready`].
+///
+/// Forking only keeps the calling thread, so this has to happen before the runtime starts. The
+/// child keeps the terminal until it's ready, so that it can take the user through OAuth and its
+/// errors are seen; the original process then exits successfully, or with a failure if the child
+/// exits first.
+pub(crate) fn fork() -> std::io::Result<Detach> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let (mut read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error()),
+        0 => {
+            drop(read);
+            Ok(Detach(write))
+        }
+        _ => {
+            drop(write);
+            let mut byte = [0];
+            let status = match read.read(&mut byte) {
+                Ok(1) => 0,
+                _ => 1,
+            };
+            std::process::exit(status)
+        }
+    }
+}
+
+impl Detach {
+    /// Leave the terminal's session, point stdin, stdout and stderr at /dev/null, and let the
+    /// original process exit.
+    pub(crate) fn ready(mut self) -> std::io::Result<()> {
+        if unsafe { libc::setsid() } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let null = File::options().read(true).write(true).open("/dev/null")?;
+        for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        self.0.write_all(&[0])
+    }
+}
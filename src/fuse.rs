@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ffi::OsStr,
     sync::{atomic::Ordering, Arc, Mutex},
     time::{Duration, SystemTime, UNIX_EPOCH},
@@ -10,17 +10,29 @@ use fuser::{
     ReplyWrite, Request, TimeOrNow,
 };
 use itertools::Itertools;
-use libc::{EBADF, EINVAL, ENOENT, ENOTDIR};
+use libc::{
+    c_int, EACCES, EBADF, EFBIG, EINVAL, ENOENT, ENOTDIR, EROFS, O_ACCMODE, O_RDONLY, R_OK, W_OK,
+    X_OK,
+};
 use orgize::Org;
 
 use crate::{org::ToOrg, Pid};
 use crate::{
     org::{
-        calendar::OrgCalendar, conflict::read_conflict_local, tasklist::OrgTaskList, MaybeIdMap,
-        MetaPendingContainer,
+        calendar::OrgCalendar,
+        conflict::read_conflict_local,
+        parse_org,
+        tasklist::{parse_tasks, OrgTaskList},
+        Lists, MaybeIdMap, MetaPendingContainer, Rebase,
     },
     write::WriteCommand,
 };
+use view::{
+    file_ino, file_name, is_json_slot, is_root_entry, item_index, item_ino, list_slot, parent_ino,
+    slot, View, CALENDARS_VIEW, ICS_VIEW, INBOX_INO, NAME_MAX, REFRESH_INO, ROOT_VIEW, TASKS_VIEW,
+};
+
+mod view;
 
 const BLKSIZE: u32 = 512;
 const DEFAULT_DIR_ATTR: FileAttr = FileAttr {
@@ -62,11 +74,110 @@ type Inode = u64;
 type FileHandle = u64;
 type Instance = (Inode, Pid);
 
+/// What a process has open, and the snapshot its writes are reconciled against.
+///
+/// Instances are identified by the opening process's PID together with its start time, since
+/// PIDs get recycled: a state left behind by an exited process (if its `waitpid` watcher missed
+/// the exit) is discarded rather than inherited by the next process to reuse the PID.
 pub(crate) struct InstanceState {
     pub(super) file_handles: Vec<FileHandle>,
+    // start time of the process that opened the file, in clock ticks since boot
+    started: Option<u64>,
     org: Org,
+    // what the file read as from Google's copy, as of `org` (which also has this process's own
+    // edits), so that edits others have made since can be told apart
+    remote: String,
     write_buffer: Vec<u8>,
     write_time: SystemTime,
+    // whether `write_buffer` holds writes not yet reconciled by `fsync`
+    dirty: bool,
+}
+
+/// How calendars and tasklists are laid out in their directories.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum Layout {
+    /// an org file for each calendar or tasklist
+    #[default]
+    Flat,
+    /// a directory for each calendar or tasklist, with an org file for each of its events or
+    /// tasks named by its ID
+    Nested,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub(crate) struct FsOptions {
+    /// name of the directory holding one org file per calendar
+    #[arg(long, default_value = "calendars", value_parser = parse_dir_name)]
+    calendars_dir: String,
+    /// name of the directory holding one org file per tasklist
+    #[arg(long, default_value = "tasks", value_parser = parse_dir_name)]
+    tasks_dir: String,
+    /// additionally expose each calendar as a read-only iCalendar file under `ics/`
+    #[arg(long)]
+    ics: bool,
+    /// title of the tasklist receiving tasks captured by appending headlines to `inbox.org`
+    /// (e.g. `echo '* TODO Buy milk' >> inbox.org`); no inbox without it
+    #[arg(long, value_name = "TASKLIST")]
+    inbox: Option<String>,
+    /// ID of a calendar to mount read-only; may be repeated
+    #[arg(long = "read-only-calendar", value_name = "ID")]
+    read_only_calendars: Vec<String>,
+    /// ID of a tasklist to mount read-only; may be repeated
+    #[arg(long = "read-only-tasklist", value_name = "ID")]
+    read_only_tasklists: Vec<String>,
+    /// layout of the calendar and tasklist directories
+    #[arg(long, value_enum, default_value_t)]
+    layout: Layout,
+    /// extension of the org files, e.g. org_archive
+    #[arg(long, value_name = "EXT", default_value = "org", value_parser = parse_extension)]
+    extension: String,
+}
+
+impl FsOptions {
+    pub(crate) fn list_dirs_distinct(&self) -> bool {
+        self.calendars_dir != self.tasks_dir
+    }
+
+    pub(crate) fn calendars_dir(&self) -> &str {
+        &self.calendars_dir
+    }
+
+    pub(crate) fn tasks_dir(&self) -> &str {
+        &self.tasks_dir
+    }
+
+    /// The name of the org file for `stem`, with the `--extension`, as the mount names it.
+    pub(crate) fn org_file_name(&self, stem: &str) -> String {
+        file_name(stem, &self.extension)
+    }
+}
+
+fn parse_dir_name(name: &str) -> Result<String, String> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        Err(format!("`{name}` can't be a directory name"))
+    } else if is_root_entry(name) {
+        Err(format!(
+            "`{name}` is already taken by a file or directory of the mount"
+        ))
+    } else {
+        Ok(name.to_owned())
+    }
+}
+
+fn parse_extension(extension: &str) -> Result<String, String> {
+    if extension.is_empty() || extension.contains(['/', '\0']) {
+        Err(format!("`{extension}` can't be a file extension"))
+    } else if extension == "json" {
+        // each list's org file would share its name with the list's `.json`
+        Err(format!(
+            "`{extension}` is already the extension of the JSON files"
+        ))
+    } else if extension.len() > NAME_MAX - "x-01234567.".len() {
+        // a shortened name has to keep at least a byte of the stem, and the hash
+        Err(format!("`{extension}` is too long for a file extension"))
+    } else {
+        Ok(extension.to_owned())
+    }
 }
 
 pub(crate) struct OrgFS {
@@ -74,38 +185,50 @@ pub(crate) struct OrgFS {
     pub(crate) gid: u32,
     pub(crate) calendars: Vec<(Inode, OrgCalendar)>,
     pub(crate) tasklists: Vec<(Inode, OrgTaskList)>,
-    tx_wcmd: tokio::sync::mpsc::UnboundedSender<WriteCommand>,
+    // every list loaded so far, of which `calendars` and `tasklists` are a prefix
+    shared: (Lists<OrgCalendar>, Lists<OrgTaskList>),
+    views: Vec<View>,
+    // bounded by `--write-queue`, so sending blocks the filesystem while the queue is full
+    tx_wcmd: tokio::sync::mpsc::Sender<WriteCommand>,
     tx_fh: tokio::sync::mpsc::UnboundedSender<Pid>,
     #[allow(clippy::type_complexity)]
     pending_fh: Arc<Mutex<HashMap<Instance, InstanceState>>>,
+    // listings snapshotted on `opendir`, so that paging through with `readdir` is consistent
+    dir_handles: HashMap<FileHandle, Vec<DirEntry>>,
+    // contents snapshotted on `open` of the files which keep no per-process state (agendas, `.ics`)
+    file_images: HashMap<(Inode, FileHandle), Vec<u8>>,
+    next_handle: FileHandle,
+    // the list file and ID of each item file under `--layout nested`, by `item_index`
+    items: Vec<(Inode, String)>,
+    last_refresh: SystemTime,
+    // slot in `tasklists` of the capture tasklist, and appended bytes not yet ending in a newline
+    inbox: Option<usize>,
+    inbox_buffer: Vec<u8>,
+    // calendar and tasklist files mounted read-only with `--read-only-calendar`/`--read-only-tasklist`
+    read_only: HashSet<Inode>,
+    options: FsOptions,
 }
 
+type DirEntry = (Inode, FileType, String);
+
 const TTL: Duration = Duration::new(0, 0);
 
 const ROOT_DIR_INO: Inode = 1;
-const fn root_dir_attr(uid: u32, gid: u32) -> FileAttr {
-    FileAttr {
-        ino: ROOT_DIR_INO,
-        uid,
-        gid,
-        ..DEFAULT_DIR_ATTR
-    }
-}
-
 const CALENDAR_DIR_INO: Inode = 2;
-const fn calendar_dir_attr(uid: u32, gid: u32) -> FileAttr {
-    FileAttr {
-        ino: CALENDAR_DIR_INO,
-        uid,
-        gid,
-        ..DEFAULT_DIR_ATTR
-    }
+const TASKS_DIR_INO: Inode = 3;
+const ICS_DIR_INO: Inode = 4;
+
+// `st_blocks` counts 512-byte units whatever the block size
+const fn blocks(size: u64) -> u64 {
+    size.div_ceil(512)
 }
 
-const TASKS_DIR_INO: Inode = 3;
-const fn tasks_dir_attr(uid: u32, gid: u32) -> FileAttr {
+// a directory's size is the total size of the files in it
+const fn dir_attr(uid: u32, gid: u32, ino: Inode, size: u64) -> FileAttr {
     FileAttr {
-        ino: TASKS_DIR_INO,
+        ino,
+        size,
+        blocks: blocks(size),
         uid,
         gid,
         ..DEFAULT_DIR_ATTR
@@ -113,11 +236,10 @@ const fn tasks_dir_attr(uid: u32, gid: u32) -> FileAttr {
 }
 
 const fn file_attr(uid: u32, gid: u32, ino: Inode, size: u64, time: SystemTime) -> FileAttr {
-    let blocks = size.div_ceil(BLKSIZE as u64);
     FileAttr {
         ino,
         size,
-        blocks,
+        blocks: blocks(size),
         atime: time,
         mtime: time,
         ctime: time,
@@ -128,47 +250,291 @@ const fn file_attr(uid: u32, gid: u32, ino: Inode, size: u64, time: SystemTime)
     }
 }
 
-const FILE_START_OFFSET: Inode = TASKS_DIR_INO + 1;
+const fn read_only_file_attr(
+    uid: u32,
+    gid: u32,
+    ino: Inode,
+    size: u64,
+    time: SystemTime,
+) -> FileAttr {
+    FileAttr {
+        perm: 0o444,
+        ..file_attr(uid, gid, ino, size, time)
+    }
+}
 
 impl OrgFS {
     #[allow(clippy::type_complexity)]
     pub(crate) fn new(
-        calendars: Arc<Vec<OrgCalendar>>,
-        tasklists: Arc<Vec<OrgTaskList>>,
-        tx_wcmd: tokio::sync::mpsc::UnboundedSender<WriteCommand>,
+        calendars: Lists<OrgCalendar>,
+        tasklists: Lists<OrgTaskList>,
+        tx_wcmd: tokio::sync::mpsc::Sender<WriteCommand>,
         tx_fh: tokio::sync::mpsc::UnboundedSender<Pid>,
         pending_fh: Arc<Mutex<HashMap<Instance, InstanceState>>>,
+        options: FsOptions,
     ) -> Self {
-        let csl = calendars.len();
-        Self {
+        let mut views = vec![
+            ROOT_VIEW,
+            View {
+                name: options.calendars_dir.clone().into(),
+                ..CALENDARS_VIEW
+            },
+            View {
+                name: options.tasks_dir.clone().into(),
+                ..TASKS_VIEW
+            },
+        ];
+        if options.ics {
+            views.push(ICS_VIEW);
+        }
+        let mut fs = Self {
             uid: nix::unistd::getuid().as_raw(),
             gid: nix::unistd::getgid().as_raw(),
-            calendars: calendars
-                .iter()
-                .cloned()
-                .enumerate()
-                .map(|(i, cal)| (FILE_START_OFFSET + i as u64, cal))
-                .collect(),
-            tasklists: tasklists
-                .iter()
-                .cloned()
-                .enumerate()
-                .map(|(i, tl)| (FILE_START_OFFSET + csl as u64 + i as u64, tl))
-                .collect(),
+            calendars: Vec::new(),
+            tasklists: Vec::new(),
+            shared: (calendars, tasklists),
+            views,
             tx_wcmd,
             tx_fh,
             pending_fh,
+            dir_handles: HashMap::new(),
+            file_images: HashMap::new(),
+            next_handle: 1,
+            items: Vec::new(),
+            last_refresh: UNIX_EPOCH,
+            inbox: None,
+            inbox_buffer: Vec::new(),
+            read_only: HashSet::new(),
+            options,
+        };
+        fs.pick_up_lists();
+        let options = &fs.options;
+        for id in &options.read_only_calendars {
+            if !fs
+                .calendars
+                .iter()
+                .any(|(_, cal)| cal.with_meta(|m| m.calendar().id.as_ref() == Some(id)))
+            {
+                tracing::warn!(calendar_id = %id, "No calendar with this ID to make read-only");
+            }
+        }
+        for id in &options.read_only_tasklists {
+            if !fs
+                .tasklists
+                .iter()
+                .any(|(_, tl)| tl.with_meta(|m| m.tasklist().id.as_ref() == Some(id)))
+            {
+                tracing::warn!(tasklist_id = %id, "No tasklist with this ID to make read-only");
+            }
+        }
+        let inbox = options.inbox.as_ref().and_then(|title| {
+            let slot = fs
+                .tasklists
+                .iter()
+                .position(|(_, tl)| tl.with_meta(|m| m.tasklist().title.as_ref() == Some(title)));
+            if slot.is_none() {
+                tracing::error!("No tasklist titled {:?} to capture into", title);
+            }
+            slot.filter(|&slot| {
+                let writable = !fs.read_only.contains(&fs.tasklists[slot].0);
+                if !writable {
+                    tracing::error!(
+                        "Tasklist {:?} is read-only, so can't capture into it",
+                        title
+                    );
+                }
+                writable
+            })
+        });
+        fs.inbox = inbox;
+        fs
+    }
+
+    // give files to the calendars and tasklists found since we last looked
+    fn pick_up_lists(&mut self) {
+        for cal in &self.shared.0.read().unwrap()[self.calendars.len()..] {
+            let ino = file_ino(CALENDAR_DIR_INO, self.calendars.len());
+            if cal.with_meta(|m| {
+                m.calendar()
+                    .id
+                    .as_ref()
+                    .is_some_and(|id| self.options.read_only_calendars.contains(id))
+            }) {
+                self.read_only.insert(ino);
+            }
+            self.calendars.push((ino, cal.clone()));
+        }
+        for tl in &self.shared.1.read().unwrap()[self.tasklists.len()..] {
+            let ino = file_ino(TASKS_DIR_INO, self.tasklists.len());
+            if tl.with_meta(|m| {
+                m.tasklist()
+                    .id
+                    .as_ref()
+                    .is_some_and(|id| self.options.read_only_tasklists.contains(id))
+            }) {
+                self.read_only.insert(ino);
+            }
+            self.tasklists.push((ino, tl.clone()));
         }
     }
 
     fn is_calendar_file(&self, ino: Inode) -> bool {
-        FILE_START_OFFSET <= ino && ino < FILE_START_OFFSET + self.calendars.len() as Inode
+        parent_ino(ino) == CALENDAR_DIR_INO && slot(ino) < self.calendars.len()
     }
 
     fn is_tasks_file(&self, ino: Inode) -> bool {
-        FILE_START_OFFSET + self.calendars.len() as Inode <= ino
-            && ino
-                < FILE_START_OFFSET + self.calendars.len() as Inode + self.tasklists.len() as Inode
+        parent_ino(ino) == TASKS_DIR_INO && slot(ino) < self.tasklists.len()
+    }
+
+    fn view(&self, dir: Inode) -> Option<&View> {
+        self.views.iter().find(|view| view.ino == dir)
+    }
+
+    // under `--layout nested`, a calendar or tasklist is a directory of its items
+    fn is_list_dir(&self, ino: Inode) -> bool {
+        self.options.layout == Layout::Nested
+            && (self.is_calendar_file(ino) || self.is_tasks_file(ino))
+    }
+
+    // the list file and ID of an item file
+    fn item(&self, ino: Inode) -> Option<&(Inode, String)> {
+        self.items.get(item_index(ino)?)
+    }
+
+    // the list file `ino` is, or is an item of
+    fn list_ino(&self, ino: Inode) -> Inode {
+        self.item(ino).map_or(ino, |&(list, _)| list)
+    }
+
+    // IDs of the items shown in the list directory `list`
+    fn item_ids(&self, list: Inode) -> Option<Vec<String>> {
+        if self.is_calendar_file(list) {
+            let (_, cal) = &self.calendars[slot(list)];
+            (!cal.is_removed()).then(|| cal.event_ids())
+        } else if self.is_tasks_file(list) {
+            let (_, tl) = &self.tasklists[slot(list)];
+            (!tl.is_removed()).then(|| tl.task_ids())
+        } else {
+            None
+        }
+    }
+
+    // contents and modification time of the file of the item `id` of `list`
+    fn render_item(&self, list: Inode, id: &str) -> Option<(String, SystemTime)> {
+        if self.is_calendar_file(list) {
+            let (_, cal) = &self.calendars[slot(list)];
+            let contents = cal.event_to_org_string(id).filter(|_| !cal.is_removed())?;
+            Some((
+                contents,
+                cal.with_meta(|m| m.updated().load(Ordering::Acquire)),
+            ))
+        } else {
+            let (_, tl) = &self.tasklists[slot(list)];
+            let contents = tl.task_to_org_string(id).filter(|_| !tl.is_removed())?;
+            Some((
+                contents,
+                tl.with_meta(|m| m.updated().load(Ordering::Acquire)),
+            ))
+        }
+    }
+
+    // the inode of the item `id` of `list`, numbering it if it's not been listed before
+    fn item_file_ino(&mut self, list: Inode, id: &str) -> Inode {
+        let index = match self.items.iter().position(|(l, i)| *l == list && i == id) {
+            Some(index) => index,
+            None => {
+                self.items.push((list, id.to_owned()));
+                self.items.len() - 1
+            }
+        };
+        item_ino(index)
+    }
+
+    // the name of the org file for `stem`, with the `--extension`
+    pub(super) fn org_file_name(&self, stem: &str) -> String {
+        self.options.org_file_name(stem)
+    }
+
+    // the entries of the directory `ino`, whether a view or a list directory
+    fn listing(&mut self, ino: Inode) -> Option<Vec<DirEntry>> {
+        if let Some(view) = self.view(ino) {
+            return Some(self.dir_entries(view));
+        }
+        let mut entries = vec![
+            (ino, FileType::Directory, ".".to_owned()),
+            (parent_ino(ino), FileType::Directory, "..".to_owned()),
+        ];
+        for id in self.item_ids(ino).filter(|_| self.is_list_dir(ino))? {
+            let item = self.item_file_ino(ino, &id);
+            entries.push((item, FileType::RegularFile, self.org_file_name(&id)));
+        }
+        Some(entries)
+    }
+
+    fn is_read_only_file(&self, ino: Inode) -> bool {
+        let ino = self.list_ino(ino);
+        self.read_only.contains(&ino)
+            || !matches!(ino, REFRESH_INO | INBOX_INO)
+                && self
+                    .view(parent_ino(ino))
+                    .is_some_and(|view| !view.writable || is_json_slot(slot(ino)))
+    }
+
+    // files the user has no permission to change, as opposed to ones that can't be changed at all
+    fn is_access_denied(&self, ino: Inode) -> bool {
+        let ino = self.list_ino(ino);
+        self.is_calendar_file(ino) && !self.calendars[slot(ino)].1.is_writable()
+    }
+
+    // capture a task for every complete headline appended to the inbox
+    fn capture(&mut self, data: &[u8]) {
+        self.inbox_buffer.extend_from_slice(data);
+        let Some(end) = self.inbox_buffer.iter().rposition(|&b| b == b'\n') else {
+            return;
+        };
+        let lines = self.inbox_buffer.drain(..=end).collect::<Vec<_>>();
+        let Some((_, tasklist)) = self.inbox.map(|slot| &self.tasklists[slot]) else {
+            return;
+        };
+        let org = parse_tasks(&String::from_utf8_lossy(&lines));
+        if tasklist.capture(&org, &self.tx_wcmd) {
+            let tasklist_id = tasklist.with_meta(|m| m.tasklist().id.clone()).unwrap();
+            self.tx_wcmd
+                .blocking_send(WriteCommand::TouchTasklist { tasklist_id })
+                .expect("Failed to send tasklist touch command");
+        }
+    }
+
+    // sync everything now rather than on the next poll
+    fn refresh(&mut self) {
+        tracing::info!("Refresh requested, syncing…");
+        for (_, calendar) in self.calendars.iter().filter(|(_, cal)| !cal.is_removed()) {
+            let calendar_id = calendar
+                .with_meta(|m| m.calendar().id.clone())
+                .expect("calendar with no id");
+            self.tx_wcmd
+                .blocking_send(WriteCommand::SyncCalendar { calendar_id })
+                .expect("Failed to send calendar sync command");
+        }
+        for (_, tasklist) in self.tasklists.iter().filter(|(_, tl)| !tl.is_removed()) {
+            let tasklist_id = tasklist
+                .with_meta(|m| m.tasklist().id.clone())
+                .expect("tasklist with no id");
+            self.tx_wcmd
+                .blocking_send(WriteCommand::SyncTasklist { tasklist_id })
+                .expect("Failed to send tasklist sync command");
+        }
+        self.last_refresh = SystemTime::now();
+    }
+
+    fn render(&self, ino: Inode) -> Option<String> {
+        match self.item(ino) {
+            Some((list, id)) => self.render_item(*list, id),
+            None => self
+                .view(parent_ino(ino))
+                .and_then(|view| (view.render)(self, slot(ino))),
+        }
+        .map(|(contents, _)| contents)
     }
 
     fn allocate_stateful_file_handle(&mut self, ino: Inode, pid: u32) -> u64 {
@@ -178,14 +544,21 @@ impl OrgFS {
         // buffer contents (vim won't hold any open file handles and the backing data may have
         // changed in the meantime).
         // The idea behind this is to snapshot the state as Org per PID the first time any process
-        // opens a file. Subsequent reads will return the **latest data**, but writes from this
-        // process are reconciled against this snapshot.
+        // opens a file. Reads are served from this snapshot until the process's last handle on
+        // the file is released, and writes from this process are reconciled against it; opening
+        // the file afresh then returns the **latest data**.
         //
         // Lifecycle:
         // * allocated on `open`, freed on `release` or pid exit
-        // * used by `setattr` and `write` for write buffer, and `fsync` to reconcile changes
-        // * fast-forwarded on `read`
+        // * used by `read`, by `setattr` and `write` for write buffer, and `fsync` to reconcile
+        //   changes
         if let Some((org, updated)) = match ino {
+            i if self.is_list_dir(i) => None,
+            i if self.item(i).is_some() => {
+                let (list, id) = self.item(i).unwrap();
+                self.render_item(*list, id)
+                    .map(|(contents, updated)| (parse_org(&contents), updated))
+            }
             i if self.is_calendar_file(i) => {
                 self.calendars
                     .iter()
@@ -211,6 +584,8 @@ impl OrgFS {
             _ => None,
         } {
             let mut guard = self.pending_fh.lock().unwrap();
+            let started = process_start_time(pid);
+            purge_recycled_pid(&mut guard, pid, started);
             if guard.keys().all(|(_, p)| *p != pid) {
                 // newly opened file, watch the pid
                 self.tx_fh.send(pid).unwrap();
@@ -225,14 +600,19 @@ impl OrgFS {
                 .zip(1..)
                 .find_map(|(fh, i)| (fh != i).then_some(i))
                 .unwrap();
-            let write_buffer = org.to_org_string().into();
+            let contents = org.to_org_string();
+            let remote = read_conflict_local(&contents);
+            let write_buffer = contents.into();
             guard
                 .entry((ino, pid))
                 .or_insert(InstanceState {
                     file_handles: Vec::default(),
+                    started,
+                    remote,
                     org,
                     write_buffer,
                     write_time: updated,
+                    dirty: false,
                 })
                 .file_handles
                 .push(fh);
@@ -241,123 +621,332 @@ impl OrgFS {
             0
         }
     }
-
-    fn get_inode(&self, ino: Inode) -> Option<FileAttr> {
-        match ino {
-            ROOT_DIR_INO => Some(root_dir_attr(self.uid, self.gid)),
-            CALENDAR_DIR_INO => Some(calendar_dir_attr(self.uid, self.gid)),
-            TASKS_DIR_INO => Some(tasks_dir_attr(self.uid, self.gid)),
-            i if self.is_calendar_file(i) => {
-                self.calendars
+    fn dir_entries(&self, view: &View) -> Vec<DirEntry> {
+        let mut entries = vec![
+            (view.ino, FileType::Directory, ".".to_owned()),
+            (ROOT_DIR_INO, FileType::Directory, "..".to_owned()),
+        ];
+        if view.ino == ROOT_DIR_INO {
+            entries.extend(
+                self.views
                     .iter()
-                    .find(|(ino, _)| ino == &i)
-                    .map(|(_, cal)| {
-                        file_attr(
-                            self.uid,
-                            self.gid,
-                            i,
-                            cal.to_org_string().len() as u64,
-                            cal.with_meta(|m| m.updated().load(Ordering::Acquire)),
-                        )
-                    })
+                    .filter(|v| v.ino != ROOT_DIR_INO)
+                    .map(|v| (v.ino, FileType::Directory, v.name.to_string())),
+            );
+        }
+        entries.extend((view.entries)(self).into_iter().map(|(slot, filename)| {
+            let ino = file_ino(view.ino, slot);
+            match filename
+                .strip_suffix(self.options.extension.as_str())
+                .and_then(|name| name.strip_suffix('.'))
+                .filter(|_| self.is_list_dir(ino))
+            {
+                Some(name) => (ino, FileType::Directory, name.to_owned()),
+                None => (ino, FileType::RegularFile, filename),
             }
-            i if self.is_tasks_file(i) => {
-                self.tasklists
-                    .iter()
-                    .find(|(ino, _)| ino == &i)
-                    .map(|(_, tl)| {
-                        file_attr(
-                            self.uid,
-                            self.gid,
-                            i,
-                            tl.to_org_string().len() as u64,
-                            tl.with_meta(|m| m.updated().load(Ordering::Acquire)),
-                        )
-                    })
+        }));
+        entries
+    }
+
+    // turn what `pid` has written to `ino` since it was snapshotted into write commands
+    fn reconcile(&mut self, ino: Inode, pid: Pid) {
+        let mut commands = Vec::new();
+        if let Some(InstanceState {
+            org,
+            remote,
+            write_buffer,
+            write_time,
+            dirty,
+            ..
+        }) = self.pending_fh.lock().unwrap().get_mut(&(ino, pid))
+        {
+            *dirty = false;
+            let written = String::from_utf8_lossy(without_padding(write_buffer));
+
+            // compute diff
+            let old = MaybeIdMap::from(&*org);
+            tracing::debug!("Old: {:?} ", old);
+            let n_old = old.len();
+            let parse = |text: &str| {
+                if self.is_tasks_file(ino) {
+                    parse_tasks(text)
+                } else {
+                    parse_org(text)
+                }
+            };
+            let new_org = parse(&read_conflict_local(&written));
+            let new = MaybeIdMap::from(&new_org);
+            tracing::debug!("New: {:?} ", new);
+            let mut diff = old.diff(new);
+            tracing::debug!("Computed diff\n{:#?}", diff);
+            if self.item(ino).is_some() && diff.removed.len() > 0 {
+                tracing::warn!(
+                    "Not deleting the item emptied from its own file, ino: {}; delete it from \
+                     its list instead",
+                    ino
+                );
+                diff.removed = MaybeIdMap::default();
             }
-            _ => None,
+            assert!(diff.removed.len() < n_old,
+                "Refusing to delete **all** existing entries to prevent data loss\nThis is probably a bug");
+            for (id, headline) in diff.added.map() {
+                tracing::warn!(
+                    %id,
+                    title = %headline.title_raw(),
+                    "Found new entry with an ID we didn't know about"
+                );
+            }
+            for headline in diff.removed.fresh() {
+                tracing::warn!(title = %headline.title_raw(), "Found removed entry without ID");
+            }
+
+            // the file as it reads now, to tell what another process (or Google) changed since
+            // `remote`, and so isn't to be reverted
+            let live = || read_conflict_local(&self.render(ino).unwrap_or_default());
+            let rebase = |live: &str| Rebase {
+                base: MaybeIdMap::from(&parse(remote)),
+                live: MaybeIdMap::from(&parse(live)),
+            };
+            match self.list_ino(ino) {
+                i if self.is_calendar_file(i) => {
+                    let orgcal = self
+                        .calendars
+                        .iter()
+                        .find(|(ino, _)| ino == &i)
+                        .map(|(_, cal)| cal)
+                        .expect("Calendar file not found during fsync");
+                    orgcal.clear_pending();
+                    let live = live();
+                    orgcal.rebase(&mut diff, &rebase(&live));
+                    let calendar_id = orgcal
+                        .with_meta(|meta| meta.calendar().id.clone())
+                        .expect("Calendar ID not found during fsync");
+                    commands = orgcal.generate_commands(diff);
+                    if !commands.is_empty() {
+                        tracing::debug!("Updating cached Org for ino: {}", ino);
+                        *org = new_org;
+                        *remote = live;
+                        *write_time = SystemTime::now();
+                        commands.push(WriteCommand::TouchCalendar { calendar_id });
+                    } else {
+                        tracing::debug!(
+                            "No changes detected during fsync for calendar {}",
+                            calendar_id
+                        );
+                    }
+                }
+                i if self.is_tasks_file(i) => {
+                    let orgtask = self
+                        .tasklists
+                        .iter()
+                        .find(|(ino, _)| ino == &i)
+                        .map(|(_, tl)| tl)
+                        .expect("Tasklist file not found during fsync");
+                    orgtask.clear_pending();
+                    let live = live();
+                    orgtask.rebase(&mut diff, &rebase(&live));
+                    let tasklist_id = orgtask
+                        .with_meta(|meta| meta.tasklist().id.clone())
+                        .expect("Tasklist ID not found during fsync");
+                    commands = OrgTaskList::generate_commands(&tasklist_id, diff, &new_org);
+                    if !commands.is_empty() {
+                        tracing::debug!("Updating cached Org for ino: {}", ino);
+                        *org = new_org;
+                        *remote = live;
+                        *write_time = SystemTime::now();
+                        commands.push(WriteCommand::TouchTasklist { tasklist_id });
+                    } else {
+                        tracing::debug!(
+                            "No changes detected during fsync for tasklist {}",
+                            tasklist_id
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+        // only once `pending_fh` is unlocked, so that a full write queue holds up this process
+        // alone rather than every other one reading or writing a file
+        for wcmd in commands {
+            self.tx_wcmd
+                .blocking_send(wcmd)
+                .expect("Failed to send write command");
+        }
+    }
+
+    fn get_inode(&self, ino: Inode) -> Option<FileAttr> {
+        if let Some(view) = self.view(ino) {
+            let size = (view.entries)(self)
+                .into_iter()
+                .filter_map(|(slot, _)| self.get_inode(file_ino(view.ino, slot)))
+                .filter(|attr| attr.kind == FileType::RegularFile)
+                .map(|attr| attr.size)
+                .sum();
+            return Some(dir_attr(self.uid, self.gid, ino, size));
+        }
+        if self.is_list_dir(ino) {
+            let size = self
+                .item_ids(ino)?
+                .iter()
+                .filter_map(|id| self.render_item(ino, id))
+                .map(|(contents, _)| contents.len() as u64)
+                .sum();
+            return Some(dir_attr(self.uid, self.gid, ino, size));
+        }
+        let (contents, updated) = match self.item(ino) {
+            Some((list, id)) => self.render_item(*list, id)?,
+            None => (self.view(parent_ino(ino))?.render)(self, slot(ino))?,
+        };
+        let mut attr = self.attr_of_file(ino, contents.len() as u64, updated);
+        if let Some(created) = self.created(ino) {
+            attr.crtime = created;
+        }
+        Some(attr)
+    }
+
+    // the attributes of the file `ino`, read-only if its list is or the user may only read it
+    fn attr_of_file(&self, ino: Inode, size: u64, time: SystemTime) -> FileAttr {
+        if self.is_read_only_file(ino) || self.is_access_denied(ino) {
+            read_only_file_attr(self.uid, self.gid, ino, size, time)
+        } else {
+            file_attr(self.uid, self.gid, ino, size, time)
         }
     }
+
+    // the attributes of `ino` as `pid` has it open, with what it's written so far
+    fn instance_attr(&self, ino: Inode, pid: Pid) -> Option<FileAttr> {
+        let pending_fh = self.pending_fh.lock().unwrap();
+        let state = pending_fh.get(&(ino, pid))?;
+        Some(self.attr_of_file(ino, state.write_buffer.len() as u64, state.write_time))
+    }
+
+    // when the earliest event in a calendar's files was created; Google doesn't say for tasks
+    fn created(&self, ino: Inode) -> Option<SystemTime> {
+        if !matches!(parent_ino(ino), CALENDAR_DIR_INO | ICS_DIR_INO) {
+            return None;
+        }
+        let (_, cal) = self.calendars.get(list_slot(slot(ino)))?;
+        Some(cal.with_meta(|m| m.created().load(Ordering::Acquire))).filter(|&t| t != UNIX_EPOCH)
+    }
+}
+
+// when the process `pid` started, from field 22 of /proc/<pid>/stat (counting from the fields after
+// the parenthesised command name, which may itself contain spaces)
+fn process_start_time(pid: Pid) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    stat.rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .nth(19)?
+        .parse()
+        .ok()
+}
+
+// drop the states of an earlier process which had the same PID
+fn purge_recycled_pid(
+    pending_fh: &mut HashMap<Instance, InstanceState>,
+    pid: Pid,
+    started: Option<u64>,
+) {
+    pending_fh.retain(|(ino, p), state| {
+        let recycled = *p == pid && state.started != started;
+        if recycled {
+            tracing::debug!(
+                "Discarding stale snapshot of ino {} for recycled PID {}",
+                ino,
+                pid
+            );
+        }
+        !recycled
+    });
+}
+
+// the bytes of `contents` a read of `size` at `offset` returns; offsets are in bytes, so a chunk
+// may end partway through a multibyte character and the next one picks up from there
+fn read_chunk(contents: &[u8], offset: usize, size: usize) -> &[u8] {
+    &contents[offset.min(contents.len())..contents.len().min(offset.saturating_add(size))]
+}
+
+// the most an open file's buffer may grow to, so that a mistaken size (`truncate -s 1T`) fails
+// with `EFBIG` rather than exhausting memory; no calendar or tasklist comes near it
+const MAX_BUFFER_LEN: u64 = 64 << 20;
+
+// write `data` at `offset`, filling any gap past the end of `buffer` with zero bytes as a write
+// after seeking past the end of a file does (or one of a write's chunks arriving before another)
+fn write_at(buffer: &mut Vec<u8>, offset: u64, data: &[u8]) -> Result<(), c_int> {
+    let end = offset.checked_add(data.len() as u64).ok_or(EFBIG)?;
+    preallocate(buffer, end)?;
+    buffer[offset as usize..end as usize].copy_from_slice(data);
+    Ok(())
+}
+
+// extend `buffer` with zero bytes to at least `len`, as preallocating it does
+fn preallocate(buffer: &mut Vec<u8>, len: u64) -> Result<(), c_int> {
+    if len > MAX_BUFFER_LEN {
+        return Err(EFBIG);
+    }
+    if len as usize > buffer.len() {
+        buffer.resize(len as usize, 0);
+    }
+    Ok(())
+}
+
+// `buffer` without the zero bytes `fallocate` padded it with and a write didn't fill
+fn without_padding(buffer: &[u8]) -> &[u8] {
+    let len = buffer
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .map_or(0, |i| i + 1);
+    &buffer[..len]
 }
 
 impl Filesystem for OrgFS {
     fn lookup(&mut self, _req: &Request, parent: Inode, name: &OsStr, reply: ReplyEntry) {
-        if let Some(fileattr) = match parent {
-            ROOT_DIR_INO => match name.to_str() {
-                Some("calendars") => Some(calendar_dir_attr(self.uid, self.gid)),
-                Some("tasks") => Some(tasks_dir_attr(self.uid, self.gid)),
-                _ => None,
-            },
-            CALENDAR_DIR_INO => name.to_str().and_then(|filename| {
-                self.calendars.iter().find_map(|(ino, cal)| {
-                    cal.with_meta(|m| {
-                        m.calendar()
-                            .summary
-                            .as_ref()
-                            .filter(|summary| format!("{}.org", summary) == filename)
-                            .map(|_| {
-                                file_attr(
-                                    self.uid,
-                                    self.gid,
-                                    *ino,
-                                    cal.to_org_string().len() as u64,
-                                    cal.with_meta(|m| m.updated().load(Ordering::Acquire)),
-                                )
-                            })
-                    })
-                })
-            }),
-            TASKS_DIR_INO => name.to_str().and_then(|filename| {
-                self.tasklists.iter().find_map(|(ino, tl)| {
-                    tl.with_meta(|m| {
-                        m.tasklist()
-                            .title
-                            .as_ref()
-                            .filter(|title| format!("{}.org", title) == filename)
-                            .map(|_| {
-                                file_attr(
-                                    self.uid,
-                                    self.gid,
-                                    *ino,
-                                    tl.to_org_string().len() as u64,
-                                    tl.with_meta(|m| m.updated().load(Ordering::Acquire)),
-                                )
-                            })
-                    })
-                })
-            }),
-            _ => None,
-        } {
+        self.pick_up_lists();
+        if let Some(fileattr) = self
+            .listing(parent)
+            .and_then(|entries| {
+                entries
+                    .into_iter()
+                    .skip(2) // `.` and `..`
+                    .find(|(_, _, filename)| Some(filename.as_str()) == name.to_str())
+            })
+            .and_then(|(ino, _, _)| self.get_inode(ino))
+        {
             reply.entry(&TTL, &fileattr, 0);
         } else {
             reply.error(ENOENT);
         }
     }
 
-    fn getattr(&mut self, req: &Request, ino: Inode, _fh: Option<u64>, reply: ReplyAttr) {
-        if let Some(InstanceState {
-            write_buffer,
-            write_time,
-            ..
-        }) = self.pending_fh.lock().unwrap().get(&(ino, req.pid()))
-        {
-            reply.attr(
-                &TTL,
-                &file_attr(
-                    self.uid,
-                    self.gid,
-                    ino,
-                    write_buffer.len() as u64,
-                    *write_time,
-                ),
-            );
-        } else if let Some(fileattr) = self.get_inode(ino) {
+    fn getattr(&mut self, req: &Request, ino: Inode, fh: Option<u64>, reply: ReplyAttr) {
+        if let Some(attr) = self.instance_attr(ino, req.pid()) {
+            reply.attr(&TTL, &attr);
+        } else if let Some(mut fileattr) = self.get_inode(ino) {
+            if let Some(image) = fh.and_then(|fh| self.file_images.get(&(ino, fh))) {
+                fileattr.size = image.len() as u64;
+                fileattr.blocks = blocks(fileattr.size);
+            }
             reply.attr(&TTL, &fileattr);
         } else {
             reply.error(ENOENT);
         }
     }
 
+    fn access(&mut self, _req: &Request<'_>, ino: Inode, mask: i32, reply: ReplyEmpty) {
+        let Some(attr) = self.get_inode(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+        // everything belongs to the mounting user, so only the owner bits matter
+        let allowed = i32::from(attr.perm >> 6) & (R_OK | W_OK | X_OK);
+        if mask & (R_OK | W_OK | X_OK) & !allowed == 0 {
+            reply.ok();
+        } else {
+            reply.error(EACCES);
+        }
+    }
+
     fn setattr(
         &mut self,
         req: &Request,
@@ -376,21 +965,46 @@ impl Filesystem for OrgFS {
         _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
+        if size.is_some() && self.is_read_only_file(ino) {
+            reply.error(EROFS);
+            return;
+        }
+        if size.is_some() && self.is_access_denied(ino) {
+            reply.error(EACCES);
+            return;
+        }
         if let Some(mut attrs) = self.get_inode(ino) {
-            if let Some(size) = size {
+            // truncating a control file (e.g. `echo > .refresh`) has nothing to discard
+            if let Some(size) = size.filter(|_| !matches!(ino, REFRESH_INO | INBOX_INO)) {
+                let mut pending_fh = self.pending_fh.lock().unwrap();
+                let state = pending_fh.get_mut(&(ino, req.pid()));
                 if size == 0 {
-                    if let Some(InstanceState { write_buffer, .. }) =
-                        self.pending_fh.lock().unwrap().get_mut(&(ino, req.pid()))
+                    if let Some(InstanceState {
+                        write_buffer,
+                        dirty,
+                        ..
+                    }) = state
                     {
                         attrs.blocks = 0;
                         attrs.size = 0;
                         write_buffer.clear();
+                        *dirty = true;
                     } else {
                         tracing::warn!(
                             "Zero-truncate requested on a file that is not open, ino: {}",
                             ino
                         );
                     }
+                } else if let Some(InstanceState { write_buffer, .. }) =
+                    state.filter(|state| size >= state.write_buffer.len() as u64)
+                {
+                    // growing pads with zero bytes, as `fallocate` does
+                    if let Err(e) = preallocate(write_buffer, size) {
+                        reply.error(e);
+                        return;
+                    }
+                    attrs.size = size;
+                    attrs.blocks = blocks(size);
                 } else {
                     tracing::error!(
                         "Unsupported non-zero truncate requested, ino: {}, size: {}",
@@ -426,15 +1040,41 @@ impl Filesystem for OrgFS {
         _lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
+        if self.is_read_only_file(ino) {
+            reply.error(EROFS);
+            return;
+        }
+        if self.is_access_denied(ino) {
+            reply.error(EACCES);
+            return;
+        }
+        if ino == REFRESH_INO {
+            self.refresh();
+            reply.written(data.len() as u32);
+            return;
+        }
+        if ino == INBOX_INO {
+            self.capture(data);
+            reply.written(data.len() as u32);
+            return;
+        }
         if let Some(InstanceState {
             file_handles,
             write_buffer,
+            dirty,
             ..
         }) = self.pending_fh.lock().unwrap().get_mut(&(ino, req.pid()))
         {
             assert!(file_handles.contains(&fh));
-            assert_eq!(offset as usize, write_buffer.len());
-            write_buffer.extend_from_slice(data);
+            let Ok(offset) = u64::try_from(offset) else {
+                reply.error(EINVAL);
+                return;
+            };
+            if let Err(e) = write_at(write_buffer, offset, data) {
+                reply.error(e);
+                return;
+            }
+            *dirty = true;
         } else {
             reply.error(EBADF);
             return;
@@ -451,99 +1091,60 @@ impl Filesystem for OrgFS {
         reply.written(data.len() as u32);
     }
 
+    fn fallocate(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        if self.is_read_only_file(ino) {
+            reply.error(EROFS);
+            return;
+        }
+        if self.is_access_denied(ino) {
+            reply.error(EACCES);
+            return;
+        }
+        if offset < 0 || length <= 0 {
+            reply.error(EINVAL);
+            return;
+        }
+        if matches!(ino, REFRESH_INO | INBOX_INO) {
+            reply.ok();
+            return;
+        }
+        if let Some(InstanceState {
+            file_handles,
+            write_buffer,
+            ..
+        }) = self.pending_fh.lock().unwrap().get_mut(&(ino, req.pid()))
+        {
+            assert!(file_handles.contains(&fh));
+            // only plain preallocation changes what's read back; keeping the size, punching holes
+            // and the rest have nothing to reserve here, so succeed without doing anything
+            let preallocated = match mode {
+                0 => offset
+                    .checked_add(length)
+                    .ok_or(EFBIG)
+                    .and_then(|end| preallocate(write_buffer, end as u64)),
+                _ => Ok(()),
+            };
+            match preallocated {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e),
+            }
+        } else {
+            reply.error(EBADF);
+        }
+    }
+
     fn fsync(&mut self, req: &Request<'_>, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
         if let Some(_attrs) = self.get_inode(ino) {
-            // sync with online here
-            if let Some(InstanceState {
-                org,
-                write_buffer,
-                write_time,
-                ..
-            }) = self.pending_fh.lock().unwrap().get_mut(&(ino, req.pid()))
-            {
-                let written = String::from_utf8_lossy(write_buffer);
-
-                // compute diff
-                let old = MaybeIdMap::from(&*org);
-                tracing::debug!("Old: {:?} ", old);
-                let n_old = old.len();
-                let new_org = Org::parse(read_conflict_local(&written));
-                let new = MaybeIdMap::from(&new_org);
-                tracing::debug!("New: {:?} ", new);
-                let diff = old.diff(new);
-                tracing::debug!("Computed diff\n{:#?}", diff);
-                assert!(diff.removed.len() < n_old,
-                    "Refusing to delete **all** existing entries to prevent data loss\nThis is probably a bug");
-                for (id, headline) in diff.added.map() {
-                    tracing::warn!(
-                        "Found new entry with ID {} we didn't know about: {}",
-                        id,
-                        headline.title_raw()
-                    );
-                }
-                for headline in diff.removed.fresh() {
-                    tracing::warn!("Found removed entry without ID: {}", headline.title_raw());
-                }
-
-                match ino {
-                    i if self.is_calendar_file(i) => {
-                        let orgcal = self
-                            .calendars
-                            .iter()
-                            .find(|(ino, _)| ino == &i)
-                            .map(|(_, cal)| cal)
-                            .expect("Calendar file not found during fsync");
-                        orgcal.clear_pending();
-                        let calendar_id = orgcal
-                            .with_meta(|meta| meta.calendar().id.clone())
-                            .expect("Calendar ID not found during fsync");
-                        if orgcal.generate_commands(diff, &self.tx_wcmd) {
-                            tracing::debug!("Updating cached Org for ino: {}", ino);
-                            *org = new_org;
-                            *write_time = SystemTime::now();
-                            self.tx_wcmd
-                                .send(WriteCommand::TouchCalendar { calendar_id })
-                                .expect("Failed to send calendar touch command");
-                        } else {
-                            tracing::debug!(
-                                "No changes detected during fsync for calendar {}",
-                                calendar_id
-                            );
-                        }
-                    }
-                    i if self.is_tasks_file(i) => {
-                        let orgtask = self
-                            .tasklists
-                            .iter()
-                            .find(|(ino, _)| ino == &i)
-                            .map(|(_, tl)| tl)
-                            .expect("Tasklist file not found during fsync");
-                        orgtask.clear_pending();
-                        let tasklist_id = orgtask
-                            .with_meta(|meta| meta.tasklist().id.clone())
-                            .expect("Tasklist ID not found during fsync");
-                        if OrgTaskList::generate_commands(
-                            &tasklist_id,
-                            diff,
-                            &self.tx_wcmd,
-                            &new_org,
-                        ) {
-                            tracing::debug!("Updating cached Org for ino: {}", ino);
-                            *org = new_org;
-                            *write_time = SystemTime::now();
-                            self.tx_wcmd
-                                .send(WriteCommand::TouchTasklist { tasklist_id })
-                                .expect("Failed to send tasklist touch command");
-                        } else {
-                            tracing::debug!(
-                                "No changes detected during fsync for tasklist {}",
-                                tasklist_id
-                            );
-                        }
-                    }
-                    _ => {}
-                }
-            }
+            self.reconcile(ino, req.pid());
             tracing::trace!(
                 "fsync pending_fh: {:?}",
                 self.pending_fh
@@ -563,7 +1164,7 @@ impl Filesystem for OrgFS {
         &mut self,
         req: &Request,
         ino: Inode,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
@@ -574,136 +1175,106 @@ impl Filesystem for OrgFS {
             reply.error(EINVAL);
             return;
         }
-        if let Some(org) = match () {
-            () if self.is_calendar_file(ino) => self
-                .calendars
-                .iter()
-                .find(|(i, _)| &ino == i)
-                .map(|(_, cal)| cal.to_org_string()),
-            () if self.is_tasks_file(ino) => self
-                .tasklists
-                .iter()
-                .find(|(i, _)| &ino == i)
-                .map(|(_, tl)| tl.to_org_string()),
-            () => None,
-        } {
-            if offset as usize >= org.len() {
-                reply.data(&[]);
-                return;
-            }
-            if let Some(InstanceState { org: cached, .. }) =
-                self.pending_fh.lock().unwrap().get_mut(&(ino, req.pid()))
-            {
-                tracing::debug!(
-                    "Fast-forwarding cached Org for ino: {}, pid: {}",
-                    ino,
-                    req.pid()
-                );
-                *cached = Org::parse(&org);
-            }
-            tracing::trace!(
-                "read pending_fh: {:?}",
-                self.pending_fh
-                    .lock()
-                    .unwrap()
-                    .iter()
-                    .map(|(x, InstanceState { file_handles, .. })| (x, file_handles))
-                    .collect::<Vec<_>>()
-            );
-            reply.data(
-                &org.as_bytes()
-                    [offset as usize..usize::min(org.len(), offset as usize + size as usize)],
-            );
+        let guard = self.pending_fh.lock().unwrap();
+        if let Some(state) = guard.get(&(ino, req.pid())) {
+            // serve the snapshot whose size `getattr` reports; it's kept for as long as the file
+            // is open, so a large file read in several chunks can't change between them
+            reply.data(read_chunk(
+                &state.write_buffer,
+                offset as usize,
+                size as usize,
+            ));
+        } else if let Some(image) = self.file_images.get(&(ino, fh)) {
+            reply.data(read_chunk(image, offset as usize, size as usize));
+        } else if let Some(org) = self.render(ino) {
+            reply.data(read_chunk(org.as_bytes(), offset as usize, size as usize));
         } else {
             reply.error(EBADF);
+            return;
         }
+        drop(guard);
+        tracing::trace!(
+            "read pending_fh: {:?}",
+            self.pending_fh
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(x, InstanceState { file_handles, .. })| (x, file_handles))
+                .collect::<Vec<_>>()
+        );
     }
 
     fn readdir(
         &mut self,
         _req: &fuser::Request<'_>,
         ino: Inode,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         mut reply: fuser::ReplyDirectory,
     ) {
-        let entries =
-            match ino {
-                ROOT_DIR_INO => {
-                    vec![
-                        (ROOT_DIR_INO, FileType::Directory, ".".to_owned()),
-                        (ROOT_DIR_INO, FileType::Directory, "..".to_owned()),
-                        (
-                            CALENDAR_DIR_INO,
-                            FileType::Directory,
-                            "calendars".to_owned(),
-                        ),
-                        (TASKS_DIR_INO, FileType::Directory, "tasks".to_owned()),
-                    ]
-                }
-                CALENDAR_DIR_INO => {
-                    let mut entries = vec![
-                        (CALENDAR_DIR_INO, FileType::Directory, ".".to_owned()),
-                        (ROOT_DIR_INO, FileType::Directory, "..".to_owned()),
-                    ];
-                    entries.extend(self.calendars.iter().enumerate().filter_map(
-                        |(i, (_, cal))| {
-                            cal.with_meta(|meta| {
-                                meta.calendar().summary.as_ref().map(|summary| {
-                                    (
-                                        FILE_START_OFFSET + i as Inode,
-                                        FileType::RegularFile,
-                                        format!("{}.org", summary),
-                                    )
-                                })
-                            })
-                        },
-                    ));
-                    entries
-                }
-                TASKS_DIR_INO => {
-                    let mut entries = vec![
-                        (TASKS_DIR_INO, FileType::Directory, ".".to_owned()),
-                        (ROOT_DIR_INO, FileType::Directory, "..".to_owned()),
-                    ];
-                    entries.extend(
-                        self.tasklists
-                            .iter()
-                            .enumerate()
-                            .filter_map(|(i, (_, tl))| {
-                                tl.with_meta(|meta| {
-                                    meta.tasklist().title.as_ref().map(|title| {
-                                        (
-                                            FILE_START_OFFSET
-                                                + self.calendars.len() as Inode
-                                                + i as Inode,
-                                            FileType::RegularFile,
-                                            format!("{}.org", title),
-                                        )
-                                    })
-                                })
-                            }),
-                    );
-                    entries
-                }
-                _ => {
+        // fall back to a fresh listing for handles we didn't hand out
+        let entries = match self.dir_handles.get(&fh) {
+            Some(entries) => entries.clone(),
+            None => match self.listing(ino) {
+                Some(entries) => entries,
+                None => {
                     reply.error(ENOTDIR);
                     return;
                 }
-            };
+            },
+        };
 
-        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
+        for (i, entry) in entries.iter().enumerate().skip(offset as usize) {
             // i + 1 means the index of the next entry
-            if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
+            if reply.add(entry.0, (i + 1) as i64, entry.1, &entry.2) {
                 break;
             }
         }
         reply.ok();
     }
 
-    fn open(&mut self, req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+    fn opendir(&mut self, _req: &Request<'_>, ino: Inode, _flags: i32, reply: ReplyOpen) {
+        self.pick_up_lists();
+        let Some(entries) = self.listing(ino) else {
+            reply.error(ENOTDIR);
+            return;
+        };
+        let fh = self.next_handle;
+        self.next_handle += 1;
+        self.dir_handles.insert(fh, entries);
+        reply.opened(fh, 0);
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: Inode,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.dir_handles.remove(&fh);
+        reply.ok();
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
         tracing::debug!("open ino: {}, pid: {}", ino, req.pid());
-        let fh = self.allocate_stateful_file_handle(ino, req.pid());
+        if flags & O_ACCMODE != O_RDONLY && self.is_read_only_file(ino) {
+            reply.error(EROFS);
+            return;
+        }
+        if flags & O_ACCMODE != O_RDONLY && self.is_access_denied(ino) {
+            reply.error(EACCES);
+            return;
+        }
+        let mut fh = self.allocate_stateful_file_handle(ino, req.pid());
+        if fh == 0 {
+            if let Some(contents) = self.render(ino) {
+                fh = self.next_handle;
+                self.next_handle += 1;
+                self.file_images.insert((ino, fh), contents.into_bytes());
+            }
+        }
         reply.opened(fh, 0);
     }
 
@@ -717,6 +1288,7 @@ impl Filesystem for OrgFS {
         _flush: bool,
         reply: ReplyEmpty,
     ) {
+        self.file_images.remove(&(ino, fh));
         match req.pid() {
             0 => {
                 // kernel context
@@ -756,3 +1328,776 @@ impl Filesystem for OrgFS {
         reply.ok();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use google_calendar3::api::{CalendarListEntry, Event, EventDateTime, Events};
+
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex, RwLock},
+    };
+
+    use orgize::Org;
+
+    use super::{
+        file_attr, preallocate, purge_recycled_pid, read_chunk, without_padding, write_at,
+        FsOptions, Instance, InstanceState, Layout, OrgFS, CALENDAR_DIR_INO, ROOT_DIR_INO,
+    };
+    use crate::org::{calendar::OrgCalendar, tasklist::OrgTaskList, Lists, ToOrg};
+    use crate::{write::WriteCommand, Pid};
+
+    // the options as the flags default them
+    fn test_options() -> FsOptions {
+        FsOptions {
+            calendars_dir: "calendars".to_owned(),
+            tasks_dir: "tasks".to_owned(),
+            ics: false,
+            inbox: None,
+            read_only_calendars: Vec::new(),
+            read_only_tasklists: Vec::new(),
+            layout: Layout::Flat,
+            extension: "org".to_owned(),
+        }
+    }
+
+    // a filesystem of `calendars` and `tasklists`, with the receiving ends of its channels and the
+    // state of its open files
+    #[allow(clippy::type_complexity)]
+    fn test_fs(
+        calendars: Lists<OrgCalendar>,
+        tasklists: Lists<OrgTaskList>,
+        options: FsOptions,
+    ) -> (
+        OrgFS,
+        tokio::sync::mpsc::Receiver<WriteCommand>,
+        tokio::sync::mpsc::UnboundedReceiver<Pid>,
+        Arc<Mutex<HashMap<Instance, InstanceState>>>,
+    ) {
+        let (tx_wcmd, rx_wcmd) = tokio::sync::mpsc::channel(1024);
+        let (tx_fh, rx_fh) = tokio::sync::mpsc::unbounded_channel();
+        let pending_fh = Arc::new(Mutex::new(HashMap::new()));
+        let fs = OrgFS::new(
+            calendars,
+            tasklists,
+            tx_wcmd,
+            tx_fh,
+            pending_fh.clone(),
+            options,
+        );
+        (fs, rx_wcmd, rx_fh, pending_fh)
+    }
+
+    #[test]
+    fn recycled_pid_snapshot_discarded() {
+        let state = |started| InstanceState {
+            file_handles: vec![1],
+            started,
+            org: Org::parse("* stale\n"),
+            remote: String::new(),
+            write_buffer: Vec::new(),
+            write_time: std::time::UNIX_EPOCH,
+            dirty: false,
+        };
+        let mut pending_fh =
+            HashMap::from([((2, 100), state(Some(1))), ((2, 200), state(Some(1)))]);
+
+        // the same process opening another file keeps its snapshots
+        purge_recycled_pid(&mut pending_fh, 100, Some(1));
+        assert_eq!(pending_fh.len(), 2);
+
+        // a new process with the same PID doesn't inherit them
+        purge_recycled_pid(&mut pending_fh, 100, Some(2));
+        assert!(!pending_fh.contains_key(&(2, 100)));
+        assert!(pending_fh.contains_key(&(2, 200)));
+    }
+
+    #[test]
+    fn own_process_start_time() {
+        assert!(super::process_start_time(std::process::id()).is_some());
+    }
+
+    #[test]
+    fn write_past_end() {
+        let mut buffer = b"* TODO".to_vec();
+        // the second chunk of a write arriving first
+        write_at(&mut buffer, 8, b"b\n").unwrap();
+        assert_eq!(buffer, b"* TODO\0\0b\n");
+        write_at(&mut buffer, 6, b" A").unwrap();
+        assert_eq!(buffer, b"* TODO Ab\n");
+        assert_eq!(without_padding(&buffer), b"* TODO Ab\n");
+    }
+
+    #[test]
+    fn oversized_buffer_refused() {
+        let mut buffer = b"* TODO".to_vec();
+        // `fallocate -l 100G`, `truncate -s 1T` and a write at the very end of the file offsets
+        assert_eq!(preallocate(&mut buffer, 100 << 30), Err(libc::EFBIG));
+        assert_eq!(preallocate(&mut buffer, 1 << 40), Err(libc::EFBIG));
+        assert_eq!(write_at(&mut buffer, u64::MAX, b"x"), Err(libc::EFBIG));
+        assert_eq!(buffer, b"* TODO");
+        assert_eq!(preallocate(&mut buffer, 4096), Ok(()));
+        assert_eq!(buffer.len(), 4096);
+    }
+
+    #[test]
+    fn read_multibyte_in_chunks() {
+        let date = EventDateTime {
+            date: chrono::NaiveDate::from_ymd_opt(1970, 1, 1),
+            ..EventDateTime::default()
+        };
+        let calendar = OrgCalendar::from((
+            CalendarListEntry::default(),
+            Events {
+                items: Some(vec![Event {
+                    id: Some("party".to_owned()),
+                    summary: Some("🎉 Party 🥳".to_owned()),
+                    start: Some(date.clone()),
+                    end: Some(date),
+                    ..Event::default()
+                }]),
+                ..Events::default()
+            },
+        ));
+        let contents = calendar.to_org_string();
+        let attr = file_attr(0, 0, 0, contents.len() as u64, std::time::UNIX_EPOCH);
+
+        // chunks of 3 bytes split the 4-byte emoji
+        let mut read = Vec::new();
+        while (read.len() as u64) < attr.size {
+            read.extend_from_slice(read_chunk(contents.as_bytes(), read.len(), 3));
+        }
+        assert!(read_chunk(contents.as_bytes(), read.len(), 3).is_empty());
+        assert_eq!(String::from_utf8(read).unwrap(), contents);
+        assert!(contents.contains("🎉 Party 🥳"));
+    }
+
+    #[test]
+    fn read_only_calendar() {
+        let calendar = |id: &str| {
+            OrgCalendar::from((
+                CalendarListEntry {
+                    id: Some(id.to_owned()),
+                    summary: Some(id.to_owned()),
+                    access_role: Some("owner".to_owned()),
+                    ..CalendarListEntry::default()
+                },
+                Events::default(),
+            ))
+        };
+        let (mut fs, _rx_wcmd, _rx_fh, _pending_fh) = test_fs(
+            Arc::new(RwLock::new(vec![
+                calendar("holidays"),
+                calendar("personal"),
+            ])),
+            Arc::default(),
+            FsOptions {
+                read_only_calendars: vec!["holidays".to_owned()],
+                ..test_options()
+            },
+        );
+        assert!(fs.is_read_only_file(fs.calendars[0].0));
+        assert!(!fs.is_read_only_file(fs.calendars[1].0));
+
+        // and stays so while a process has it open
+        let pid = std::process::id();
+        for (ino, perm) in [(fs.calendars[0].0, 0o444), (fs.calendars[1].0, 0o644)] {
+            fs.allocate_stateful_file_handle(ino, pid);
+            assert_eq!(fs.instance_attr(ino, pid).unwrap().perm, perm);
+        }
+    }
+
+    #[test]
+    fn lists_added_and_removed() {
+        let calendar = |id: &str| {
+            OrgCalendar::from((
+                CalendarListEntry {
+                    id: Some(id.to_owned()),
+                    summary: Some(id.to_owned()),
+                    access_role: Some("owner".to_owned()),
+                    ..CalendarListEntry::default()
+                },
+                Events::default(),
+            ))
+        };
+        let calendars = Arc::new(RwLock::new(vec![calendar("holidays")]));
+        let (mut fs, _rx_wcmd, _rx_fh, _pending_fh) = test_fs(
+            calendars.clone(),
+            Arc::default(),
+            FsOptions {
+                read_only_calendars: vec!["personal".to_owned()],
+                ..test_options()
+            },
+        );
+        let files = |fs: &OrgFS| {
+            fs.dir_entries(fs.view(CALENDAR_DIR_INO).unwrap())
+                .into_iter()
+                .map(|(_, _, name)| name)
+                .filter(|name| name.ends_with(".org"))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(files(&fs), ["holidays.org"]);
+
+        // a calendar subscribed to while mounted
+        calendars.write().unwrap().push(calendar("personal"));
+        fs.pick_up_lists();
+        assert_eq!(files(&fs), ["holidays.org", "personal.org"]);
+        assert!(fs.is_read_only_file(fs.calendars[1].0));
+
+        // and one deleted upstream, keeping the other's inode
+        let personal = fs.calendars[1].0;
+        fs.calendars[0].1.mark_removed();
+        assert_eq!(files(&fs), ["personal.org"]);
+        assert!(fs.get_inode(fs.calendars[0].0).is_none());
+        assert!(fs.get_inode(personal).is_some());
+    }
+
+    #[test]
+    fn file_extension() {
+        let calendar = OrgCalendar::from((
+            CalendarListEntry {
+                id: Some("holidays".to_owned()),
+                summary: Some("holidays".to_owned()),
+                ..CalendarListEntry::default()
+            },
+            Events::default(),
+        ));
+        let (mut fs, _rx_wcmd, _rx_fh, _pending_fh) = test_fs(
+            Arc::new(RwLock::new(vec![calendar])),
+            Arc::default(),
+            FsOptions {
+                extension: "org_archive".to_owned(),
+                ..test_options()
+            },
+        );
+        let names = |fs: &mut OrgFS, ino| {
+            fs.listing(ino)
+                .unwrap()
+                .into_iter()
+                .map(|(_, _, name)| name)
+                .collect::<Vec<_>>()
+        };
+        assert!(names(&mut fs, ROOT_DIR_INO).contains(&"agenda.org_archive".to_owned()));
+        assert!(names(&mut fs, CALENDAR_DIR_INO).contains(&"holidays.org_archive".to_owned()));
+
+        // and the list is a directory of its own under `--layout nested`
+        fs.options.layout = Layout::Nested;
+        assert!(names(&mut fs, CALENDAR_DIR_INO).contains(&"holidays".to_owned()));
+
+        assert!(super::parse_extension("org").is_ok());
+        assert!(super::parse_extension("../org").is_err());
+        assert!(super::parse_extension("").is_err());
+        assert!(super::parse_extension("json").is_err());
+        assert!(super::parse_extension(&"x".repeat(super::NAME_MAX)).is_err());
+        let longest = "x".repeat(super::NAME_MAX - "x-01234567.".len());
+        assert!(super::parse_extension(&longest).is_ok());
+        assert!(super::file_name(&"y".repeat(super::NAME_MAX), &longest).len() <= super::NAME_MAX);
+    }
+
+    #[test]
+    fn list_dir_names() {
+        assert!(super::parse_dir_name("calendars").is_ok());
+        assert!(super::parse_dir_name("agenda").is_ok());
+        for name in [
+            "",
+            "/",
+            "a/b",
+            "..",
+            "ics",
+            ".status",
+            "agenda.org",
+            "today.txt",
+        ] {
+            assert!(super::parse_dir_name(name).is_err(), "{name:?}");
+        }
+        assert!(super::parse_dir_name("a\0b").is_err());
+    }
+
+    #[test]
+    fn long_names_truncated() {
+        let calendar = |summary: String| {
+            OrgCalendar::from((
+                CalendarListEntry {
+                    id: Some(summary.clone()),
+                    summary: Some(summary),
+                    ..CalendarListEntry::default()
+                },
+                Events::default(),
+            ))
+        };
+        let (mut fs, _rx_wcmd, _rx_fh, _pending_fh) = test_fs(
+            Arc::new(RwLock::new(vec![
+                calendar("é".repeat(299) + "a"),
+                calendar("é".repeat(299) + "b"),
+            ])),
+            Arc::default(),
+            test_options(),
+        );
+        let files = fs
+            .listing(CALENDAR_DIR_INO)
+            .unwrap()
+            .into_iter()
+            .filter(|(_, _, name)| name.ends_with(".org"))
+            .collect::<Vec<_>>();
+        assert_eq!(files.len(), 2);
+        assert_ne!(files[0].2, files[1].2);
+        for (ino, _, name) in files {
+            assert!(name.len() <= 255);
+            assert!(name.starts_with("éé"));
+            assert!(fs.render(ino).unwrap().contains("#+TITLE: éé"));
+        }
+    }
+
+    #[test]
+    fn dir_size_totals_files() {
+        let calendar = |id: &str| {
+            OrgCalendar::from((
+                CalendarListEntry {
+                    id: Some(id.to_owned()),
+                    summary: Some(id.to_owned()),
+                    ..CalendarListEntry::default()
+                },
+                Events::default(),
+            ))
+        };
+        let (fs, _rx_wcmd, _rx_fh, _pending_fh) = test_fs(
+            Arc::new(RwLock::new(vec![
+                calendar("holidays"),
+                calendar("personal"),
+            ])),
+            Arc::default(),
+            test_options(),
+        );
+        let size = fs
+            .dir_entries(fs.view(CALENDAR_DIR_INO).unwrap())
+            .into_iter()
+            .filter(|(_, kind, _)| *kind == fuser::FileType::RegularFile)
+            .map(|(ino, _, _)| fs.get_inode(ino).unwrap().size)
+            .sum::<u64>();
+        assert!(size > 0);
+        let attr = fs.get_inode(CALENDAR_DIR_INO).unwrap();
+        assert_eq!(attr.size, size);
+        assert_eq!(attr.blocks, size.div_ceil(512));
+    }
+
+    #[test]
+    fn counts_file() {
+        let date = EventDateTime {
+            date: chrono::NaiveDate::from_ymd_opt(1970, 1, 1),
+            ..EventDateTime::default()
+        };
+        let event = |id: &str| Event {
+            id: Some(id.to_owned()),
+            summary: Some(id.to_owned()),
+            start: Some(date.clone()),
+            end: Some(date.clone()),
+            ..Event::default()
+        };
+        let calendar = OrgCalendar::from((
+            CalendarListEntry {
+                id: Some("personal".to_owned()),
+                summary: Some("Personal".to_owned()),
+                ..CalendarListEntry::default()
+            },
+            Events {
+                items: Some(vec![event("party"), event("dinner")]),
+                ..Events::default()
+            },
+        ));
+        let (mut fs, _rx_wcmd, _rx_fh, _pending_fh) = test_fs(
+            Arc::new(RwLock::new(vec![calendar])),
+            Arc::default(),
+            test_options(),
+        );
+        let (ino, _, _) = fs
+            .listing(ROOT_DIR_INO)
+            .unwrap()
+            .into_iter()
+            .find(|(_, _, name)| name == ".counts")
+            .unwrap();
+        assert_eq!(fs.render(ino).unwrap(), "calendar\tPersonal\t2\t0\n");
+    }
+
+    #[test]
+    fn today_file() {
+        use chrono::{Local, TimeDelta};
+        use google_tasks1::api::{Task, TaskList, Tasks};
+
+        use crate::org::tasklist::OrgTaskList;
+
+        let today = Local::now().date_naive();
+        let on = |date| EventDateTime {
+            date: Some(date),
+            ..EventDateTime::default()
+        };
+        let at = |hour| EventDateTime {
+            date_time: Some(
+                today
+                    .and_hms_opt(hour, 0, 0)
+                    .unwrap()
+                    .and_local_timezone(Local)
+                    .earliest()
+                    .unwrap()
+                    .to_utc(),
+            ),
+            ..EventDateTime::default()
+        };
+        let event = |summary: &str, start, end| Event {
+            id: Some(summary.to_lowercase()),
+            summary: Some(summary.to_owned()),
+            start: Some(start),
+            end: Some(end),
+            ..Event::default()
+        };
+        let yesterday = today - TimeDelta::days(1);
+        let tomorrow = today + TimeDelta::days(1);
+        let calendar = OrgCalendar::from((
+            CalendarListEntry {
+                id: Some("personal".to_owned()),
+                summary: Some("Personal".to_owned()),
+                ..CalendarListEntry::default()
+            },
+            Events {
+                items: Some(vec![
+                    event("Lunch", at(12), at(13)),
+                    event("Holiday", on(today), on(tomorrow)),
+                    event("Party", on(yesterday), on(today)),
+                ]),
+                ..Events::default()
+            },
+        ));
+        let task = |title: &str, due: chrono::NaiveDate, status: &str| Task {
+            id: Some(title.to_lowercase()),
+            title: Some(title.to_owned()),
+            due: Some(format!("{due}T00:00:00.000Z")),
+            status: Some(status.to_owned()),
+            ..Task::default()
+        };
+        let tasklist = OrgTaskList::from((
+            TaskList {
+                id: Some("work".to_owned()),
+                title: Some("Work".to_owned()),
+                ..TaskList::default()
+            },
+            Tasks {
+                items: Some(vec![
+                    task("Report", today, "needsAction"),
+                    task("Slides", tomorrow, "needsAction"),
+                    task("Email", today, "completed"),
+                ]),
+                ..Tasks::default()
+            },
+        ));
+        let (mut fs, _rx_wcmd, _rx_fh, _pending_fh) = test_fs(
+            Arc::new(RwLock::new(vec![calendar])),
+            Arc::new(RwLock::new(vec![tasklist])),
+            test_options(),
+        );
+        let (ino, _, _) = fs
+            .listing(ROOT_DIR_INO)
+            .unwrap()
+            .into_iter()
+            .find(|(_, _, name)| name == "today.org")
+            .unwrap();
+        assert!(fs.is_read_only_file(ino));
+        let today = fs.render(ino).unwrap();
+        let headlines = today
+            .lines()
+            .filter(|line| line.starts_with("* "))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            headlines,
+            [
+                "* Holiday :Personal:",
+                "* TODO Report :Work:",
+                "* Lunch :Personal:"
+            ]
+        );
+    }
+
+    #[test]
+    fn concurrent_editors() {
+        use crate::{client::MockClient, write::process_write};
+
+        let date = EventDateTime {
+            date: chrono::NaiveDate::from_ymd_opt(1970, 1, 1),
+            ..EventDateTime::default()
+        };
+        let calendars = Arc::new(RwLock::new(vec![OrgCalendar::from((
+            CalendarListEntry {
+                id: Some("personal".to_owned()),
+                summary: Some("Personal".to_owned()),
+                access_role: Some("owner".to_owned()),
+                ..CalendarListEntry::default()
+            },
+            Events {
+                items: Some(vec![Event {
+                    id: Some("party".to_owned()),
+                    etag: Some("\"1\"".to_owned()),
+                    summary: Some("Party".to_owned()),
+                    description: Some("Bring snacks".to_owned()),
+                    start: Some(date.clone()),
+                    end: Some(date),
+                    ..Event::default()
+                }]),
+                ..Events::default()
+            },
+        ))]));
+        let (mut fs, mut rx_wcmd, _rx_fh, pending_fh) =
+            test_fs(calendars.clone(), Arc::default(), test_options());
+        let client = MockClient::default();
+
+        // three editors open the file before any of them saves
+        let ino = fs.calendars[0].0;
+        let (first, second, third) = (1_000_001, 1_000_002, 1_000_003);
+        for pid in [first, second, third] {
+            fs.allocate_stateful_file_handle(ino, pid);
+        }
+        let mut save = |fs: &mut OrgFS, pid, from: &str, to: &str| {
+            {
+                let mut guard = pending_fh.lock().unwrap();
+                let state = guard.get_mut(&(ino, pid)).unwrap();
+                let edited = String::from_utf8_lossy(&state.write_buffer).replace(from, to);
+                state.write_buffer = edited.into_bytes();
+            }
+            fs.reconcile(ino, pid);
+            while let Ok(wcmd) = rx_wcmd.try_recv() {
+                let calendars = calendars.read().unwrap().clone();
+                futures::executor::block_on(process_write(&client, &calendars, &[], wcmd));
+            }
+        };
+        let rendered = || calendars.read().unwrap()[0].to_org_string();
+
+        // the second's save keeps the title the first changed
+        save(&mut fs, first, "* Party", "* Birthday party");
+        save(&mut fs, second, "Bring snacks", "Bring cake");
+        let rendered_after = rendered();
+        assert!(rendered_after.contains("* Birthday party\n"));
+        assert!(rendered_after.contains("Bring cake"));
+        assert!(!rendered_after.contains("<<<<<<<"));
+
+        // but the third changing the title as well conflicts, and isn't sent
+        let writes = client.writes.lock().unwrap().len();
+        save(&mut fs, third, "* Party", "* Picnic");
+        assert_eq!(client.writes.lock().unwrap().len(), writes);
+        let rendered_after = rendered();
+        assert!(rendered_after.contains("<<<<<<< remote (read only)\n* COMMENT Birthday party"));
+        assert!(rendered_after.contains("* Picnic\n"));
+    }
+
+    #[test]
+    fn edit_reaches_google() {
+        use crate::{
+            client::MockClient,
+            write::{process_write, CalendarEventModify, CalendarEventWrite, WriteCommand},
+        };
+
+        let date = EventDateTime {
+            date: chrono::NaiveDate::from_ymd_opt(1970, 1, 1),
+            ..EventDateTime::default()
+        };
+        let calendars = Arc::new(RwLock::new(vec![OrgCalendar::from((
+            CalendarListEntry {
+                id: Some("personal".to_owned()),
+                summary: Some("Personal".to_owned()),
+                access_role: Some("owner".to_owned()),
+                ..CalendarListEntry::default()
+            },
+            Events {
+                items: Some(vec![Event {
+                    id: Some("party".to_owned()),
+                    etag: Some("\"1\"".to_owned()),
+                    summary: Some("Party".to_owned()),
+                    start: Some(date.clone()),
+                    end: Some(date),
+                    ..Event::default()
+                }]),
+                ..Events::default()
+            },
+        ))]));
+        let (mut fs, mut rx_wcmd, _rx_fh, pending_fh) =
+            test_fs(calendars.clone(), Arc::default(), test_options());
+
+        // what `open`, `write` and `fsync` do for an editor saving the file
+        let ino = fs.calendars[0].0;
+        let pid = std::process::id();
+        fs.allocate_stateful_file_handle(ino, pid);
+        {
+            let mut guard = pending_fh.lock().unwrap();
+            let state = guard.get_mut(&(ino, pid)).unwrap();
+            let edited =
+                String::from_utf8_lossy(&state.write_buffer).replace("* Party", "* Birthday party");
+            state.write_buffer = edited.into_bytes();
+        }
+        fs.reconcile(ino, pid);
+
+        let client = MockClient::default();
+        while let Ok(wcmd) = rx_wcmd.try_recv() {
+            let calendars = calendars.read().unwrap().clone();
+            futures::executor::block_on(process_write(&client, &calendars, &[], wcmd));
+        }
+        let writes = client.writes.into_inner().unwrap();
+        let [WriteCommand::CalendarEvent {
+            calendar_id,
+            cmd:
+                CalendarEventWrite::Modify {
+                    event_id,
+                    modification: CalendarEventModify::Patch { event },
+                },
+        }] = writes.as_slice()
+        else {
+            panic!("expected a single event patch, got {writes:?}");
+        };
+        assert_eq!(calendar_id, "personal");
+        assert_eq!(event_id, "party");
+        assert_eq!(event.summary.as_deref(), Some("Birthday party"));
+        assert!(calendars.read().unwrap()[0]
+            .to_org_string()
+            .contains("* Birthday party"));
+    }
+
+    #[test]
+    fn preallocated_write() {
+        use crate::{client::MockClient, write::process_write};
+
+        let date = EventDateTime {
+            date: chrono::NaiveDate::from_ymd_opt(1970, 1, 1),
+            ..EventDateTime::default()
+        };
+        let calendars = Arc::new(RwLock::new(vec![OrgCalendar::from((
+            CalendarListEntry {
+                id: Some("personal".to_owned()),
+                summary: Some("Personal".to_owned()),
+                access_role: Some("owner".to_owned()),
+                ..CalendarListEntry::default()
+            },
+            Events {
+                items: Some(vec![Event {
+                    id: Some("party".to_owned()),
+                    etag: Some("\"1\"".to_owned()),
+                    summary: Some("Party".to_owned()),
+                    start: Some(date.clone()),
+                    end: Some(date),
+                    ..Event::default()
+                }]),
+                ..Events::default()
+            },
+        ))]));
+        let (mut fs, mut rx_wcmd, _rx_fh, pending_fh) =
+            test_fs(calendars.clone(), Arc::default(), test_options());
+
+        // an editor truncating the file, preallocating more than it needs, then writing it out in
+        // two chunks
+        let ino = fs.calendars[0].0;
+        let pid = std::process::id();
+        fs.allocate_stateful_file_handle(ino, pid);
+        {
+            let mut guard = pending_fh.lock().unwrap();
+            let state = guard.get_mut(&(ino, pid)).unwrap();
+            let edited = String::from_utf8_lossy(&state.write_buffer)
+                .replace("* Party", "* Birthday party")
+                .into_bytes();
+            state.write_buffer.clear();
+            preallocate(&mut state.write_buffer, edited.len() as u64 + 4096).unwrap();
+            let (head, tail) = edited.split_at(edited.len() / 2);
+            write_at(&mut state.write_buffer, 0, head).unwrap();
+            write_at(&mut state.write_buffer, head.len() as u64, tail).unwrap();
+            assert_eq!(without_padding(&state.write_buffer), edited);
+        }
+        fs.reconcile(ino, pid);
+
+        let client = MockClient::default();
+        while let Ok(wcmd) = rx_wcmd.try_recv() {
+            let calendars = calendars.read().unwrap().clone();
+            futures::executor::block_on(process_write(&client, &calendars, &[], wcmd));
+        }
+        assert_eq!(client.writes.lock().unwrap().len(), 1);
+        let rendered = calendars.read().unwrap()[0].to_org_string();
+        assert!(rendered.contains("* Birthday party\n"));
+        assert!(!rendered.contains('\0'));
+    }
+
+    #[test]
+    fn nested_layout() {
+        use crate::{client::MockClient, write::process_write};
+
+        let date = EventDateTime {
+            date: chrono::NaiveDate::from_ymd_opt(1970, 1, 1),
+            ..EventDateTime::default()
+        };
+        let calendars = Arc::new(RwLock::new(vec![OrgCalendar::from((
+            CalendarListEntry {
+                id: Some("personal".to_owned()),
+                summary: Some("Personal".to_owned()),
+                access_role: Some("owner".to_owned()),
+                ..CalendarListEntry::default()
+            },
+            Events {
+                items: Some(vec![Event {
+                    id: Some("party".to_owned()),
+                    etag: Some("\"1\"".to_owned()),
+                    summary: Some("Party".to_owned()),
+                    start: Some(date.clone()),
+                    end: Some(date),
+                    ..Event::default()
+                }]),
+                ..Events::default()
+            },
+        ))]));
+        let (mut fs, mut rx_wcmd, _rx_fh, pending_fh) = test_fs(
+            calendars.clone(),
+            Arc::default(),
+            FsOptions {
+                layout: Layout::Nested,
+                ..test_options()
+            },
+        );
+
+        // the calendar is a directory beside its `.json`, holding a file for the event
+        let list = fs.calendars[0].0;
+        let entries = fs.listing(CALENDAR_DIR_INO).unwrap();
+        assert!(entries.contains(&(list, fuser::FileType::Directory, "Personal".to_owned())));
+        assert!(entries.iter().any(|(_, _, name)| name == "Personal.json"));
+        let (ino, _, _) = fs
+            .listing(list)
+            .unwrap()
+            .into_iter()
+            .find(|(_, _, name)| name == "party.org")
+            .unwrap();
+        let contents = fs.render(ino).unwrap();
+        assert!(contents.starts_with("* Party\n"));
+        assert_eq!(fs.get_inode(ino).unwrap().size, contents.len() as u64);
+        assert_eq!(fs.get_inode(list).unwrap().size, contents.len() as u64);
+
+        let client = MockClient::default();
+        let pid = std::process::id();
+        let mut save = |fs: &mut OrgFS, edit: &dyn Fn(&str) -> String| {
+            fs.allocate_stateful_file_handle(ino, pid);
+            {
+                let mut guard = pending_fh.lock().unwrap();
+                let state = guard.get_mut(&(ino, pid)).unwrap();
+                state.write_buffer = edit(&String::from_utf8_lossy(&state.write_buffer)).into();
+            }
+            fs.reconcile(ino, pid);
+            pending_fh.lock().unwrap().clear();
+            while let Ok(wcmd) = rx_wcmd.try_recv() {
+                let calendars = calendars.read().unwrap().clone();
+                futures::executor::block_on(process_write(&client, &calendars, &[], wcmd));
+            }
+        };
+
+        // emptying the file doesn't delete the event
+        save(&mut fs, &|_| String::new());
+        assert!(client.writes.lock().unwrap().is_empty());
+
+        // but editing it patches just that event
+        save(&mut fs, &|contents| {
+            contents.replace("* Party", "* Birthday party")
+        });
+        assert_eq!(client.writes.lock().unwrap().len(), 1);
+        assert!(fs.render(ino).unwrap().starts_with("* Birthday party\n"));
+        assert!(calendars.read().unwrap()[0]
+            .to_org_string()
+            .contains("* Birthday party"));
+    }
+}
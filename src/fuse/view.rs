@@ -0,0 +1,402 @@
+use std::{
+    borrow::Cow,
+    sync::atomic::Ordering,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use itertools::Itertools;
+
+use crate::org::{
+    calendar::{agenda_headlines, agenda_to_org_string},
+    tasklist::due_headlines,
+    timestamp::display_date,
+    MetaPendingContainer, ToOrg,
+};
+
+use super::{Inode, OrgFS, CALENDAR_DIR_INO, ICS_DIR_INO, ROOT_DIR_INO, TASKS_DIR_INO};
+
+// files inside a view are numbered by slot, with the view's directory inode in the high bits
+const SLOT_BITS: u32 = 32;
+
+pub(super) const fn file_ino(dir: Inode, slot: usize) -> Inode {
+    (dir << SLOT_BITS) | slot as Inode
+}
+
+pub(super) const fn parent_ino(ino: Inode) -> Inode {
+    ino >> SLOT_BITS
+}
+
+pub(super) const fn slot(ino: Inode) -> usize {
+    (ino & ((1 << SLOT_BITS) - 1)) as usize
+}
+
+// item files under `--layout nested` are numbered apart from the rest, in the order they're first
+// listed
+const ITEM_INO: Inode = 1 << 63;
+
+pub(super) const fn item_ino(index: usize) -> Inode {
+    ITEM_INO | index as Inode
+}
+
+pub(super) const fn item_index(ino: Inode) -> Option<usize> {
+    if ino & ITEM_INO != 0 {
+        Some((ino & !ITEM_INO) as usize)
+    } else {
+        None
+    }
+}
+
+// flags the slot of a list's read-only `.json` sibling, beside its `.org` file in the same slot
+const JSON_SLOT: usize = 1 << (SLOT_BITS - 1);
+
+pub(super) const fn is_json_slot(slot: usize) -> bool {
+    slot & JSON_SLOT != 0
+}
+
+// the slot in `calendars`/`tasklists` of the list rendered in `slot`
+pub(super) const fn list_slot(slot: usize) -> usize {
+    slot & !JSON_SLOT
+}
+
+// the longest filename most filesystems (and so editors) cope with, in bytes
+pub(super) const NAME_MAX: usize = 255;
+
+/// `stem.extension`, cut short to fit in [`NAME_MAX`] bytes if need be.
+///
+/// A shortened stem ends in a hash of the whole of it, so that names differing only past the cut
+/// stay apart; the hash is FNV-1a rather than std's, which may change between releases, to give
+/// the same filename on every run.
+pub(super) fn file_name(stem: &str, extension: &str) -> String {
+    if stem.len() + 1 + extension.len() <= NAME_MAX {
+        return format!("{}.{}", stem, extension);
+    }
+    let hash = stem.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x01000193)
+    });
+    let mut end = NAME_MAX.saturating_sub(1 + 8 + 1 + extension.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}-{:08x}.{}", &stem[..end], hash, extension)
+}
+
+/// A virtual directory of rendered files.
+///
+/// The root directory is itself a view (with an empty name) whose entries are the top-level
+/// files; every other view is listed in the root under its `name`.
+pub(super) struct View {
+    pub(super) name: Cow<'static, str>,
+    pub(super) ino: Inode,
+    pub(super) writable: bool,
+    /// (slot, filename) of every file in the directory
+    pub(super) entries: fn(&OrgFS) -> Vec<(usize, String)>,
+    /// contents and modification time of the file in `slot`
+    pub(super) render: fn(&OrgFS, usize) -> Option<(String, SystemTime)>,
+}
+
+pub(super) const ROOT_VIEW: View = View {
+    name: Cow::Borrowed(""),
+    ino: ROOT_DIR_INO,
+    writable: false,
+    entries: |fs| {
+        let mut entries = vec![
+            (AGENDA_SLOT, fs.org_file_name(AGENDA_FILE_STEM)),
+            (TODAY_SLOT, fs.org_file_name(TODAY_FILE_STEM)),
+            (STATUS_SLOT, STATUS_FILE_NAME.to_owned()),
+            (COUNTS_SLOT, COUNTS_FILE_NAME.to_owned()),
+            (REFRESH_SLOT, REFRESH_FILE_NAME.to_owned()),
+        ];
+        if fs.inbox.is_some() {
+            entries.push((INBOX_SLOT, fs.org_file_name(INBOX_FILE_STEM)));
+        }
+        entries
+    },
+    render: |fs, slot| match slot {
+        AGENDA_SLOT => Some((
+            agenda_to_org_string(
+                fs.calendars
+                    .iter()
+                    .map(|(_, cal)| cal)
+                    .filter(|cal| !cal.is_removed()),
+            ),
+            fs.calendars
+                .iter()
+                .map(|(_, cal)| cal.with_meta(|m| m.updated().load(Ordering::Acquire)))
+                .max()
+                .unwrap_or(UNIX_EPOCH),
+        )),
+        // the day changes without anything being synced, so it's always as of now
+        TODAY_SLOT => Some((render_today(fs), SystemTime::now())),
+        STATUS_SLOT => Some((render_status(fs), SystemTime::now())),
+        COUNTS_SLOT => Some((render_counts(fs), SystemTime::now())),
+        REFRESH_SLOT => Some((
+            if fs.last_refresh == UNIX_EPOCH {
+                String::new()
+            } else {
+                format!(
+                    "{}\n",
+                    chrono::DateTime::<chrono::Local>::from(fs.last_refresh).to_rfc3339()
+                )
+            },
+            fs.last_refresh,
+        )),
+        // captured headlines are sent off as soon as they're appended
+        INBOX_SLOT if fs.inbox.is_some() => Some((String::new(), UNIX_EPOCH)),
+        _ => None,
+    },
+};
+
+const AGENDA_SLOT: usize = 0;
+const AGENDA_FILE_STEM: &str = "agenda";
+const STATUS_SLOT: usize = 1;
+const STATUS_FILE_NAME: &str = ".status";
+const REFRESH_SLOT: usize = 2;
+const REFRESH_FILE_NAME: &str = ".refresh";
+/// Writing anything to this file syncs everything at once; reading it gives the time of the last
+/// such refresh.
+pub(super) const REFRESH_INO: Inode = file_ino(ROOT_DIR_INO, REFRESH_SLOT);
+const INBOX_SLOT: usize = 3;
+const INBOX_FILE_STEM: &str = "inbox";
+/// Appending headlines to this file creates them as tasks in the `--inbox` tasklist.
+pub(super) const INBOX_INO: Inode = file_ino(ROOT_DIR_INO, INBOX_SLOT);
+const COUNTS_SLOT: usize = 4;
+const COUNTS_FILE_NAME: &str = ".counts";
+const TODAY_SLOT: usize = 5;
+const TODAY_FILE_STEM: &str = "today";
+
+/// Whether a directory called `name` would clash with one of the root's own entries, whatever the
+/// `--extension` and whether or not they're enabled.
+pub(super) fn is_root_entry(name: &str) -> bool {
+    [
+        STATUS_FILE_NAME,
+        REFRESH_FILE_NAME,
+        COUNTS_FILE_NAME,
+        &ICS_VIEW.name,
+    ]
+    .contains(&name)
+        || name.split_once('.').is_some_and(|(stem, _)| {
+            [AGENDA_FILE_STEM, TODAY_FILE_STEM, INBOX_FILE_STEM].contains(&stem)
+        })
+}
+
+// the events and due tasks of today, from every calendar and tasklist, in the order they happen
+fn render_today(fs: &OrgFS) -> String {
+    let today = display_date(&chrono::Utc::now());
+    let events = agenda_headlines(
+        fs.calendars
+            .iter()
+            .map(|(_, cal)| cal)
+            .filter(|cal| !cal.is_removed()),
+        Some(today),
+    );
+    let tasks = due_headlines(
+        fs.tasklists
+            .iter()
+            .map(|(_, tl)| tl)
+            .filter(|tl| !tl.is_removed()),
+        today,
+    );
+    events
+        .into_iter()
+        .chain(tasks)
+        .sorted_by_key(|(start, _)| *start)
+        .map(|(_, str)| str)
+        .join("\n")
+}
+
+fn render_status(fs: &OrgFS) -> String {
+    fn format_time(time: SystemTime) -> String {
+        if time == UNIX_EPOCH {
+            "never".to_owned()
+        } else {
+            chrono::DateTime::<chrono::Local>::from(time).to_rfc3339()
+        }
+    }
+    let mut str = String::new();
+    str.push_str("calendars:\n");
+    for (_, cal) in fs.calendars.iter().filter(|(_, cal)| !cal.is_removed()) {
+        cal.with_meta(|m| {
+            str.push_str(&format!(
+                "  {} ({})\n",
+                m.calendar().summary.as_deref().unwrap_or_default(),
+                m.calendar().id.as_deref().unwrap_or_default()
+            ));
+            str.push_str(&format!(
+                "    sync token: {}\n",
+                m.sync_token()
+                    .lock()
+                    .unwrap()
+                    .as_deref()
+                    .unwrap_or("full sync pending")
+            ));
+            str.push_str(&format!(
+                "    last sync: {}\n",
+                format_time(m.synced().load(Ordering::Acquire))
+            ));
+            str.push_str(&format!(
+                "    pending writes: {}\n",
+                m.pending().0.len() + m.pending().1.len()
+            ));
+        });
+    }
+    str.push_str("tasklists:\n");
+    for (_, tl) in fs.tasklists.iter().filter(|(_, tl)| !tl.is_removed()) {
+        tl.with_meta(|m| {
+            str.push_str(&format!(
+                "  {} ({})\n",
+                m.tasklist().title.as_deref().unwrap_or_default(),
+                m.tasklist().id.as_deref().unwrap_or_default()
+            ));
+            str.push_str(&format!(
+                "    last sync: {}\n",
+                format_time(m.synced().load(Ordering::Acquire))
+            ));
+            str.push_str(&format!(
+                "    pending writes: {}\n",
+                m.pending().0.len() + m.pending().1.len()
+            ));
+        });
+    }
+    str
+}
+
+// a line for each list, tab-separated for scripts: `calendar` or `tasklist`, its name, the number
+// of events or tasks its file shows, and its number of pending writes
+fn render_counts(fs: &OrgFS) -> String {
+    let calendars = fs
+        .calendars
+        .iter()
+        .filter(|(_, cal)| !cal.is_removed())
+        .map(|(_, cal)| {
+            cal.with_meta(|m| {
+                (
+                    "calendar",
+                    m.calendar().summary.clone().unwrap_or_default(),
+                    cal.event_count(),
+                    m.pending().0.len() + m.pending().1.len(),
+                )
+            })
+        });
+    let tasklists = fs
+        .tasklists
+        .iter()
+        .filter(|(_, tl)| !tl.is_removed())
+        .map(|(_, tl)| {
+            tl.with_meta(|m| {
+                (
+                    "tasklist",
+                    m.tasklist().title.clone().unwrap_or_default(),
+                    tl.task_count(),
+                    m.pending().0.len() + m.pending().1.len(),
+                )
+            })
+        });
+    calendars
+        .chain(tasklists)
+        .map(|(kind, name, items, pending)| format!("{kind}\t{name}\t{items}\t{pending}\n"))
+        .collect()
+}
+
+pub(super) const CALENDARS_VIEW: View = View {
+    name: Cow::Borrowed("calendars"),
+    ino: CALENDAR_DIR_INO,
+    writable: true,
+    entries: |fs| {
+        fs.calendars
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, cal))| !cal.is_removed())
+            .filter_map(|(i, (_, cal))| {
+                cal.with_meta(|m| {
+                    m.calendar().summary.as_ref().map(|summary| {
+                        [
+                            (i, fs.org_file_name(summary)),
+                            (i | JSON_SLOT, file_name(summary, "json")),
+                        ]
+                    })
+                })
+            })
+            .flatten()
+            .collect()
+    },
+    render: |fs, slot| {
+        let (_, cal) = fs.calendars.get(list_slot(slot))?;
+        (!cal.is_removed()).then(|| {
+            (
+                if is_json_slot(slot) {
+                    cal.to_json()
+                } else {
+                    cal.to_org_string()
+                },
+                cal.with_meta(|m| m.updated().load(Ordering::Acquire)),
+            )
+        })
+    },
+};
+
+pub(super) const TASKS_VIEW: View = View {
+    name: Cow::Borrowed("tasks"),
+    ino: TASKS_DIR_INO,
+    writable: true,
+    entries: |fs| {
+        fs.tasklists
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, tl))| !tl.is_removed())
+            .filter_map(|(i, (_, tl))| {
+                tl.with_meta(|m| {
+                    m.tasklist().title.as_ref().map(|title| {
+                        [
+                            (i, fs.org_file_name(title)),
+                            (i | JSON_SLOT, file_name(title, "json")),
+                        ]
+                    })
+                })
+            })
+            .flatten()
+            .collect()
+    },
+    render: |fs, slot| {
+        let (_, tl) = fs.tasklists.get(list_slot(slot))?;
+        (!tl.is_removed()).then(|| {
+            (
+                if is_json_slot(slot) {
+                    tl.to_json()
+                } else {
+                    tl.to_org_string()
+                },
+                tl.with_meta(|m| m.updated().load(Ordering::Acquire)),
+            )
+        })
+    },
+};
+
+pub(super) const ICS_VIEW: View = View {
+    name: Cow::Borrowed("ics"),
+    ino: ICS_DIR_INO,
+    writable: false,
+    entries: |fs| {
+        fs.calendars
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, cal))| !cal.is_removed())
+            .filter_map(|(i, (_, cal))| {
+                cal.with_meta(|m| {
+                    m.calendar()
+                        .summary
+                        .as_ref()
+                        .map(|summary| (i, file_name(summary, "ics")))
+                })
+            })
+            .collect()
+    },
+    render: |fs, slot| {
+        let (_, cal) = fs.calendars.get(slot)?;
+        (!cal.is_removed()).then(|| {
+            (
+                cal.to_ics(),
+                cal.with_meta(|m| m.updated().load(Ordering::Acquire)),
+            )
+        })
+    },
+};
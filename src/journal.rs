@@ -0,0 +1,261 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{
+    client,
+    org::{calendar::OrgCalendar, tasklist::OrgTaskList, MetaPendingContainer},
+    write::{process_write, CalendarEventWrite, TaskWrite, WriteCommand},
+};
+
+pub(crate) const JOURNAL_FILE_NAME: &str = "pending_writes.jsonl";
+
+/// Append-only log of write commands which have not yet reached the server.
+///
+/// Every write is appended as one JSON line before it is attempted, and the log is rewritten to
+/// hold exactly the containers' pending writes once they settle. A torn final line (from a crash
+/// mid-append) fails to parse and is skipped on replay.
+pub(crate) struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+    // whether the log may hold writes which are no longer pending
+    dirty: AtomicBool,
+}
+
+impl Journal {
+    pub(crate) fn open(path: PathBuf) -> std::io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        let len = file.metadata()?.len();
+        if len > 0 {
+            // terminate a torn final line so that it doesn't swallow the next entry
+            let mut last = [0];
+            file.seek(SeekFrom::Start(len - 1))?;
+            file.read_exact(&mut last)?;
+            if last != *b"\n" {
+                file.write_all(b"\n")?;
+            }
+        }
+        let dirty = len > 0;
+        Ok(Journal {
+            path,
+            file: Mutex::new(file),
+            dirty: AtomicBool::new(dirty),
+        })
+    }
+
+    /// Every write command in the log, in the order they were appended.
+    pub(crate) fn entries(&self) -> Vec<WriteCommand> {
+        let _guard = self.file.lock().unwrap();
+        let Ok(file) = File::open(&self.path) else {
+            return Vec::new();
+        };
+        BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str(&line) {
+                Ok(wcmd) => Some(wcmd),
+                Err(e) => {
+                    tracing::warn!(error = %e, "Skipping unreadable journal entry");
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub(crate) fn append(&self, wcmd: &WriteCommand) {
+        let mut line = serde_json::to_string(wcmd).expect("Failed to serialize write command");
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file
+            .write_all(line.as_bytes())
+            .and_then(|()| file.sync_data())
+        {
+            tracing::error!(error = %e, "Failed to journal write command");
+        }
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// Rewrite the log to contain only the writes still pending in `calendars` and `tasklists`.
+    pub(crate) fn compact(&self, calendars: &[OrgCalendar], tasklists: &[OrgTaskList]) {
+        if !self.dirty.load(Ordering::Acquire) {
+            return;
+        }
+        let mut pending = Vec::new();
+        for calendar in calendars {
+            let calendar_id = calendar.with_meta(|m| m.calendar().id.clone()).unwrap();
+            calendar.with_pending(|(inserts, modifies)| {
+                pending.extend(inserts.iter().map(|insert| WriteCommand::CalendarEvent {
+                    calendar_id: calendar_id.clone(),
+                    cmd: CalendarEventWrite::Insert(insert.clone()),
+                }));
+                pending.extend(modifies.iter().map(|(event_id, modification)| {
+                    WriteCommand::CalendarEvent {
+                        calendar_id: calendar_id.clone(),
+                        cmd: CalendarEventWrite::Modify {
+                            event_id: event_id.clone(),
+                            modification: modification.clone(),
+                        },
+                    }
+                }));
+            });
+        }
+        for tasklist in tasklists {
+            let tasklist_id = tasklist.with_meta(|m| m.tasklist().id.clone()).unwrap();
+            tasklist.with_pending(|(inserts, modifies)| {
+                pending.extend(inserts.iter().map(|insert| WriteCommand::Task {
+                    tasklist_id: tasklist_id.clone(),
+                    cmd: TaskWrite::Insert(insert.clone()),
+                }));
+                pending.extend(
+                    modifies
+                        .iter()
+                        .map(|(task_id, modification)| WriteCommand::Task {
+                            tasklist_id: tasklist_id.clone(),
+                            cmd: TaskWrite::Modify {
+                                task_id: task_id.clone(),
+                                modification: modification.clone(),
+                            },
+                        }),
+                );
+            });
+        }
+
+        let mut file = self.file.lock().unwrap();
+        // write the new log beside the old one and rename it into place, so a crash leaves one
+        // or the other intact
+        let tmp_path = self.path.with_extension("jsonl.tmp");
+        let result = (|| {
+            let mut tmp = File::create(&tmp_path)?;
+            for wcmd in &pending {
+                let mut line =
+                    serde_json::to_string(wcmd).expect("Failed to serialize write command");
+                line.push('\n');
+                tmp.write_all(line.as_bytes())?;
+            }
+            tmp.sync_all()?;
+            std::fs::rename(&tmp_path, &self.path)?;
+            OpenOptions::new().append(true).open(&self.path)
+        })();
+        match result {
+            Ok(new_file) => {
+                *file = new_file;
+                self.dirty.store(!pending.is_empty(), Ordering::Release);
+                tracing::debug!("Compacted journal to {} pending writes", pending.len());
+            }
+            Err(e) => tracing::error!(error = %e, "Failed to compact journal"),
+        }
+    }
+
+    /// Retry every write left in the log by a previous run.
+    pub(crate) async fn replay(
+        &self,
+        client: &impl client::GoogleApi,
+        calendars: &[OrgCalendar],
+        tasklists: &[OrgTaskList],
+    ) {
+        let entries = self.entries();
+        if entries.is_empty() {
+            return;
+        }
+        tracing::info!("Replaying {} journalled writes…", entries.len());
+        for wcmd in entries {
+            let known = match &wcmd {
+                WriteCommand::CalendarEvent { calendar_id, .. } => calendars
+                    .iter()
+                    .any(|cal| cal.with_meta(|m| m.calendar().id.as_ref() == Some(calendar_id))),
+                WriteCommand::Task { tasklist_id, .. } => tasklists
+                    .iter()
+                    .any(|tl| tl.with_meta(|m| m.tasklist().id.as_ref() == Some(tasklist_id))),
+                _ => false,
+            };
+            if known {
+                process_write(client, calendars, tasklists, wcmd).await;
+            } else {
+                tracing::warn!("Dropping journalled write for unknown target: {:?}", wcmd);
+            }
+        }
+        self.compact(calendars, tasklists);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use google_tasks1::api::Task;
+
+    use super::Journal;
+    use crate::write::{TaskModify, TaskWrite, WriteCommand};
+
+    #[test]
+    fn skips_torn_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(super::JOURNAL_FILE_NAME);
+        let journal = Journal::open(path.clone()).unwrap();
+        let patch = WriteCommand::Task {
+            tasklist_id: "tasklist".to_owned(),
+            cmd: TaskWrite::Modify {
+                task_id: "task".to_owned(),
+                modification: TaskModify::Patch {
+                    task: Box::new(Task {
+                        title: Some("title".to_owned()),
+                        ..Default::default()
+                    }),
+                },
+            },
+        };
+        let delete = WriteCommand::Task {
+            tasklist_id: "tasklist".to_owned(),
+            cmd: TaskWrite::Modify {
+                task_id: "task".to_owned(),
+                modification: TaskModify::Delete,
+            },
+        };
+        journal.append(&patch);
+        journal.append(&delete);
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(br#"{"Task":{"tasklist_id":"#)
+            .unwrap();
+
+        let journal = Journal::open(path).unwrap();
+        journal.append(&delete);
+        let entries = journal.entries();
+        assert_eq!(entries.len(), 3);
+        let WriteCommand::Task {
+            cmd:
+                TaskWrite::Modify {
+                    modification: TaskModify::Patch { task },
+                    ..
+                },
+            ..
+        } = &entries[0]
+        else {
+            panic!("expected a patch, got {:?}", entries[0]);
+        };
+        assert_eq!(task.title.as_deref(), Some("title"));
+        assert!(matches!(
+            entries[1],
+            WriteCommand::Task {
+                cmd: TaskWrite::Modify {
+                    modification: TaskModify::Delete,
+                    ..
+                },
+                ..
+            }
+        ));
+    }
+}
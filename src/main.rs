@@ -1,21 +1,40 @@
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc, Mutex, RwLock},
+    time::SystemTime,
 };
 
-use clap::Parser;
-use fuse::OrgFS;
+use clap::{ArgAction, CommandFactory, Parser};
+use fuse::{FsOptions, OrgFS};
 use fuser::MountOption;
-use futures::{stream, StreamExt};
+use futures::{future, stream, StreamExt};
+use google_calendar3::api::CalendarListEntry;
+use google_tasks1::api::TaskList;
 use tokio::sync::Notify;
+use tracing::Level;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, EnvFilter};
 
 use crate::{
-    org::{calendar::OrgCalendar, tasklist::OrgTaskList, MetaPendingContainer},
-    write::{process_write, WriteCommand},
+    client::GoogleApi,
+    journal::{Journal, JOURNAL_FILE_NAME},
+    org::{
+        calendar::{self, OrgCalendar},
+        tasklist::OrgTaskList,
+        Lists, MetaPendingContainer, ToOrg,
+    },
+    write::{flush_pending, process_write, WriteCommand},
 };
 
 mod client;
+mod config;
+mod daemon;
 mod fuse;
+mod journal;
+mod metrics;
 mod oauth;
 mod org;
 mod streaming;
@@ -24,88 +43,339 @@ mod write;
 pub(crate) type Pid = u32;
 
 const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(120); // 2 minutes
+const SYNC_CLOCK_SKEW: std::time::Duration = std::time::Duration::from_secs(60);
+const SHUTDOWN_FLUSH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const DEFAULT_LOAD_CONCURRENCY: NonZeroUsize = NonZeroUsize::new(8).unwrap();
+const DEFAULT_WRITE_QUEUE: NonZeroUsize = NonZeroUsize::new(256).unwrap();
 
 #[derive(Parser, Debug)]
 #[clap(author = "Nick Hu", version, about)]
 /// Application configuration
 struct Args {
     /// mount point
-    #[arg()]
-    mount: String,
+    #[arg(required_unless_present_any = ["dump", "once"])]
+    mount: Option<String>,
+    #[command(flatten)]
+    fs: FsOptions,
+    #[command(flatten)]
+    render: org::RenderOptions,
+    #[command(flatten)]
+    auth: client::AuthOptions,
+    /// extra FUSE mount option (allow_other, allow_root, auto_unmount, ro, async_read); may be
+    /// repeated
+    #[arg(short = 'o', long = "option", value_name = "OPTION", value_parser = parse_mount_option)]
+    mount_options: Vec<MountOption>,
+    /// number of calendars or tasklists to fetch at once when loading at startup
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_LOAD_CONCURRENCY)]
+    load_concurrency: NonZeroUsize,
+    /// give up if signing in to Google and loading every calendar and tasklist takes longer than
+    /// this, rather than waiting for the network indefinitely
+    #[arg(long, value_name = "SECONDS")]
+    startup_timeout: Option<u64>,
+    /// number of writes (and syncs) to queue for Google; once that many are waiting, saving a
+    /// file blocks until the oldest has been sent
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_WRITE_QUEUE)]
+    write_queue: NonZeroUsize,
+    /// log the writes that would be sent to Google and apply them locally, without sending them
+    #[arg(long)]
+    dry_run: bool,
+    /// serve a Prometheus-style snapshot of sync times, pending writes and API call counts on
+    /// this address (e.g. 127.0.0.1:9000)
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<SocketAddr>,
+    /// don't poll Google for changes: the mount shows what was loaded at startup, plus its own
+    /// writes, and is only brought up to date by writing to `.refresh` or sending SIGHUP
+    #[arg(long, conflicts_with_all = ["dump", "once"])]
+    no_poll: bool,
+    /// print every calendar and tasklist as org to stdout and exit, instead of mounting
+    #[arg(long)]
+    dump: bool,
+    /// write every calendar and tasklist as org to `--output-dir` and exit, instead of mounting
+    #[arg(long, requires = "output_dir", conflicts_with = "dump")]
+    once: bool,
+    /// directory for `--once` to write `<name>.<extension>` files to, replacing any already there
+    #[arg(long, value_name = "DIR", requires = "once")]
+    output_dir: Option<PathBuf>,
+    /// don't ask the kernel to unmount when the process dies; auto-unmounting implies
+    /// `allow_other`, which needs `user_allow_other` in /etc/fuse.conf
+    #[arg(long)]
+    no_auto_unmount: bool,
+    /// require the mount point to be an existing empty directory, rather than creating it
+    #[arg(long)]
+    no_create: bool,
+    /// read flags from this TOML file rather than config.toml in the config directory (e.g.
+    /// ~/.config/orgmode-google-fuse); flags given here take precedence over the file's
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// log more: -v for progress, -vv for debugging and -vvv for everything (`RUST_LOG`
+    /// overrides this)
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+    /// log to PATH instead of stderr, starting a new file each day and keeping a week's worth;
+    /// without PATH, to orgmode-google-fuse.log in the state directory (e.g.
+    /// ~/.local/state/orgmode-google-fuse)
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<Option<PathBuf>>,
+    /// how to write log lines
+    #[arg(long, value_enum, default_value_t)]
+    log_format: LogFormat,
+    /// run in the background once mounted, logging to `--log-file` (by default the one in the
+    /// state directory); signing in to Google, if needed, happens first
+    #[arg(long, conflicts_with_all = ["dump", "once"])]
+    daemon: bool,
 }
 
-#[tokio::main]
-async fn main() -> std::io::Result<()> {
-    tracing_subscriber::fmt::init();
-    let args = Args::parse();
-    std::fs::create_dir_all(&args.mount).expect("Failed to create mount directory");
-
-    let client = Arc::new(client::GoogleClient::new().await);
-
-    let cl = client.list_calendars().await.unwrap();
-    let sync_tokens = Arc::new(tokio::sync::Mutex::new(Vec::default()));
-    let calendars = Arc::new(
-        stream::iter(cl.items.unwrap_or_default().into_iter())
-            .filter_map(|cal| async {
-                let events = client.list_events(cal.id.as_ref().unwrap()).await.ok()?;
-                let sync_token = events.next_sync_token.as_ref().cloned();
-                sync_tokens
-                    .lock()
-                    .await
-                    .push((cal.id.clone().unwrap(), sync_token));
-                Some((cal, events).into())
-            })
-            .collect::<Vec<_>>()
-            .await,
-    );
+/// How log lines are written.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum LogFormat {
+    /// human-readable lines, with fields as `key=value` after the message
+    #[default]
+    Text,
+    /// one JSON object per line, with the message and fields as its keys, for log aggregators
+    Json,
+}
 
-    let tls = client.list_tasklists().await.unwrap();
-    let tasklists = Arc::new(
-        stream::iter(tls.items.unwrap_or_default().into_iter())
-            .filter_map(|tl| async {
-                let tasks = client.list_tasks(tl.id.as_ref().unwrap()).await.ok()?;
-                Some((tl, tasks).into())
-            })
-            .collect::<Vec<_>>()
-            .await,
-    );
+const LOG_FILE_NAME: &str = "orgmode-google-fuse.log";
+const LOG_FILES_KEPT: usize = 7;
+
+/// A daily rolling appender writing to `path`, or to the default log file in the state directory.
+///
+/// Each day's log gets the date inserted before the extension, e.g. `sync.2024-07-01.log` for
+/// `sync.log`, and all but the last [`LOG_FILES_KEPT`] are deleted.
+fn log_file_appender(path: Option<&Path>) -> std::io::Result<RollingFileAppender> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => client::state_dir().join(LOG_FILE_NAME),
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::other(format!("{} is not a file", path.display())))?;
+    let name = Path::new(name);
+    let mut builder = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .max_log_files(LOG_FILES_KEPT)
+        .filename_prefix(name.file_stem().unwrap_or_default().to_string_lossy());
+    if let Some(extension) = name.extension() {
+        builder = builder.filename_suffix(extension.to_string_lossy());
+    }
+    builder.build(dir).map_err(std::io::Error::other)
+}
+
+// the level to log at for `verbose` repetitions of `-v`, by default only warnings and errors
+fn log_level(verbose: u8) -> Level {
+    match verbose {
+        0 => Level::WARN,
+        1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE,
+    }
+}
+
+fn parse_mount_option(option: &str) -> Result<MountOption, String> {
+    match option {
+        "allow_other" => Ok(MountOption::AllowOther),
+        "allow_root" => Ok(MountOption::AllowRoot),
+        "auto_unmount" => Ok(MountOption::AutoUnmount),
+        "ro" => Ok(MountOption::RO),
+        // a libfuse session option rather than a kernel one, so it has no variant of its own
+        "async_read" => Ok(MountOption::CUSTOM(option.to_owned())),
+        _ => Err(format!(
+            "unsupported mount option `{option}`; expected one of allow_other, allow_root, \
+             auto_unmount, ro, async_read"
+        )),
+    }
+}
+
+fn main() -> std::io::Result<()> {
+    let args = Args::parse_from(config::args_with_config::<Args>());
+    if args.render.todo_keyword() == args.render.done_keyword() {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--todo-keyword and --done-keyword must differ",
+            )
+            .exit();
+    }
+    if !args.render.conflict_markers().are_distinct() {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--conflict-start, --conflict-middle and --conflict-end must differ",
+            )
+            .exit();
+    }
+    if !args.fs.list_dirs_distinct() {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--calendars-dir and --tasks-dir must differ",
+            )
+            .exit();
+    }
+    // logs go to stderr, keeping stdout for `--dump`, unless that's going away
+    let log_file = args.log_file.clone().or(args.daemon.then_some(None));
+    let writer = match &log_file {
+        Some(path) => BoxMakeWriter::new(log_file_appender(path.as_deref())?),
+        None => BoxMakeWriter::new(std::io::stderr),
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_env_filter(
+            EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| EnvFilter::new(log_level(args.verbose).to_string())),
+        );
+    match args.log_format {
+        LogFormat::Text => subscriber.with_ansi(log_file.is_none()).init(),
+        LogFormat::Json => subscriber.json().flatten_event(true).init(),
+    }
+    org::set_render_options(args.render.clone());
+    if !args.dump && !args.once {
+        // before loading anything, so that a mistyped path fails fast
+        let mount = args
+            .mount
+            .as_deref()
+            .expect("mount point is required unless dumping");
+        prepare_mount_point(Path::new(mount), !args.no_create)
+            .inspect_err(|e| tracing::error!(%mount, error = %e, "Can't mount"))?;
+    }
+
+    let detach = args.daemon.then(daemon::fork).transpose()?;
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(args, detach))
+}
+
+async fn run(args: Args, detach: Option<daemon::Detach>) -> std::io::Result<()> {
+    // dumping and dry runs never write, so needn't be allowed to
+    let writable = !(args.dump || args.once || args.dry_run);
+    let (auth, dry_run) = (args.auth, args.dry_run);
+    let concurrency = args.load_concurrency.get();
+    let startup = async {
+        let client = Arc::new(client::GoogleClient::new(auth, dry_run, writable).await);
+        // the colours are Google's own and don't change, so once is enough
+        match client.list_colors().await {
+            Ok(colors) => org::calendar::set_event_colors(colors.event.unwrap_or_default()),
+            Err(e) => tracing::warn!(error = %e, "Failed to fetch the event colours"),
+        }
+
+        let cl = client.list_calendars().await.unwrap();
+        let calendars = load_calendars(&client, cl.items.unwrap_or_default(), concurrency).await;
+        let calendars = Arc::new(RwLock::new(calendars));
 
-    let (tx_wcmd, mut rx_wcmd) = tokio::sync::mpsc::unbounded_channel::<WriteCommand>();
+        let tls = client.list_tasklists().await.unwrap();
+        let tasklists = load_tasklists(&client, tls.items.unwrap_or_default(), concurrency).await;
+        let tasklists = Arc::new(RwLock::new(tasklists));
+        (client, calendars, tasklists)
+    };
+    let (client, calendars, tasklists) = match args.startup_timeout {
+        Some(secs) => tokio::time::timeout(std::time::Duration::from_secs(secs), startup)
+            .await
+            .map_err(|_| {
+                tracing::error!("Startup didn't finish within {} seconds", secs);
+                std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "timed out signing in to Google and loading calendars and tasklists",
+                )
+            })?,
+        None => startup.await,
+    };
+
+    if args.dump {
+        dump(
+            &calendars.read().unwrap(),
+            &tasklists.read().unwrap(),
+            &args.fs,
+        );
+        return Ok(());
+    }
+    if let Some(dir) = args.output_dir {
+        let (calendars, tasklists) = (calendars.read().unwrap(), tasklists.read().unwrap());
+        return write_org_files(&dir, &calendars, &tasklists, &args.fs).inspect_err(
+            |e| tracing::error!(dir = %dir.display(), error = %e, "Failed to write org files"),
+        );
+    }
+    let mount = args.mount.expect("mount point is required unless dumping");
+
+    // retry writes which didn't reach the server before the last run ended
+    let journal = Journal::open(client::state_dir().join(JOURNAL_FILE_NAME))
+        .expect("Failed to open pending write journal");
+    journal
+        .replay(&*client, &snapshot(&calendars), &snapshot(&tasklists))
+        .await;
+
+    // bounded, so that a burst of edits waits on Google rather than piling up in memory
+    let (tx_wcmd, mut rx_wcmd) = tokio::sync::mpsc::channel::<WriteCommand>(args.write_queue.get());
     let (tx_fh, mut rx_fh) = tokio::sync::mpsc::unbounded_channel::<Pid>();
     let pending_fh = Arc::new(Mutex::new(HashMap::new()));
-    let _handle = fuser::spawn_mount2(
+    let mut mount_options = vec![MountOption::FSName("orgmode-google-fuse".to_string())];
+    if !args.no_auto_unmount {
+        // don't leave a stale mount behind if we die without unmounting
+        mount_options.push(MountOption::AutoUnmount);
+    }
+    for option in args.mount_options {
+        if !mount_options.contains(&option) {
+            mount_options.push(option);
+        }
+    }
+    // unmounts when dropped, so keep it until we're done
+    let session = mount_fs(&mount, &mount_options, || {
         OrgFS::new(
             calendars.clone(),
             tasklists.clone(),
             tx_wcmd.clone(),
-            tx_fh,
+            tx_fh.clone(),
             pending_fh.clone(),
-        ),
-        &args.mount,
-        &[MountOption::FSName("orgmode-google-fuse".to_string())],
-    )?;
+            args.fs.clone(),
+        )
+    })?;
+    if let Some(detach) = detach {
+        tracing::info!("Mounted {}, detaching", mount);
+        client.detach();
+        detach.ready()?;
+    }
 
+    if let Some(addr) = args.metrics_addr {
+        tokio::spawn(metrics::serve(
+            addr,
+            client.clone(),
+            calendars.clone(),
+            tasklists.clone(),
+        ));
+    }
+
+    if args.no_poll {
+        tracing::info!("Not polling; refresh with .refresh or SIGHUP");
+    }
     // spawn background task to poll for calendars updates
     let trigger_calendar_update = Arc::new(Notify::new());
     tokio::spawn({
+        let client = client.clone();
         let calendars = calendars.clone();
         let tx_wcmd = tx_wcmd.clone();
         let trigger_calendar_update = trigger_calendar_update.clone();
+        let (poll, concurrency) = (!args.no_poll, args.load_concurrency.get());
         async move {
             let mut interval = tokio::time::interval(POLL_INTERVAL);
             interval.reset();
             loop {
                 tokio::select! {
-                    _ = interval.tick() => {}
+                    _ = interval.tick(), if poll => {}
                     _ = trigger_calendar_update.notified() => { interval.reset() }
                 }
                 tracing::info!("Polling for calendar updates…");
-                for calendar in calendars.iter() {
+                discover_calendars(&client, &calendars, concurrency).await;
+                for calendar in snapshot(&calendars).iter().filter(|c| !c.is_removed()) {
                     let calendar_id = calendar
                         .with_meta(|m| m.calendar().id.clone())
                         .expect("calendar with no id");
                     tx_wcmd
                         .send(WriteCommand::SyncCalendar { calendar_id })
+                        .await
                         .unwrap();
                 }
             }
@@ -115,24 +385,28 @@ async fn main() -> std::io::Result<()> {
     // spawn background task to poll for tasks updates
     let trigger_tasklist_update = Arc::new(Notify::new());
     tokio::spawn({
+        let client = client.clone();
         let tasklists = tasklists.clone();
         let tx_wcmd = tx_wcmd.clone();
         let trigger_tasklist_update = trigger_tasklist_update.clone();
+        let (poll, concurrency) = (!args.no_poll, args.load_concurrency.get());
         async move {
             let mut interval = tokio::time::interval(POLL_INTERVAL);
             interval.reset();
             loop {
                 tokio::select! {
-                    _ = interval.tick() => {}
+                    _ = interval.tick(), if poll => {}
                     _ = trigger_tasklist_update.notified() => { interval.reset() }
                 }
                 tracing::info!("Polling for task updates…");
-                for tasklist in tasklists.iter() {
+                discover_tasklists(&client, &tasklists, concurrency).await;
+                for tasklist in snapshot(&tasklists).iter().filter(|t| !t.is_removed()) {
                     let tasklist_id = tasklist
                         .with_meta(|m| m.tasklist().id.clone())
                         .expect("tasklist with no id");
                     tx_wcmd
                         .send(WriteCommand::SyncTasklist { tasklist_id })
+                        .await
                         .unwrap();
                 }
             }
@@ -181,14 +455,11 @@ async fn main() -> std::io::Result<()> {
                     if !waitpids.lock().unwrap().contains(&pid) {
                         // we don't know if the file handle was `release`d, so track active waitpids and don't spawn multiple
                         tracing::debug!("Spawning waitpid for PID: {}", pid);
-                        tokio::spawn(async move {
-                            waitpids.lock().unwrap().push(pid);
-                            tracing::trace!("waiting: {:?}", waitpids.lock().unwrap());
-                            if let Ok(mut wh) = waitpid_any::WaitHandle::open(pid as i32) {
-                                wh.wait().unwrap();
-                            }
-                            tracing::debug!("Dropping PID: {}", pid);
-                            pending_fh.lock().unwrap().retain(|(_ino, p), _| pid != *p);
+                        waitpids.lock().unwrap().push(pid);
+                        tracing::trace!("waiting: {:?}", waitpids.lock().unwrap());
+                        // waiting blocks, so keep it off the runtime's worker threads
+                        tokio::task::spawn_blocking(move || {
+                            watch_pid(pid, &pending_fh);
                             waitpids.lock().unwrap().retain(|p| pid != *p);
                             tracing::trace!("waiting: {:?}", waitpids.lock().unwrap());
                         });
@@ -197,7 +468,20 @@ async fn main() -> std::io::Result<()> {
             } => {}
             _ = async {
                 while let Some(wcmd) = rx_wcmd.recv().await {
-                    process_write(&client, &calendars, &mut sync_tokens.lock().await, &tasklists, wcmd).await;
+                    let is_write = wcmd.is_write();
+                    if is_write {
+                        journal.append(&wcmd);
+                    }
+                    let settles = is_write
+                        || matches!(
+                            wcmd,
+                            WriteCommand::SyncCalendar { .. } | WriteCommand::SyncTasklist { .. }
+                        );
+                    let (calendars, tasklists) = (snapshot(&calendars), snapshot(&tasklists));
+                    process_write(&*client, &calendars, &tasklists, wcmd).await;
+                    if settles {
+                        journal.compact(&calendars, &tasklists);
+                    }
                 }
             } => {
                 tracing::info!("Processed write commands");
@@ -205,18 +489,309 @@ async fn main() -> std::io::Result<()> {
         }
     }
 
+    flush_pending(
+        &*client,
+        &snapshot(&calendars),
+        &snapshot(&tasklists),
+        &mut rx_wcmd,
+        &journal,
+        SHUTDOWN_FLUSH_TIMEOUT,
+    )
+    .await;
+
+    session.join();
     Ok(())
 }
 
-async fn update_tasklist(
+// a copy of `lists` to work on without holding the lock across an await
+fn snapshot<T: Clone>(lists: &Lists<T>) -> Vec<T> {
+    lists.read().unwrap().clone()
+}
+
+/// Fetch the events of each of `entries`, leaving out calendars whose events fail to load.
+async fn load_calendars(
+    client: &client::GoogleClient,
+    entries: Vec<CalendarListEntry>,
+    concurrency: usize,
+) -> Vec<OrgCalendar> {
+    stream::iter(entries)
+        .map(|cal| async {
+            let id = cal.id.as_ref().unwrap();
+            let events = if calendar::is_free_busy(&cal) {
+                calendar::busy_events(client.freebusy_query(id).await.ok()?)
+            } else {
+                client.list_events(id).await.ok()?
+            };
+            Some((cal, events).into())
+        })
+        // in order, so that files keep their inodes across runs
+        .buffered(concurrency)
+        .filter_map(future::ready)
+        .collect()
+        .await
+}
+
+/// Fetch the tasks of each of `entries`, leaving out tasklists whose tasks fail to load.
+async fn load_tasklists(
     client: &client::GoogleClient,
+    entries: Vec<TaskList>,
+    concurrency: usize,
+) -> Vec<OrgTaskList> {
+    stream::iter(entries)
+        .map(|tl| async move {
+            let tasks = client.list_tasks(tl.id.as_ref().unwrap()).await.ok()?;
+            Some((tl, tasks).into())
+        })
+        .buffered(concurrency)
+        .filter_map(future::ready)
+        .collect()
+        .await
+}
+
+/// Bring `calendars` in line with the user's calendar list: those no longer on it are marked
+/// removed, and new ones are loaded and appended.
+async fn discover_calendars(
+    client: &client::GoogleClient,
+    calendars: &Lists<OrgCalendar>,
+    concurrency: usize,
+) {
+    let entries = match client.list_calendars().await {
+        Ok(cl) => cl.items.unwrap_or_default(),
+        Err(e) => {
+            tracing::warn!(error = %e, "Failed to list calendars");
+            return;
+        }
+    };
+    let listed: HashSet<_> = entries.iter().filter_map(|cal| cal.id.clone()).collect();
+    let mut known = HashSet::new();
+    for calendar in snapshot(calendars) {
+        let id = calendar.with_meta(|m| m.calendar().id.clone());
+        if let Some(id) = id {
+            if !listed.contains(&id) && !calendar.is_removed() {
+                tracing::info!(calendar_id = %id, "Calendar is gone from Google, removing it");
+                calendar.mark_removed();
+            }
+            known.insert(id);
+        }
+    }
+    let new: Vec<_> = entries
+        .into_iter()
+        .filter(|cal| cal.id.as_ref().is_some_and(|id| !known.contains(id)))
+        .collect();
+    if new.is_empty() {
+        return;
+    }
+    tracing::info!("Found {} new calendars", new.len());
+    let new = load_calendars(client, new, concurrency).await;
+    calendars.write().unwrap().extend(new);
+}
+
+/// Bring `tasklists` in line with the user's tasklists: those no longer listed are marked
+/// removed, and new ones are loaded and appended.
+async fn discover_tasklists(
+    client: &client::GoogleClient,
+    tasklists: &Lists<OrgTaskList>,
+    concurrency: usize,
+) {
+    let entries = match client.list_tasklists().await {
+        Ok(tls) => tls.items.unwrap_or_default(),
+        Err(e) => {
+            tracing::warn!(error = %e, "Failed to list tasklists");
+            return;
+        }
+    };
+    let listed: HashSet<_> = entries.iter().filter_map(|tl| tl.id.clone()).collect();
+    let mut known = HashSet::new();
+    for tasklist in snapshot(tasklists) {
+        let id = tasklist.with_meta(|m| m.tasklist().id.clone());
+        if let Some(id) = id {
+            if !listed.contains(&id) && !tasklist.is_removed() {
+                tracing::info!(tasklist_id = %id, "Tasklist is gone from Google, removing it");
+                tasklist.mark_removed();
+            }
+            known.insert(id);
+        }
+    }
+    let new: Vec<_> = entries
+        .into_iter()
+        .filter(|tl| tl.id.as_ref().is_some_and(|id| !known.contains(id)))
+        .collect();
+    if new.is_empty() {
+        return;
+    }
+    tracing::info!("Found {} new tasklists", new.len());
+    let new = load_tasklists(client, new, concurrency).await;
+    tasklists.write().unwrap().extend(new);
+}
+
+// tried in turn to unmount a stale mount point
+const UNMOUNT_COMMANDS: &[&[&str]] = &[&["fusermount3", "-u"], &["fusermount", "-u"], &["umount"]];
+
+/// Check that `mount` is a directory to mount on, creating it if it's missing and `create`.
+///
+/// Mounting hides whatever is in the directory, so that is refused unless `create`, and warned
+/// about otherwise.
+fn prepare_mount_point(mount: &Path, create: bool) -> std::io::Result<()> {
+    let mut entries = match std::fs::read_dir(mount) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && create => {
+            tracing::info!("Creating mount point {}", mount.display());
+            return std::fs::create_dir_all(mount);
+        }
+        // left for `mount_fs` to clean up
+        Err(e) if e.raw_os_error() == Some(libc::ENOTCONN) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if entries.next().is_some() {
+        if !create {
+            return Err(std::io::Error::other("mount point is not empty"));
+        }
+        tracing::warn!(
+            "Mounting over {}, which is not empty; its contents are hidden until unmounted",
+            mount.display()
+        );
+    }
+    Ok(())
+}
+
+/// Mount the filesystem built by `fs` at `mount`.
+///
+/// A run which was killed without unmounting leaves the mount point disconnected (or still
+/// mounted), so in that case unmount it and try once more.
+fn mount_fs(
+    mount: &str,
+    options: &[MountOption],
+    fs: impl Fn() -> OrgFS,
+) -> std::io::Result<fuser::BackgroundSession> {
+    let try_mount = || fuser::spawn_mount2(fs(), mount, options);
+    match try_mount() {
+        Err(e) if matches!(e.raw_os_error(), Some(libc::ENOTCONN | libc::EBUSY)) => {
+            tracing::warn!(%mount, error = %e, "Stale mount, unmounting it…");
+            let unmounted = UNMOUNT_COMMANDS.iter().any(|cmd| {
+                std::process::Command::new(cmd[0])
+                    .args(&cmd[1..])
+                    .arg(mount)
+                    .status()
+                    .is_ok_and(|status| status.success())
+            });
+            if !unmounted {
+                tracing::error!(
+                    "Failed to unmount {}; unmount it with `fusermount -u {}` and try again",
+                    mount,
+                    mount
+                );
+                return Err(e);
+            }
+            try_mount().inspect_err(
+                |e| tracing::error!(%mount, error = %e, "Failed to mount after unmounting"),
+            )
+        }
+        result => result,
+    }
+}
+
+// each file is headed by an org comment naming where it would be mounted
+fn dump(calendars: &[OrgCalendar], tasklists: &[OrgTaskList], options: &FsOptions) {
+    for calendar in calendars {
+        let summary = calendar.with_meta(|m| m.calendar().summary.clone().unwrap_or_default());
+        let name = options.org_file_name(&summary);
+        println!("# {}/{}", options.calendars_dir(), name);
+        println!("{}", calendar.to_org_string());
+    }
+    for tasklist in tasklists {
+        let title = tasklist.with_meta(|m| m.tasklist().title.clone().unwrap_or_default());
+        let name = options.org_file_name(&title);
+        println!("# {}/{}", options.tasks_dir(), name);
+        println!("{}", tasklist.to_org_string());
+    }
+}
+
+/// Write every calendar and tasklist to `<name>.<extension>` in their directories under `dir`, as
+/// they'd appear when mounted.
+///
+/// Each file is written beside its destination and renamed over it, so that anything reading
+/// the directory (e.g. org-agenda) never sees one half-written.
+fn write_org_files(
+    dir: &Path,
+    calendars: &[OrgCalendar],
+    tasklists: &[OrgTaskList],
+    options: &FsOptions,
+) -> std::io::Result<()> {
+    let (calendars_dir, tasks_dir) = (
+        dir.join(options.calendars_dir()),
+        dir.join(options.tasks_dir()),
+    );
+    std::fs::create_dir_all(&calendars_dir)?;
+    std::fs::create_dir_all(&tasks_dir)?;
+    let files = calendars
+        .iter()
+        .map(|calendar| {
+            (
+                &calendars_dir,
+                calendar.with_meta(|m| m.calendar().summary.clone()),
+                calendar.to_org_string(),
+            )
+        })
+        .chain(tasklists.iter().map(|tasklist| {
+            (
+                &tasks_dir,
+                tasklist.with_meta(|m| m.tasklist().title.clone()),
+                tasklist.to_org_string(),
+            )
+        }));
+    let mut written = HashSet::new();
+    for (dir, name, org) in files {
+        let Some(name) = name else { continue };
+        // a name can't reach outside the directory
+        let name = options.org_file_name(&name.replace('/', "-"));
+        let path = dir.join(&name);
+        if !written.insert(path.clone()) {
+            tracing::warn!("Not writing a second {}", path.display());
+            continue;
+        }
+        // named apart from the file, which may already take up all of NAME_MAX
+        let tmp = dir.join(format!(".{}.tmp", std::process::id()));
+        std::fs::write(&tmp, org)?;
+        std::fs::rename(&tmp, &path)?;
+        tracing::info!("Wrote {}", path.display());
+    }
+    Ok(())
+}
+
+/// Wait for `pid` to exit, then drop the file snapshots it was holding.
+///
+/// The process may already be gone by the time it is watched (e.g. a short-lived `cat`), in which
+/// case its snapshots are dropped straight away.
+fn watch_pid<K, V>(pid: Pid, pending_fh: &Mutex<HashMap<(K, Pid), V>>) {
+    match waitpid_any::WaitHandle::open(pid as i32) {
+        Ok(mut wh) => {
+            if let Err(e) = wh.wait() {
+                tracing::warn!(pid, error = %e, "Failed to wait for PID");
+            }
+        }
+        Err(e) => tracing::debug!("PID {} exited before it could be watched: {}", pid, e),
+    }
+    tracing::debug!("Dropping PID: {}", pid);
+    pending_fh.lock().unwrap().retain(|(_ino, p), _| pid != *p);
+}
+
+async fn update_tasklist(
+    client: &impl client::GoogleApi,
     org_tasklist: &OrgTaskList,
 ) -> google_tasks1::Result<()> {
     let tl_id = org_tasklist
         .with_meta(|m| m.tasklist().id.clone())
         .expect("tasklist with no id");
-    tracing::info!("Updating tasklist {}…", tl_id);
-    let tasks = client.list_tasks(&tl_id).await?;
+    tracing::info!(tasklist_id = %tl_id, "Updating tasklist…");
+    // only fetch what changed since the last sync, with some slack for clock skew
+    let since = org_tasklist
+        .with_meta(|m| m.synced().load(Ordering::Acquire))
+        .checked_sub(SYNC_CLOCK_SKEW)
+        .unwrap_or(std::time::UNIX_EPOCH)
+        .max(std::time::UNIX_EPOCH);
+    let tasks = client
+        .list_tasks_updated_since(&tl_id, since.into())
+        .await?;
     let updated = client
         .get_tasklist(&tl_id)
         .await?
@@ -229,26 +804,43 @@ async fn update_tasklist(
         })
         .unwrap_or(std::time::UNIX_EPOCH);
     org_tasklist.sync(tasks, updated);
+    org_tasklist.with_meta(|m| m.synced().store(SystemTime::now(), Ordering::Release));
     Ok(())
 }
 
 async fn update_calendar(
-    client: &client::GoogleClient,
+    client: &impl client::GoogleApi,
     org_calendar: &OrgCalendar,
     sync_token: Option<&client::SyncToken>,
 ) -> google_calendar3::Result<Option<client::SyncToken>> {
     let cal_id = org_calendar
         .with_meta(|m| m.calendar().id.clone())
         .expect("calendar with no id");
+    if org_calendar.is_free_busy() {
+        // there's no sync token for free/busy, so every block is fetched each time
+        tracing::info!(calendar_id = %cal_id, "Syncing busy times");
+        let busy = client.freebusy_query(cal_id.as_ref()).await?;
+        org_calendar.sync_busy(busy, SystemTime::now());
+        org_calendar.with_meta(|m| m.synced().store(SystemTime::now(), Ordering::Release));
+        return Ok(None);
+    }
     let events = match sync_token {
         Some(sync_token) => {
-            tracing::info!("Syncing calendar {} with token {}", cal_id, sync_token);
-            client
+            tracing::info!(calendar_id = %cal_id, %sync_token, "Syncing calendar with token");
+            match client
                 .list_events_with_sync_token(cal_id.as_ref(), sync_token)
-                .await?
+                .await
+            {
+                Err(e) if client::is_full_sync_required(&e) => {
+                    tracing::info!(calendar_id = %cal_id, "Sync token expired, resyncing in full");
+                    org_calendar.with_meta(|m| *m.sync_token().lock().unwrap() = None);
+                    client.list_events(cal_id.as_ref()).await?
+                }
+                result => result?,
+            }
         }
         _ => {
-            tracing::info!("Syncing calendar {} without token", cal_id);
+            tracing::info!(calendar_id = %cal_id, "Syncing calendar without token");
             client.list_events(cal_id.as_ref()).await?
         }
     };
@@ -258,5 +850,172 @@ async fn update_calendar(
         .map(|dt| dt.into())
         .unwrap_or(std::time::UNIX_EPOCH);
     org_calendar.sync(events, updated);
+    org_calendar.with_meta(|m| m.synced().store(SystemTime::now(), Ordering::Release));
     Ok(next_sync_token)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, process::Command, sync::Mutex};
+
+    use clap::Parser;
+
+    use super::{
+        log_file_appender, log_level, prepare_mount_point, watch_pid, write_org_files, Args,
+        LogFormat, Pid,
+    };
+
+    #[test]
+    fn exited_pid_snapshots_dropped() {
+        // a reader which opens, reads and closes before its watcher gets to it
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id() as Pid;
+        child.wait().unwrap();
+        let pending_fh = Mutex::new(HashMap::from([((1, pid), ()), ((1, pid + 1), ())]));
+        watch_pid(pid, &pending_fh);
+        assert_eq!(
+            pending_fh
+                .into_inner()
+                .unwrap()
+                .into_keys()
+                .collect::<Vec<_>>(),
+            vec![(1, pid + 1)]
+        );
+    }
+
+    #[test]
+    fn verbosity_flags() {
+        let level = |args: &[&str]| log_level(Args::parse_from(args).verbose);
+        assert_eq!(level(&["test", "--dump"]), tracing::Level::WARN);
+        assert_eq!(level(&["test", "--dump", "-v"]), tracing::Level::INFO);
+        assert_eq!(level(&["test", "--dump", "-vv"]), tracing::Level::DEBUG);
+        assert_eq!(level(&["test", "-vvvv", "--dump"]), tracing::Level::TRACE);
+    }
+
+    #[test]
+    fn log_file_dated() {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut appender = log_file_appender(Some(&dir.path().join("sync.log"))).unwrap();
+        appender.write_all(b"hello\n").unwrap();
+        appender.flush().unwrap();
+        let names = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        let [name] = names.as_slice() else {
+            panic!("expected one log file, got {names:?}");
+        };
+        let date = name
+            .strip_prefix("sync.")
+            .and_then(|name| name.strip_suffix(".log"))
+            .unwrap();
+        assert!(chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok());
+    }
+
+    #[test]
+    fn log_format_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sync.log");
+        let file = std::sync::Mutex::new(std::fs::File::create(&path).unwrap());
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(file)
+            .json()
+            .flatten_event(true)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let calendar_id = "personal";
+            tracing::error!(%calendar_id, error = %"Bad Request", "Failed to sync calendar");
+        });
+        let line: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(line["level"], "ERROR");
+        assert_eq!(line["message"], "Failed to sync calendar");
+        assert_eq!(line["calendar_id"], "personal");
+        assert_eq!(line["error"], "Bad Request");
+        assert_eq!(
+            Args::parse_from(["test", "--dump", "--log-format", "json"]).log_format,
+            LogFormat::Json
+        );
+    }
+
+    #[test]
+    fn mount_point_checks() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("org");
+        assert!(prepare_mount_point(&missing, false).is_err());
+        prepare_mount_point(&missing, true).unwrap();
+        assert!(missing.is_dir());
+        prepare_mount_point(&missing, false).unwrap();
+        // a non-empty directory is only warned about, unless it must already be ready
+        std::fs::write(missing.join("file"), "").unwrap();
+        prepare_mount_point(&missing, true).unwrap();
+        assert!(prepare_mount_point(&missing, false).is_err());
+    }
+
+    #[test]
+    fn org_files_written() {
+        use google_calendar3::api::{CalendarListEntry, Events};
+
+        use crate::org::calendar::OrgCalendar;
+
+        let calendar = |summary: &str| {
+            OrgCalendar::from((
+                CalendarListEntry {
+                    summary: Some(summary.to_owned()),
+                    ..CalendarListEntry::default()
+                },
+                Events::default(),
+            ))
+        };
+        let options = |args: &[&str]| {
+            let once = ["test", "--once", "--output-dir", "."];
+            Args::parse_from(once.iter().chain(args)).fs
+        };
+        let files = |dir: &std::path::Path| {
+            let mut files = std::fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let calendars = [calendar("Work"), calendar("a/b")];
+        write_org_files(dir.path(), &calendars, &[], &options(&[])).unwrap();
+        assert_eq!(files(dir.path()), ["calendars", "tasks"]);
+        assert_eq!(
+            files(&dir.path().join("calendars")),
+            ["Work.org", "a-b.org"]
+        );
+        assert!(
+            std::fs::read_to_string(dir.path().join("calendars/Work.org"))
+                .unwrap()
+                .starts_with("#+TITLE: Work\n")
+        );
+
+        // with the mount's `--extension` and directory names
+        let dir = tempfile::tempdir().unwrap();
+        let archive = options(&["--extension", "org_archive", "--calendars-dir", "cal"]);
+        write_org_files(dir.path(), &calendars, &[], &archive).unwrap();
+        assert_eq!(files(dir.path()), ["cal", "tasks"]);
+        assert_eq!(
+            files(&dir.path().join("cal")),
+            ["Work.org_archive", "a-b.org_archive"]
+        );
+
+        // a name too long for a file is cut short as the mount does
+        let dir = tempfile::tempdir().unwrap();
+        write_org_files(
+            dir.path(),
+            &[calendar(&"é".repeat(200))],
+            &[],
+            &options(&[]),
+        )
+        .unwrap();
+        let [name] = files(&dir.path().join("calendars")).try_into().unwrap();
+        assert!(name.len() <= 255);
+        assert!(name.starts_with("éé") && name.ends_with(".org"));
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::{
+    client::GoogleClient,
+    org::{calendar::OrgCalendar, tasklist::OrgTaskList, Lists, MetaPendingContainer},
+};
+
+const PREFIX: &str = "orgmode_google_fuse";
+
+/// Counts of the calls made to Google, and of those which failed.
+#[derive(Default)]
+pub(crate) struct ApiMetrics {
+    calls: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl ApiMetrics {
+    pub(crate) fn record(&self, ok: bool) {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Serve a Prometheus-style snapshot of the sync state on `addr`, in answer to any request.
+pub(crate) async fn serve(
+    addr: SocketAddr,
+    client: Arc<GoogleClient>,
+    calendars: Lists<OrgCalendar>,
+    tasklists: Lists<OrgTaskList>,
+) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!(%addr, error = %e, "Failed to serve metrics");
+            return;
+        }
+    };
+    tracing::info!("Serving metrics on http://{}", addr);
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to accept metrics connection");
+                continue;
+            }
+        };
+        let body = render(
+            client.metrics(),
+            &calendars.read().unwrap(),
+            &tasklists.read().unwrap(),
+        );
+        tokio::spawn(async move {
+            // the request itself doesn't matter, but read it so the client sees a clean close
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                tracing::debug!("Failed to send metrics: {}", e);
+            }
+        });
+    }
+}
+
+fn render(api: &ApiMetrics, calendars: &[OrgCalendar], tasklists: &[OrgTaskList]) -> String {
+    // (kind, id, name, last sync, pending writes) of every list
+    let mut lists = Vec::new();
+    for cal in calendars.iter().filter(|cal| !cal.is_removed()) {
+        lists.push(cal.with_meta(|m| {
+            (
+                "calendar",
+                m.calendar().id.clone().unwrap_or_default(),
+                m.calendar().summary.clone().unwrap_or_default(),
+                m.synced().load(Ordering::Acquire),
+                m.pending().0.len() + m.pending().1.len(),
+            )
+        }));
+    }
+    for tl in tasklists.iter().filter(|tl| !tl.is_removed()) {
+        lists.push(tl.with_meta(|m| {
+            (
+                "tasklist",
+                m.tasklist().id.clone().unwrap_or_default(),
+                m.tasklist().title.clone().unwrap_or_default(),
+                m.synced().load(Ordering::Acquire),
+                m.pending().0.len() + m.pending().1.len(),
+            )
+        }));
+    }
+
+    fn header(str: &mut String, name: &str, ty: &str, help: &str) {
+        writeln!(str, "# HELP {PREFIX}_{name} {help}").unwrap();
+        writeln!(str, "# TYPE {PREFIX}_{name} {ty}").unwrap();
+    }
+    let mut str = String::new();
+    header(
+        &mut str,
+        "last_sync_timestamp_seconds",
+        "gauge",
+        "When each list last synced with Google (0 if never).",
+    );
+    for (kind, id, name, synced, _) in &lists {
+        let synced = synced
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        writeln!(
+            str,
+            "{PREFIX}_last_sync_timestamp_seconds{{kind=\"{kind}\",id=\"{}\",name=\"{}\"}} {synced}",
+            escape_label(id),
+            escape_label(name)
+        )
+        .unwrap();
+    }
+    header(
+        &mut str,
+        "pending_writes",
+        "gauge",
+        "Writes to each list not yet confirmed by Google.",
+    );
+    for (kind, id, name, _, pending) in &lists {
+        writeln!(
+            str,
+            "{PREFIX}_pending_writes{{kind=\"{kind}\",id=\"{}\",name=\"{}\"}} {pending}",
+            escape_label(id),
+            escape_label(name)
+        )
+        .unwrap();
+    }
+    header(
+        &mut str,
+        "api_calls_total",
+        "counter",
+        "Calls made to Google's APIs.",
+    );
+    writeln!(
+        str,
+        "{PREFIX}_api_calls_total {}",
+        api.calls.load(Ordering::Relaxed)
+    )
+    .unwrap();
+    header(
+        &mut str,
+        "api_errors_total",
+        "counter",
+        "Calls to Google's APIs which failed or timed out.",
+    );
+    writeln!(
+        str,
+        "{PREFIX}_api_errors_total {}",
+        api.errors.load(Ordering::Relaxed)
+    )
+    .unwrap();
+    header(
+        &mut str,
+        "scrape_timestamp_seconds",
+        "gauge",
+        "When this snapshot was taken.",
+    );
+    writeln!(
+        str,
+        "{PREFIX}_scrape_timestamp_seconds {}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    )
+    .unwrap();
+    str
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use google_calendar3::api::{CalendarListEntry, Events};
+
+    use super::ApiMetrics;
+    use crate::org::calendar::OrgCalendar;
+
+    #[test]
+    fn render_metrics() {
+        let calendar = OrgCalendar::from((
+            CalendarListEntry {
+                id: Some("personal".to_owned()),
+                summary: Some("My \"Personal\" calendar".to_owned()),
+                ..CalendarListEntry::default()
+            },
+            Events::default(),
+        ));
+        let api = ApiMetrics::default();
+        api.record(true);
+        api.record(false);
+        let metrics = super::render(&api, &[calendar], &[]);
+        assert!(metrics.contains(
+            "orgmode_google_fuse_pending_writes{kind=\"calendar\",id=\"personal\",name=\"My \\\"Personal\\\" calendar\"} 0\n"
+        ));
+        assert!(metrics.contains("orgmode_google_fuse_api_calls_total 2\n"));
+        assert!(metrics.contains("orgmode_google_fuse_api_errors_total 1\n"));
+    }
+}
@@ -2,25 +2,47 @@ use std::{
     collections::{HashMap, HashSet},
     fmt::Debug,
     hash::Hash,
-    sync::MutexGuard,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock, RwLock,
+    },
+    time::SystemTime,
 };
 
+use atomic_time::AtomicSystemTime;
 use evmap::{ReadHandle, WriteHandle};
+use indexmap::IndexSet;
 use itertools::Itertools;
 use orgize::{
     ast::{Headline, Token},
     export::{from_fn, Container, Event},
-    Org,
+    Org, ParseConfig,
 };
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::{de::DeserializeOwned, Serialize};
 
 pub(crate) mod calendar;
 pub(crate) mod conflict;
+pub(crate) mod html;
 pub(crate) mod tasklist;
 pub(crate) mod timestamp;
 
+/// Parse `text` as org, with the task keywords set by `--todo-keyword` and `--done-keyword`.
+pub(crate) fn parse_org(text: impl AsRef<str>) -> Org {
+    let options = render_options();
+    ParseConfig {
+        todo_keywords: (
+            vec![options.todo_keyword().to_owned()],
+            vec![options.done_keyword().to_owned()],
+        ),
+        ..ParseConfig::default()
+    }
+    .parse(text)
+}
+
 pub(crate) trait ToOrg {
     fn to_org(&self) -> Org {
-        Org::parse(self.to_org_string())
+        parse_org(self.to_org_string())
     }
     fn to_org_string(&self) -> String {
         let org = self.to_org();
@@ -30,7 +52,7 @@ pub(crate) trait ToOrg {
 
 impl ToOrg for String {
     fn to_org(&self) -> Org {
-        Org::parse(self)
+        parse_org(self)
     }
 
     fn to_org_string(&self) -> String {
@@ -40,7 +62,7 @@ impl ToOrg for String {
 
 impl ToOrg for &str {
     fn to_org(&self) -> Org {
-        Org::parse(*self)
+        parse_org(*self)
     }
 
     fn to_org_string(&self) -> String {
@@ -54,6 +76,138 @@ impl ToOrg for Org {
     }
 }
 
+/// How items are laid out in (and so parsed back from) the rendered files; set once at startup.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub(crate) struct RenderOptions {
+    /// layout of calendar event headlines
+    #[arg(long, value_enum, default_value_t)]
+    pub(crate) calendar_format: calendar::CalendarFormat,
+    /// also link each event's location to Google Maps, below its timestamp
+    #[arg(long)]
+    pub(crate) map_links: bool,
+    /// leave out events you've declined, from the `.json` and `.ics` files as well as the org
+    #[arg(long)]
+    pub(crate) hide_declined: bool,
+    /// timezone to show times in, and to read edited times as (e.g. Europe/London); defaults to
+    /// the system timezone
+    #[arg(long, value_name = "ZONE", value_parser = parse_tz)]
+    pub(crate) tz: Option<chrono_tz::Tz>,
+    /// planning keyword to show task due dates with; either is read back as the due date
+    #[arg(long, value_enum, default_value_t)]
+    pub(crate) task_due_as: tasklist::TaskDueAs,
+    /// also show each task's `position`, the string Google sorts siblings by
+    #[arg(long)]
+    pub(crate) debug_properties: bool,
+    /// how to show HTML in event descriptions; an edited event is written back with the
+    /// description as shown
+    #[arg(long, value_enum, default_value_t)]
+    pub(crate) html_descriptions: html::HtmlDescriptions,
+    /// headline keyword for tasks still to do, e.g. NEXT [default: TODO]
+    #[arg(long, value_name = "KEYWORD", value_parser = parse_keyword)]
+    todo_keyword: Option<String>,
+    /// headline keyword for completed tasks [default: DONE]; headlines with it are completed in
+    /// Google, and those with neither keyword are left to do
+    #[arg(long, value_name = "KEYWORD", value_parser = parse_keyword)]
+    done_keyword: Option<String>,
+    /// show an `Effort: 1:30` line in a task's notes as its `:Effort:` property instead, writing
+    /// the property back as the first line of the notes
+    #[arg(long)]
+    pub(crate) parse_effort: bool,
+    /// show each calendar's own description, as comments at the top of its file
+    #[arg(long)]
+    pub(crate) include_calendar_description: bool,
+    /// also give each event a `:CATEGORY:` property naming its calendar, so agendas gathered
+    /// from several files (or agenda.org) group events by calendar
+    #[arg(long)]
+    pub(crate) event_category: bool,
+    /// make each event's headline a link to the event in Google Calendar's web UI
+    #[arg(long)]
+    pub(crate) headline_link: bool,
+    /// include completed tasks in tasklists
+    #[arg(long)]
+    pub(crate) show_completed: bool,
+    /// include hidden tasks, such as ones completed or cleared in Google's own apps, tagged
+    /// `:ARCHIVE:`
+    #[arg(long)]
+    pub(crate) show_hidden: bool,
+    /// layout of tasks in tasklist files
+    #[arg(long, value_enum, default_value_t)]
+    pub(crate) task_style: tasklist::TaskStyle,
+    /// line opening a conflict, before Google's version [default: "<<<<<<< remote (read only)"]
+    #[arg(long, value_name = "LINE", value_parser = parse_marker)]
+    conflict_start: Option<String>,
+    /// line between Google's version of a conflict and the local edit [default: "======="]
+    #[arg(long, value_name = "LINE", value_parser = parse_marker)]
+    conflict_middle: Option<String>,
+    /// line closing a conflict, after the local edit [default: ">>>>>>> local"]
+    #[arg(long, value_name = "LINE", value_parser = parse_marker)]
+    conflict_end: Option<String>,
+}
+
+impl RenderOptions {
+    pub(crate) fn todo_keyword(&self) -> &str {
+        self.todo_keyword.as_deref().unwrap_or("TODO")
+    }
+
+    pub(crate) fn done_keyword(&self) -> &str {
+        self.done_keyword.as_deref().unwrap_or("DONE")
+    }
+
+    pub(crate) fn conflict_markers(&self) -> conflict::Markers<'_> {
+        conflict::Markers {
+            start: self
+                .conflict_start
+                .as_deref()
+                .unwrap_or(conflict::CONFLICT_START),
+            middle: self
+                .conflict_middle
+                .as_deref()
+                .unwrap_or(conflict::CONFLICT_MIDDLE),
+            end: self
+                .conflict_end
+                .as_deref()
+                .unwrap_or(conflict::CONFLICT_END),
+        }
+    }
+}
+
+fn parse_keyword(keyword: &str) -> Result<String, String> {
+    if keyword.is_empty() || keyword.contains(char::is_whitespace) {
+        return Err(format!("`{keyword}` is not a single word"));
+    }
+    Ok(keyword.to_owned())
+}
+
+fn parse_marker(marker: &str) -> Result<String, String> {
+    if marker.trim().is_empty() || marker.contains('\n') {
+        return Err("a conflict marker must be a single non-blank line".to_owned());
+    }
+    Ok(marker.to_owned())
+}
+
+fn parse_tz(zone: &str) -> Result<chrono_tz::Tz, String> {
+    zone.parse()
+        .map_err(|_| format!("unknown timezone `{zone}`"))
+}
+
+static RENDER_OPTIONS: OnceLock<RenderOptions> = OnceLock::new();
+
+pub(crate) fn set_render_options(options: RenderOptions) {
+    RENDER_OPTIONS
+        .set(options)
+        .expect("render options already set");
+}
+
+pub(crate) fn render_options() -> &'static RenderOptions {
+    RENDER_OPTIONS.get_or_init(RenderOptions::default)
+}
+
+/// The calendars or tasklists, shared between the filesystem and the sync tasks.
+///
+/// Lists are only ever appended (one gone from Google is marked removed instead), so that each
+/// keeps its index, and with it the inodes of its files.
+pub(crate) type Lists<T> = Arc<RwLock<Vec<T>>>;
+
 #[derive(Debug, Clone)]
 pub(crate) struct ByETag<T>(pub(super) T)
 where
@@ -281,6 +435,60 @@ impl MaybeIdMap {
     }
 }
 
+/// A file as a process first read it (`base`) and as it reads now (`live`), for rebasing the
+/// process's edits onto whatever changed in between, e.g. by another editor saving first.
+pub(crate) struct Rebase {
+    pub(crate) base: MaybeIdMap,
+    pub(crate) live: MaybeIdMap,
+}
+
+impl Rebase {
+    /// Merge `ours`, the entry `id` as edited from the base, with its live version: fields only
+    /// changed since the base take the live value. `Ok(None)` if it hasn't changed since, and
+    /// `Err(ours)` if both changed a field to different values.
+    pub(crate) fn merge<T: Serialize + DeserializeOwned>(
+        &self,
+        id: &Token,
+        ours: T,
+        parse: impl Fn(&Headline) -> T,
+    ) -> Result<Option<T>, T> {
+        let (Some(base), Some(live)) = (self.base.map().get(id), self.live.map().get(id)) else {
+            return Ok(None);
+        };
+        let to_value = |item: &T| serde_json::to_value(item).expect("Failed to serialize");
+        let (serde_json::Value::Object(base), serde_json::Value::Object(live)) =
+            (to_value(&parse(base)), to_value(&parse(live)))
+        else {
+            return Ok(None);
+        };
+        if base == live {
+            return Ok(None);
+        }
+        let serde_json::Value::Object(mut merged) = to_value(&ours) else {
+            return Ok(None);
+        };
+        let null = serde_json::Value::Null;
+        for key in base.keys().chain(live.keys()).unique() {
+            let (base, live) = (
+                base.get(key).unwrap_or(&null),
+                live.get(key).unwrap_or(&null),
+            );
+            let edited = merged.get(key).unwrap_or(&null);
+            if live == base || live == edited {
+                continue;
+            }
+            if edited != base {
+                return Err(ours);
+            }
+            merged.insert(key.clone(), live.clone());
+        }
+        Ok(Some(
+            serde_json::from_value(serde_json::Value::Object(merged))
+                .expect("Failed to deserialize merged value"),
+        ))
+    }
+}
+
 impl From<&Org> for MaybeIdMap {
     fn from(org: &Org) -> Self {
         let mut map = MaybeIdMap::default();
@@ -306,6 +514,138 @@ macro_rules! text_from_property_drawer {
 
 use text_from_property_drawer;
 
+/// `#+TITLE:` and `#+CATEGORY:` keywords heading the file of a calendar or tasklist, so that
+/// org-agenda groups its entries under the list's name.
+pub(crate) fn render_file_keywords(title: &str) -> String {
+    format!("#+TITLE: {title}\n#+CATEGORY: {}\n", category(title))
+}
+
+/// The tag marking items from the list titled `title` where they're shown alongside other lists'.
+pub(crate) fn list_tag(title: &str) -> String {
+    // org tags may only contain alphanumerics, `_`, `@`, `#` and `%`
+    title
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || "_@#%".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Tag the headline on the first line of `rendered` with `tag`, joining the tags it already has:
+/// org only reads the last group of tags on a line as tags.
+pub(crate) fn add_tag(rendered: &mut String, tag: &str) {
+    let eol = rendered.find('\n').unwrap_or(rendered.len());
+    let has_tags = rendered[..eol]
+        .rsplit(' ')
+        .next()
+        .is_some_and(|word| word.len() > 1 && word.starts_with(':') && word.ends_with(':'));
+    if has_tags {
+        rendered.insert_str(eol, &format!("{tag}:"));
+    } else {
+        rendered.insert_str(eol, &format!(" :{tag}:"));
+    }
+}
+
+/// The agenda category for items from the list titled `title`.
+pub(crate) fn category(title: &str) -> String {
+    // a category is shown in a narrow agenda column, so keep it to one word
+    title.split_whitespace().join("_")
+}
+
+/// Move a file's modification time `updated` forward after a sync changed its contents, to
+/// `server` (when Google last changed it) or now if that's later, so that editors reload it.
+pub(crate) fn bump_updated(updated: &AtomicSystemTime, server: SystemTime) {
+    updated.store(server.max(SystemTime::now()), Ordering::Release);
+}
+
+// what orgize would trim from (or split) a property value, plus the escape character itself
+const PROPERTY_ESCAPES: &AsciiSet = &CONTROLS.add(b' ').add(b'%');
+
+/// Percent-encode the characters of an etag that wouldn't survive a reparse of its property
+/// drawer. Google's etags never contain any, so in practice they're written verbatim.
+pub(crate) fn escape_etag(etag: &str) -> String {
+    utf8_percent_encode(etag, PROPERTY_ESCAPES).to_string()
+}
+
+pub(crate) fn unescape_etag(etag: &str) -> String {
+    percent_decode_str(etag).decode_utf8_lossy().into_owned()
+}
+
+/// The last rendering of a container, valid until its contents next change.
+#[derive(Default)]
+pub(crate) struct RenderCache {
+    version: AtomicU64,
+    rendered: Mutex<Option<(u64, String)>>,
+}
+
+impl RenderCache {
+    /// Call after every change to the container's contents has been published (`refresh`ed).
+    pub(crate) fn invalidate(&self) {
+        self.version.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub(crate) fn get_or_render(&self, render: impl FnOnce() -> String) -> String {
+        // read the version first, so a change racing with `render` leaves the cache stale
+        let version = self.version.load(Ordering::Acquire);
+        if let Some((cached, rendered)) = &*self.rendered.lock().unwrap() {
+            if *cached == version {
+                return rendered.clone();
+            }
+        }
+        let rendered = render();
+        *self.rendered.lock().unwrap() = Some((version, rendered.clone()));
+        rendered
+    }
+}
+
+// how many times a write has to fail before its list's file says so
+const WRITE_ERROR_ATTEMPTS: u32 = 3;
+
+/// Writes to a container which keep failing, by the ID of the item they're for (or the title of
+/// a new one), so that its file can say that they haven't reached Google.
+#[derive(Default)]
+pub(crate) struct WriteErrors(Mutex<HashMap<String, (String, u32, String)>>);
+
+impl WriteErrors {
+    /// Count a failure of the write to `key`, the item titled `title`. Call before publishing
+    /// the write as pending, which invalidates the rendering.
+    pub(crate) fn record(&self, key: &str, title: &str, error: &impl std::fmt::Display) {
+        // Google's errors can run on with the response body
+        let message = error.to_string();
+        let message = message.lines().next().unwrap_or_default().trim();
+        let mut errors = self.0.lock().unwrap();
+        let error = errors.entry(key.to_owned()).or_default();
+        *error = (title.to_owned(), error.1 + 1, message.to_owned());
+    }
+
+    /// Forget the failures of the write to `key`, now that it's gone through. Call before
+    /// publishing the write, as for [`WriteErrors::record`].
+    pub(crate) fn clear(&self, key: &str) {
+        self.0.lock().unwrap().remove(key);
+    }
+
+    /// A comment for each write which has failed [`WRITE_ERROR_ATTEMPTS`] times, to put at the
+    /// top of the file.
+    pub(crate) fn render(&self) -> Option<String> {
+        let rendered = self
+            .0
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|(_, attempts, _)| *attempts >= WRITE_ERROR_ATTEMPTS)
+            .sorted()
+            .map(|(title, attempts, message)| {
+                format!("# SYNC ERROR: {title} failed to save {attempts} times: {message}\n")
+            })
+            .collect::<String>();
+        (!rendered.is_empty()).then_some(rendered)
+    }
+}
+
 pub(crate) trait MetaPendingContainer
 where
     ByETag<Self::Item>: Eq + Hash,
@@ -318,14 +658,16 @@ where
     fn with_meta<T>(&self, f: impl FnOnce(&Self::Meta) -> T) -> T;
     fn with_pending<T>(
         &self,
-        f: impl FnOnce(&(HashSet<Self::Insert>, HashMap<Id, Self::Modify>)) -> T,
+        f: impl FnOnce(&(IndexSet<Self::Insert>, HashMap<Id, Self::Modify>)) -> T,
     ) -> T;
     fn read(&self) -> ReadHandle<Id, Box<ByETag<Self::Item>>, Self::Meta>;
     #[allow(clippy::type_complexity)]
     fn write(&self) -> MutexGuard<'_, WriteHandle<Id, Box<ByETag<Self::Item>>, Self::Meta>>;
+    fn render_cache(&self) -> &RenderCache;
+    fn write_errors(&self) -> &WriteErrors;
     fn update_pending(
         meta: &Self::Meta,
-        pending: (HashSet<Self::Insert>, HashMap<Id, Self::Modify>),
+        pending: (IndexSet<Self::Insert>, HashMap<Id, Self::Modify>),
     ) -> Self::Meta;
 
     fn get_id(&self, id: &str) -> Option<Box<ByETag<Self::Item>>> {
@@ -337,6 +679,7 @@ where
         tracing::debug!("Adding item: {id}");
         guard.insert(id.to_owned(), Box::new(ByETag(item)));
         guard.refresh();
+        self.render_cache().invalidate();
     }
 
     fn update_id(&self, id: &str, item: Self::Item) {
@@ -344,6 +687,7 @@ where
         tracing::debug!("Updating item: {id}");
         guard.update(id.to_owned(), Box::new(ByETag(item)));
         guard.refresh();
+        self.render_cache().invalidate();
     }
 
     fn delete_id(&self, id: &str) {
@@ -351,6 +695,7 @@ where
         tracing::debug!("Deleting item: {id}");
         guard.empty(id.to_owned());
         guard.refresh();
+        self.render_cache().invalidate();
     }
 
     fn push_pending_insert(&self, insert: Self::Insert) {
@@ -359,6 +704,7 @@ where
         new_pending.0.insert(insert);
         guard.set_meta(self.with_meta(|m| Self::update_pending(m, new_pending)));
         guard.refresh();
+        self.render_cache().invalidate();
     }
 
     fn push_pending_modify(&self, id: Id, modify: Self::Modify) {
@@ -367,6 +713,7 @@ where
         new_pending.1.insert(id, modify);
         guard.set_meta(self.with_meta(|m| Self::update_pending(m, new_pending)));
         guard.refresh();
+        self.render_cache().invalidate();
     }
 
     fn clear_pending(&self) -> Self::Meta {
@@ -374,6 +721,7 @@ where
         let new_pending = Default::default();
         let meta = guard.set_meta(self.with_meta(|m| Self::update_pending(m, new_pending)));
         guard.refresh();
+        self.render_cache().invalidate();
         meta
     }
 }
@@ -390,6 +738,37 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn keywords_are_single_words() {
+        assert_eq!(parse_keyword("NEXT").unwrap(), "NEXT");
+        assert!(parse_keyword("TO DO").is_err());
+        assert!(parse_keyword("").is_err());
+    }
+
+    #[test]
+    fn write_errors_shown_until_cleared() {
+        use google_tasks1::api::{TaskList, Tasks};
+
+        let tasklist = OrgTaskList::from((
+            TaskList {
+                title: Some("Inbox".to_owned()),
+                ..TaskList::default()
+            },
+            Tasks::default(),
+        ));
+        let errors = tasklist.write_errors();
+        for _ in 0..WRITE_ERROR_ATTEMPTS - 1 {
+            errors.record("a", "\"Buy milk\"", &"Bad Request\n{ body }");
+        }
+        assert_eq!(errors.render(), None);
+        errors.record("a", "\"Buy milk\"", &"Bad Request\n{ body }");
+        assert!(tasklist
+            .to_org_string()
+            .contains("\n# SYNC ERROR: \"Buy milk\" failed to save 3 times: Bad Request\n"));
+        errors.clear("a");
+        assert_eq!(errors.render(), None);
+    }
+
     #[test]
     fn diff() {
         insta::glob!("../fixtures", "*/pre.org", |path| {
@@ -423,17 +802,14 @@ mod tests {
             );
             let text_diff = similar::TextDiff::from_lines(&old_raw, &new_raw);
             let (old, new) = (MaybeIdMap::from(&old_org), MaybeIdMap::from(&new_org));
-            let (tx_wcmd, mut rx_wcmd) = tokio::sync::mpsc::unbounded_channel::<WriteCommand>();
             let diff = old.diff(new);
-            let mut commands = Vec::new();
-            OrgTaskList::generate_commands("", diff, &tx_wcmd, &new_org);
-            drop(tx_wcmd);
-            while let Some(cmd) = rx_wcmd.blocking_recv() {
-                match cmd {
-                    WriteCommand::Task { cmd, .. } => commands.push(cmd),
+            let commands = OrgTaskList::generate_commands("", diff, &new_org)
+                .into_iter()
+                .map(|cmd| match cmd {
+                    WriteCommand::Task { cmd, .. } => cmd,
                     _ => unreachable!(),
-                }
-            }
+                })
+                .collect::<Vec<_>>();
             insta::with_settings!({
                 description => text_diff.unified_diff().context_radius(20).header("pre.org", "post.org").to_string(),
                 snapshot_suffix => "commands",
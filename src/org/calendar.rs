@@ -1,25 +1,35 @@
 use std::collections::{HashMap, HashSet};
-use std::str::FromStr;
-use std::sync::atomic::Ordering;
-use std::sync::Mutex;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{hash::Hash, sync::Arc};
 
 use atomic_time::AtomicSystemTime;
-use chrono::Local;
-use chrono_tz::Tz;
+use chrono::{Local, NaiveDate, TimeDelta, Utc};
 use evmap::{ReadHandle, ReadHandleFactory, WriteHandle};
-use google_calendar3::api::{CalendarListEntry, Event, EventDateTime, Events};
+use google_calendar3::api::{
+    CalendarListEntry, ColorDefinition, Event, EventDateTime, EventExtendedProperties, Events,
+    TimePeriod,
+};
+use indexmap::IndexSet;
 use itertools::Itertools;
 use orgize::ast::Headline;
 use orgize::rowan::ast::AstNode;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 
+use crate::client::SyncToken;
 use crate::org::conflict::push_conflict_str;
-use crate::org::timestamp::Timestamp;
-use crate::org::{Diff, MetaPendingContainer};
+use crate::org::html::{html_to_org, HtmlDescriptions};
+use crate::org::timestamp::{
+    display_date, display_tz_name, format_in_display_tz, from_display_tz, Timestamp,
+};
+use crate::org::{
+    add_tag, bump_updated, category, escape_etag, list_tag, parse_org, render_file_keywords,
+    render_options, unescape_etag, Diff, MetaPendingContainer, Rebase,
+};
 use crate::write::{CalendarEventInsert, CalendarEventModify, CalendarEventWrite, WriteCommand};
 
-use super::{def_org_meta, text_from_property_drawer, ByETag, Id, ToOrg};
+use super::{def_org_meta, text_from_property_drawer, ByETag, Id, RenderCache, ToOrg, WriteErrors};
 
 impl PartialEq for ByETag<Event> {
     fn eq(&self, other: &Self) -> bool {
@@ -40,7 +50,13 @@ def_org_meta! {
     CalendarMeta {
         calendar: CalendarListEntry,
         updated: AtomicSystemTime,
-        pending: (HashSet<CalendarEventInsert>, HashMap<String, CalendarEventModify>)
+        // when the earliest event was created, or UNIX_EPOCH if unknown
+        created: AtomicSystemTime,
+        synced: AtomicSystemTime,
+        sync_token: Mutex<Option<SyncToken>>,
+        // deleted upstream (or unsubscribed from), so no longer shown
+        removed: AtomicBool,
+        pending: (IndexSet<CalendarEventInsert>, HashMap<String, CalendarEventModify>)
     }
 }
 
@@ -48,50 +64,130 @@ def_org_meta! {
 pub(crate) struct OrgCalendar(
     ReadHandleFactory<Id, Box<ByETag<Event>>, CalendarMeta>,
     #[allow(clippy::type_complexity)] Arc<Mutex<WriteHandle<Id, Box<ByETag<Event>>, CalendarMeta>>>,
+    Arc<RenderCache>,
+    Arc<WriteErrors>,
 );
 
 impl OrgCalendar {
     pub fn sync(&self, es: Events, updated: SystemTime) {
         let mut guard = self.1.lock().unwrap();
+        if let Some(created) = earliest_created(es.items.iter().flatten()) {
+            let meta = guard.meta().unwrap();
+            let known = meta.created().load(Ordering::Acquire);
+            if known == UNIX_EPOCH || created < known {
+                meta.created().store(created, Ordering::Release);
+            }
+        }
+        let mut changed = false;
         for e in es.items.unwrap_or_default() {
             let Some(id) = &e.id else {
                 tracing::warn!("Event without id found: {:?}", e);
                 continue;
             };
-            if guard.contains_key(id) {
-                {
-                    let v = guard.get_one(id).unwrap();
-                    if v.0.etag == e.etag {
-                        tracing::debug!("Event {id} is up to date, skipping update");
-                        continue;
-                    }
+            // cancelled events are never kept, so that nothing downstream has to skip them
+            let known = guard.get_one(id).map(|v| v.0.etag == e.etag);
+            match (known, e.status.as_deref()) {
+                (Some(true), _) => {
+                    tracing::debug!("Event {id} is up to date, skipping update");
+                    continue;
                 }
-                // Update existing event
-                match e.status.as_deref() {
-                    Some("cancelled") => {
-                        tracing::info!("Removing event: {id}");
-                        guard.empty(id.clone());
-                    }
-                    _ => {
-                        tracing::info!("Updating event: {id}");
-                        guard.insert(id.clone(), Box::new(ByETag(e)));
-                    }
+                (None, Some("cancelled")) => {
+                    tracing::debug!("Event {id} is cancelled, skipping insert");
+                    continue;
+                }
+                (Some(false), Some("cancelled")) => {
+                    tracing::info!(event_id = %id, "Removing event");
+                    guard.empty(id.clone());
+                }
+                (Some(false), _) => {
+                    tracing::info!(event_id = %id, "Updating event");
+                    guard.update(id.clone(), Box::new(ByETag(e)));
+                }
+                (None, _) => {
+                    tracing::info!(event_id = %id, "Inserting new event");
+                    guard.insert(id.clone(), Box::new(ByETag(e)));
                 }
-            } else {
-                // Insert new event
-                tracing::info!("Inserting new event: {id}");
-                guard.insert(id.clone(), Box::new(ByETag(e)));
             }
+            changed = true;
+        }
+        if changed {
+            bump_updated(guard.meta().unwrap().updated(), updated);
         }
-        guard
-            .meta()
-            .unwrap()
-            .updated()
-            .store(updated, Ordering::Release);
         guard.refresh();
+        self.2.invalidate();
+    }
+
+    /// Replace the calendar's events with the blocks of `busy`, for a calendar that
+    /// [`is_free_busy`].
+    pub fn sync_busy(&self, busy: Vec<TimePeriod>, updated: SystemTime) {
+        let mut events = busy_events(busy).items.unwrap_or_default();
+        let current: HashSet<_> = events.iter().filter_map(|e| e.id.clone()).collect();
+        // blocks no longer busy go as if cancelled
+        let stale = self
+            .event_ids()
+            .into_iter()
+            .filter(|id| !current.contains(id))
+            .map(|id| Event {
+                id: Some(id),
+                status: Some("cancelled".to_owned()),
+                ..Event::default()
+            })
+            .collect_vec();
+        events.extend(stale);
+        self.sync(
+            Events {
+                items: Some(events),
+                ..Events::default()
+            },
+            updated,
+        );
+    }
+
+    /// Whether the calendar has gone from Google since it was loaded.
+    pub fn is_removed(&self) -> bool {
+        self.with_meta(|m| m.removed().load(Ordering::Acquire))
+    }
+
+    /// Whether only the times the calendar is busy can be seen, rather than its events.
+    pub fn is_free_busy(&self) -> bool {
+        self.with_meta(|m| is_free_busy(m.calendar()))
+    }
+
+    pub fn mark_removed(&self) {
+        self.with_meta(|m| m.removed().store(true, Ordering::Release));
+    }
+
+    /// Whether the user may change this calendar's events, going by its access role (`reader` and
+    /// `freeBusyReader` may not).
+    pub fn is_writable(&self) -> bool {
+        self.with_meta(|m| {
+            !matches!(
+                m.calendar().access_role.as_deref(),
+                Some("reader" | "freeBusyReader")
+            )
+        })
     }
 
     pub fn parse_event(headline: &Headline) -> Event {
+        let location = text_from_property_drawer!(headline, "location");
+        let (summary, tentative) = parse_headline(
+            headline.title_raw().trim_end(),
+            location.as_deref(),
+            render_options().calendar_format,
+            render_options().headline_link,
+        );
+        // the keyword wins over the status property, so adding or removing it changes the status
+        let status = if tentative {
+            Some("tentative".to_owned())
+        } else {
+            text_from_property_drawer!(headline, "status").map(|status| {
+                if status == "tentative" {
+                    "confirmed".to_owned()
+                } else {
+                    status
+                }
+            })
+        };
         let section = headline.section().unwrap();
         let paragraph = section.syntax().first_child().unwrap();
         let timestamp = orgize::ast::Timestamp::cast(paragraph.first_child().unwrap()).unwrap();
@@ -106,14 +202,21 @@ impl OrgCalendar {
             )
             .trim()
             .to_owned();
+        let description = match &location {
+            Some(location) => description
+                .strip_prefix(&map_link(location))
+                .map(|rest| rest.trim().to_owned())
+                .unwrap_or(description),
+            None => description,
+        };
         Event {
             description: (!description.is_empty()).then_some(description),
             end: end_to_chrono(&timestamp).map(|dt| {
                 if timestamp.hour_end().is_some() {
                     EventDateTime {
                         date: None,
-                        date_time: Some(dt.and_utc()),
-                        time_zone: iana_time_zone::get_timezone().ok(),
+                        date_time: Some(from_display_tz(dt).to_utc()),
+                        time_zone: display_tz_name(),
                     }
                 } else {
                     EventDateTime {
@@ -127,8 +230,8 @@ impl OrgCalendar {
                 if timestamp.hour_start().is_some() {
                     EventDateTime {
                         date: None,
-                        date_time: Some(dt.and_utc()),
-                        time_zone: iana_time_zone::get_timezone().ok(),
+                        date_time: Some(from_display_tz(dt).to_utc()),
+                        time_zone: display_tz_name(),
                     }
                 } else {
                     EventDateTime {
@@ -138,22 +241,55 @@ impl OrgCalendar {
                     }
                 }
             }),
-            summary: Some(headline.title_raw()),
+            summary: Some(summary),
             color_id: text_from_property_drawer!(headline, "color_id"),
-            etag: text_from_property_drawer!(headline, "etag"),
+            etag: text_from_property_drawer!(headline, "etag").map(|etag| unescape_etag(&etag)),
             id: text_from_property_drawer!(headline, "id"),
-            location: text_from_property_drawer!(headline, "location"),
-            status: text_from_property_drawer!(headline, "status"),
+            location,
+            status,
             transparency: text_from_property_drawer!(headline, "transparency"),
+            // always set, so that removing every tag clears them on the server too
+            extended_properties: Some(EventExtendedProperties {
+                private: Some(HashMap::from([(
+                    ORG_TAGS_PROPERTY.to_owned(),
+                    parse_tags(headline),
+                )])),
+                shared: None,
+            }),
             ..Event::default()
         }
     }
 
-    pub fn generate_commands(
-        &self,
-        diff: Diff,
-        tx_wcmd: &tokio::sync::mpsc::UnboundedSender<WriteCommand>,
-    ) -> bool {
+    /// Rebase the events changed in `diff` onto their live versions (see [`Rebase::merge`]). Those
+    /// whose edits conflict are dropped from it and kept pending, so that the file shows them as
+    /// conflicts.
+    pub fn rebase(&self, diff: &mut Diff, rebase: &Rebase) {
+        diff.changed.retain(|id, headline| {
+            match rebase.merge(
+                id,
+                OrgCalendar::parse_event(headline),
+                OrgCalendar::parse_event,
+            ) {
+                Ok(None) => true,
+                Ok(Some(event)) => {
+                    tracing::info!(event_id = %id, "Rebasing edit of event onto changes made since");
+                    *headline = parse_org(render_event(&event, "* ".to_owned(), true))
+                        .first_node::<Headline>()
+                        .expect("rendered event has no headline");
+                    true
+                }
+                Err(event) => {
+                    tracing::warn!(event_id = %id, "Edit of event conflicts with changes made since");
+                    let event = Box::new(event);
+                    self.push_pending_modify(id.to_string(), CalendarEventModify::Patch { event });
+                    false
+                }
+            }
+        });
+    }
+
+    /// The write commands which make the calendar match the edits in `diff`.
+    pub fn generate_commands(&self, diff: Diff) -> Vec<WriteCommand> {
         let Diff {
             added,
             removed,
@@ -163,46 +299,37 @@ impl OrgCalendar {
         self.with_meta(|meta| {
             let calendar_id = meta.calendar().id.as_ref().unwrap();
 
-            let mut did_write = false;
+            let mut commands = Vec::new();
             for id in removed.map().keys() {
-                tracing::info!("Removing event with id {:?}", id);
-                tx_wcmd
-                    .send(WriteCommand::CalendarEvent {
-                        calendar_id: calendar_id.clone(),
-                        cmd: CalendarEventWrite::Modify {
-                            event_id: id.to_string(),
-                            modification: CalendarEventModify::Delete,
-                        },
-                    })
-                    .expect("Failed to send event delete command");
-                did_write = true;
+                tracing::info!(%calendar_id, event_id = %id, "Removing event");
+                commands.push(WriteCommand::CalendarEvent {
+                    calendar_id: calendar_id.clone(),
+                    cmd: CalendarEventWrite::Modify {
+                        event_id: id.to_string(),
+                        modification: CalendarEventModify::Delete,
+                    },
+                });
             }
             for (id, updated) in changed {
                 let event = OrgCalendar::parse_event(&updated).into();
-                tracing::info!("Modifying event with id {:?}: {:?}", id, event);
-                tx_wcmd
-                    .send(WriteCommand::CalendarEvent {
-                        calendar_id: calendar_id.clone(),
-                        cmd: CalendarEventWrite::Modify {
-                            event_id: id.to_string(),
-                            modification: CalendarEventModify::Patch { event },
-                        },
-                    })
-                    .expect("Failed to send event modify command");
-                did_write = true;
+                tracing::info!(%calendar_id, event_id = %id, ?event, "Modifying event");
+                commands.push(WriteCommand::CalendarEvent {
+                    calendar_id: calendar_id.clone(),
+                    cmd: CalendarEventWrite::Modify {
+                        event_id: id.to_string(),
+                        modification: CalendarEventModify::Patch { event },
+                    },
+                });
             }
             for headline in added.fresh() {
                 let event = OrgCalendar::parse_event(headline).into();
-                tracing::info!("Adding new event: {:?}", event);
-                tx_wcmd
-                    .send(WriteCommand::CalendarEvent {
-                        calendar_id: calendar_id.clone(),
-                        cmd: CalendarEventWrite::Insert(CalendarEventInsert::Insert { event }),
-                    })
-                    .expect("Failed to send event insert command");
-                did_write = true;
+                tracing::info!(%calendar_id, ?event, "Adding new event");
+                commands.push(WriteCommand::CalendarEvent {
+                    calendar_id: calendar_id.clone(),
+                    cmd: CalendarEventWrite::Insert(CalendarEventInsert::Insert { event }),
+                });
             }
-            did_write
+            commands
         })
     }
 }
@@ -219,7 +346,7 @@ impl MetaPendingContainer for OrgCalendar {
 
     fn with_pending<T>(
         &self,
-        f: impl FnOnce(&(HashSet<Self::Insert>, HashMap<Id, Self::Modify>)) -> T,
+        f: impl FnOnce(&(IndexSet<Self::Insert>, HashMap<Id, Self::Modify>)) -> T,
     ) -> T {
         self.with_meta(|m| f(m.pending()))
     }
@@ -234,13 +361,25 @@ impl MetaPendingContainer for OrgCalendar {
         self.1.lock().unwrap()
     }
 
+    fn render_cache(&self) -> &RenderCache {
+        &self.2
+    }
+
+    fn write_errors(&self) -> &WriteErrors {
+        &self.3
+    }
+
     fn update_pending(
         meta: &Self::Meta,
-        pending: (HashSet<Self::Insert>, HashMap<Id, Self::Modify>),
+        pending: (IndexSet<Self::Insert>, HashMap<Id, Self::Modify>),
     ) -> Self::Meta {
         (
             meta.calendar().clone(),
             AtomicSystemTime::new(meta.updated().load(Ordering::Acquire)),
+            AtomicSystemTime::new(meta.created().load(Ordering::Acquire)),
+            AtomicSystemTime::new(meta.synced().load(Ordering::Acquire)),
+            Mutex::new(meta.sync_token().lock().unwrap().clone()),
+            AtomicBool::new(meta.removed().load(Ordering::Acquire)),
             pending,
         )
             .into()
@@ -259,6 +398,12 @@ impl From<(CalendarListEntry, Events)> for OrgCalendar {
                         .map(|dt| dt.into())
                         .unwrap_or(std::time::UNIX_EPOCH),
                 ),
+                AtomicSystemTime::new(
+                    earliest_created(es.1.items.iter().flatten()).unwrap_or(UNIX_EPOCH),
+                ),
+                AtomicSystemTime::new(SystemTime::now()),
+                Mutex::new(es.1.next_sync_token.clone()),
+                AtomicBool::new(false),
                 Default::default(),
             )
                 .into(),
@@ -268,24 +413,82 @@ impl From<(CalendarListEntry, Events)> for OrgCalendar {
             (id, Box::new(ByETag(event)))
         }));
         wh.refresh();
-        Self(rh.factory(), Arc::new(Mutex::new(wh)))
+        Self(
+            rh.factory(),
+            Arc::new(Mutex::new(wh)),
+            Default::default(),
+            Default::default(),
+        )
+    }
+}
+
+/// Whether the user may only see when `entry` is busy, and so can't list its events.
+pub(crate) fn is_free_busy(entry: &CalendarListEntry) -> bool {
+    entry.access_role.as_deref() == Some("freeBusyReader")
+}
+
+/// Each of `busy` as an opaque event titled "Busy", named after its start so that it keeps its
+/// ID from one query to the next.
+pub(crate) fn busy_events(busy: Vec<TimePeriod>) -> Events {
+    let items = busy
+        .into_iter()
+        .filter_map(|period| {
+            let (start, end) = (period.start?, period.end?);
+            Some(Event {
+                id: Some(format!("busy{}", start.timestamp())),
+                // so that a block changing its end is updated
+                etag: Some(end.to_rfc3339()),
+                summary: Some("Busy".to_owned()),
+                start: Some(EventDateTime {
+                    date_time: Some(start),
+                    ..EventDateTime::default()
+                }),
+                end: Some(EventDateTime {
+                    date_time: Some(end),
+                    ..EventDateTime::default()
+                }),
+                transparency: Some("opaque".to_owned()),
+                ..Event::default()
+            })
+        })
+        .collect();
+    Events {
+        items: Some(items),
+        ..Events::default()
     }
 }
 
+// `description` as org comments, which can't be mistaken for events whatever it says
+fn render_calendar_description(description: &str) -> Option<String> {
+    let description = description.trim();
+    (!description.is_empty()).then(|| {
+        description
+            .lines()
+            .map(|line| match line.trim_end() {
+                "" => "#\n".to_owned(),
+                line => format!("# {line}\n"),
+            })
+            .collect()
+    })
+}
+
+fn earliest_created<'a>(events: impl IntoIterator<Item = &'a Event>) -> Option<SystemTime> {
+    events
+        .into_iter()
+        .filter_map(|event| event.created)
+        .min()
+        .map(SystemTime::from)
+}
+
 impl From<EventDateTime> for Timestamp<Local> {
     fn from(edt: EventDateTime) -> Self {
         match (edt.date, edt.date_time, &edt.time_zone) {
             (Some(ymd), _, _) => {
                 Timestamp::ActiveDate(ymd) // all day event
             }
-            (_, Some(datetime), None) => {
-                // normal event with date and time
-                Timestamp::ActiveDateTime(datetime.with_timezone(&Local))
-            }
-            (_, Some(utc), Some(tz_str)) => {
-                // event with specified timezone
-                let tz = Tz::from_str(tz_str).expect("Invalid timezone");
-                let datetime = utc.naive_utc().and_local_timezone(tz).unwrap();
+            (_, Some(datetime), _) => {
+                // the instant itself, whatever zone it was set in; reading its UTC wall-clock
+                // time in that zone would be off by the zone's offset (and panic in a DST gap)
                 Timestamp::ActiveDateTime(datetime.with_timezone(&Local))
             }
             (_, _, _) => unreachable!(),
@@ -293,52 +496,49 @@ impl From<EventDateTime> for Timestamp<Local> {
     }
 }
 
-impl ToOrg for OrgCalendar {
-    fn to_org_string(&self) -> String {
+impl OrgCalendar {
+    fn render_org_string(&self) -> String {
         let handle = self.0.handle();
         let meta = handle.meta().expect("meta not found");
         let pending = meta.pending();
         let read_ref = handle.read().unwrap();
+        let category = event_category(meta.calendar());
+        // file-level properties, before the first headline
+        let properties = [
+            ("access_role", &meta.calendar().access_role),
+            ("background_color", &meta.calendar().background_color),
+            ("foreground_color", &meta.calendar().foreground_color),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.as_ref().map(|value| format!(":{name}: {value}\n")))
+        .collect::<String>();
+        let header = [
+            (!properties.is_empty()).then(|| format!(":PROPERTIES:\n{properties}:END:\n")),
+            meta.calendar().summary.as_deref().map(render_file_keywords),
+            meta.calendar()
+                .description
+                .as_deref()
+                .filter(|_| render_options().include_calendar_description)
+                .and_then(render_calendar_description),
+            self.3.render(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<String>();
         [
+            (!header.is_empty()).then_some(header).into_iter().collect(),
             read_ref
                 .iter()
-                .sorted_by_key(|(id, events)| {
-                    let event = events
-                        .get_one()
-                        .unwrap_or_else(|| panic!("No events found for id: {id}"));
+                // a key can briefly have no values between an `empty` and its `refresh`
+                .filter_map(|(id, events)| events.get_one().map(|event| (id, event)))
+                .sorted_by_key(|(_, event)| {
                     (
                         event.0.start.as_ref().cloned().map(Timestamp::from),
                         event.0.end.as_ref().cloned().map(Timestamp::from),
                     )
                 })
-                .flat_map(|(id, events)| {
-                    let event = events
-                        .get_one()
-                        .unwrap_or_else(|| panic!("No events found for id: {id}"));
-                    if event.0.status.as_deref() == Some("cancelled") {
-                        return None; // Skip cancelled events
-                    }
-
-                    let mut str = String::new();
-                    match pending.1.get(id) {
-                        Some(CalendarEventModify::Patch { event: new_event }) => {
-                            push_conflict_str(
-                                &mut str,
-                                &render_event(&event.0, "* COMMENT ".to_owned(), true),
-                                &render_event(new_event, "* ".to_owned(), false),
-                            );
-                        }
-                        Some(CalendarEventModify::Delete) => {
-                            push_conflict_str(
-                                &mut str,
-                                &render_event(&event.0, "* COMMENT ".to_owned(), true),
-                                "",
-                            );
-                        }
-                        None => str.push_str(&render_event(&event.0, "* ".to_owned(), true)),
-                    }
-                    Some(str)
-                })
+                .filter(|(_, event)| is_shown(&event.0, meta.calendar().id.as_deref()))
+                .map(|(id, event)| render_entry(&event.0, pending.1.get(id), category.as_deref()))
                 .collect::<Vec<_>>(),
             pending
                 .0
@@ -355,13 +555,396 @@ impl ToOrg for OrgCalendar {
     }
 }
 
+impl OrgCalendar {
+    /// The ids of the events shown in the calendar's file.
+    pub fn event_ids(&self) -> Vec<Id> {
+        let handle = self.0.handle();
+        let meta = handle.meta().expect("meta not found");
+        let read_ref = handle.read().unwrap();
+        read_ref
+            .iter()
+            .filter_map(|(id, events)| events.get_one().map(|event| (id, event)))
+            .filter(|(_, event)| is_shown(&event.0, meta.calendar().id.as_deref()))
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// The number of events the calendar's file shows.
+    pub fn event_count(&self) -> usize {
+        self.event_ids().len()
+    }
+
+    /// The event `id` on its own, as the calendar's file shows it.
+    pub fn event_to_org_string(&self, id: &str) -> Option<String> {
+        let handle = self.0.handle();
+        let meta = handle.meta().expect("meta not found");
+        let read_ref = handle.read().unwrap();
+        let event = read_ref.get_one(id)?;
+        is_shown(&event.0, meta.calendar().id.as_deref()).then(|| {
+            render_entry(
+                &event.0,
+                meta.pending().1.get(id),
+                event_category(meta.calendar()).as_deref(),
+            )
+        })
+    }
+}
+
+impl ToOrg for OrgCalendar {
+    fn to_org_string(&self) -> String {
+        self.2.get_or_render(|| self.render_org_string())
+    }
+}
+
+// cancelled events are left out, as are declined ones under --hide-declined
+fn is_shown(event: &Event, calendar_id: Option<&str>) -> bool {
+    event.status.as_deref() != Some("cancelled")
+        && !(render_options().hide_declined && is_declined(event, calendar_id))
+}
+
+// the category each event is given under --event-category
+fn event_category(calendar: &CalendarListEntry) -> Option<String> {
+    calendar
+        .summary
+        .as_deref()
+        .filter(|_| render_options().event_category)
+        .map(category)
+}
+
+// `event` as it's shown in its calendar's file, as a conflict if it has an edit pending
+fn render_entry(
+    event: &Event,
+    pending: Option<&CalendarEventModify>,
+    category: Option<&str>,
+) -> String {
+    let render = |prefix: &str| {
+        let rendered = render_event(event, prefix.to_owned(), true);
+        match category {
+            Some(category) => with_category(rendered, category),
+            None => rendered,
+        }
+    };
+    let mut str = String::new();
+    match pending {
+        Some(CalendarEventModify::Patch { event: new_event }) => {
+            push_conflict_str(
+                &mut str,
+                &render("* COMMENT "),
+                &render_event(new_event, "* ".to_owned(), false),
+            );
+        }
+        Some(CalendarEventModify::Delete) => {
+            push_conflict_str(&mut str, &render("* COMMENT "), "");
+        }
+        None => str.push_str(&render("* ")),
+    }
+    str
+}
+
+/// Whether the user has declined `event`, going by their own entry among its attendees: the one
+/// flagged as `self`, or failing that the one with the address of the (primary) calendar.
+fn is_declined(event: &Event, calendar_id: Option<&str>) -> bool {
+    let attendees = event.attendees.iter().flatten();
+    attendees
+        .clone()
+        .find(|attendee| attendee.self_ == Some(true))
+        .or_else(|| {
+            attendees
+                .clone()
+                .find(|attendee| calendar_id.is_some() && attendee.email.as_deref() == calendar_id)
+        })
+        .is_some_and(|attendee| attendee.response_status.as_deref() == Some("declined"))
+}
+
+/// Render the events of every calendar into a single agenda, sorted by start time, with each
+/// headline tagged by the calendar it came from.
+pub(crate) fn agenda_to_org_string<'a>(calendars: impl Iterator<Item = &'a OrgCalendar>) -> String {
+    agenda_headlines(calendars, None)
+        .into_iter()
+        .map(|(_, str)| str)
+        .join("\n")
+}
+
+/// The headlines of [`agenda_to_org_string`] with the start of each, keeping only the events
+/// taking up some of `day` if given.
+pub(crate) fn agenda_headlines<'a>(
+    calendars: impl Iterator<Item = &'a OrgCalendar>,
+    day: Option<NaiveDate>,
+) -> Vec<(Option<Timestamp<Local>>, String)> {
+    calendars
+        .flat_map(|cal| {
+            let handle = cal.0.handle();
+            let meta = handle.meta().expect("meta not found");
+            let summary = meta.calendar().summary.as_deref();
+            let tag = summary.map(|summary| (list_tag(summary), category(summary)));
+            let calendar_id = meta.calendar().id.as_deref();
+            let read_ref = handle.read().unwrap();
+            read_ref
+                .iter()
+                .filter_map(|(_, events)| events.get_one().map(|event| event.0.clone()))
+                .filter(|event| is_shown(event, calendar_id))
+                .filter(|event| day.is_none_or(|day| occurs_on(event, day)))
+                .map(|event| (tag.clone(), event))
+                .collect::<Vec<_>>()
+        })
+        .sorted_by_key(|(_, event)| {
+            (
+                event.start.as_ref().cloned().map(Timestamp::from),
+                event.end.as_ref().cloned().map(Timestamp::from),
+            )
+        })
+        .map(|(tag, event)| {
+            let mut str = render_event(&event, "* ".to_owned(), true);
+            if let Some((tag, category)) = tag {
+                add_tag(&mut str, &tag);
+                if render_options().event_category {
+                    str = with_category(str, &category);
+                }
+            }
+            (event.start.map(Timestamp::from), str)
+        })
+        .collect()
+}
+
+// whether `event` takes up any of `day` in the display timezone; an all-day event's end date is
+// the day after its last, and a timed event ending at midnight doesn't reach into the next day
+fn occurs_on(event: &Event, day: NaiveDate) -> bool {
+    let Some(start) = &event.start else {
+        return false;
+    };
+    let first = match (start.date, start.date_time) {
+        (Some(date), _) => date,
+        (_, Some(datetime)) => display_date(&datetime),
+        _ => return false,
+    };
+    let last = match event.end.as_ref().map(|end| (end.date, end.date_time)) {
+        Some((Some(date), _)) => date.pred_opt().unwrap_or(date),
+        Some((_, Some(datetime))) => display_date(&(datetime - TimeDelta::nanoseconds(1))),
+        _ => first,
+    };
+    (first..=last.max(first)).contains(&day)
+}
+
+// `rendered` with a `:CATEGORY:` property at the top of its drawer
+fn with_category(mut rendered: String, category: &str) -> String {
+    const DRAWER: &str = ":PROPERTIES:\n";
+    if let Some(at) = rendered.find(DRAWER) {
+        rendered.insert_str(at + DRAWER.len(), &format!(":CATEGORY: {category}\n"));
+    }
+    rendered
+}
+
+/// Layout of the title of an event's headline.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum CalendarFormat {
+    /// `Team sync`
+    #[default]
+    Summary,
+    /// `10:00 Team sync` (all-day events have no time)
+    Time,
+    /// `Team sync @ Room 1` (events without a location have no suffix)
+    Location,
+    /// `10:00 Team sync @ Room 1`
+    Full,
+}
+
+impl CalendarFormat {
+    fn shows_time(self) -> bool {
+        matches!(self, CalendarFormat::Time | CalendarFormat::Full)
+    }
+
+    fn shows_location(self) -> bool {
+        matches!(self, CalendarFormat::Location | CalendarFormat::Full)
+    }
+}
+
+const LOCATION_SEPARATOR: &str = " @ ";
+
+// `link` makes the summary a link to the event in Google Calendar, unless brackets in it would
+// break the link
+fn render_headline(event: &Event, format: CalendarFormat, link: bool) -> String {
+    let mut str = String::new();
+    if event.status.as_deref() == Some("tentative") {
+        str.push_str(TENTATIVE_KEYWORD);
+        str.push(' ');
+    }
+    if format.shows_time() {
+        if let Some(start) = event.start.as_ref().and_then(|start| start.date_time) {
+            str.push_str(&format_in_display_tz(&start, "%H:%M "));
+        }
+    }
+    let summary = event
+        .summary
+        .as_deref()
+        .map(str::trim)
+        .unwrap_or("Untitled Event");
+    match event.html_link.as_deref().filter(|_| link) {
+        Some(html_link) if !summary.contains(['[', ']']) => {
+            str.push_str(&format!("[[{}][{}]]", html_link, summary));
+        }
+        _ => str.push_str(summary),
+    }
+    if format.shows_location() {
+        if let Some(location) = &event.location {
+            str.push_str(LOCATION_SEPARATOR);
+            str.push_str(location);
+        }
+    }
+    str
+}
+
+// inverse of `render_headline`: the summary, and whether the event is marked tentative; the time
+// and location are read from the timestamp and properties instead
+fn parse_headline(
+    title: &str,
+    location: Option<&str>,
+    format: CalendarFormat,
+    link: bool,
+) -> (String, bool) {
+    let (mut title, tentative) = match title
+        .strip_prefix(TENTATIVE_KEYWORD)
+        .filter(|rest| rest.is_empty() || rest.starts_with(' '))
+    {
+        Some(rest) => (rest.trim_start(), true),
+        None => (title, false),
+    };
+    if format.shows_time() {
+        let bytes = title.as_bytes();
+        if bytes.len() >= 6
+            && bytes[..2].iter().all(u8::is_ascii_digit)
+            && bytes[2] == b':'
+            && bytes[3..5].iter().all(u8::is_ascii_digit)
+            && bytes[5] == b' '
+        {
+            title = &title[6..];
+        }
+    }
+    if format.shows_location() {
+        if let Some(location) = location {
+            title = title
+                .strip_suffix(location)
+                .and_then(|rest| rest.strip_suffix(LOCATION_SEPARATOR))
+                .unwrap_or(title);
+        }
+    }
+    if link {
+        if let Some((_, summary)) = title
+            .strip_prefix("[[")
+            .and_then(|rest| rest.strip_suffix("]]"))
+            .and_then(|rest| rest.split_once("]["))
+        {
+            title = summary;
+        }
+    }
+    (title.to_owned(), tentative)
+}
+
+/// Key of the private extended property holding an event's headline tags, stored as org writes
+/// them (`:work:urgent:`).
+const ORG_TAGS_PROPERTY: &str = "org_tags";
+
+fn render_tags(event: &Event) -> Option<&str> {
+    event
+        .extended_properties
+        .as_ref()?
+        .private
+        .as_ref()?
+        .get(ORG_TAGS_PROPERTY)
+        .map(|tags| tags.as_str())
+        .filter(|tags| !tags.is_empty())
+}
+
+// empty when the headline has no tags
+fn parse_tags(headline: &Headline) -> String {
+    let tags = headline.tags().map(|tag| tag.to_string()).collect_vec();
+    if tags.is_empty() {
+        String::new()
+    } else {
+        format!(":{}:", tags.join(":"))
+    }
+}
+
+fn map_link(location: &str) -> String {
+    format!(
+        "[[https://maps.google.com/?q={}][{}]]",
+        utf8_percent_encode(location, NON_ALPHANUMERIC),
+        location.replace(['[', ']'], "")
+    )
+}
+
+/// Headline keyword marking a tentative event, e.g. `* MAYBE Team sync`; confirmed events have
+/// none. Adding or removing it in the file sets the event's status accordingly.
+pub(crate) const TENTATIVE_KEYWORD: &str = "MAYBE";
+
+static EVENT_COLORS: OnceLock<HashMap<String, ColorDefinition>> = OnceLock::new();
+
+/// Set the event colours, by colour id, from the `colors` resource, fetched once at startup.
+pub(crate) fn set_event_colors(colors: HashMap<String, ColorDefinition>) {
+    EVENT_COLORS.set(colors).expect("event colours already set");
+}
+
+// the name of an event's colour, and the colour itself when `colors` has it
+fn event_color(
+    color_id: &str,
+    colors: Option<&HashMap<String, ColorDefinition>>,
+) -> Option<String> {
+    let name = event_color_name(color_id);
+    let background = colors.and_then(|colors| colors.get(color_id)?.background.as_deref());
+    match (name, background) {
+        (Some(name), Some(background)) => Some(format!("{} {}", name, background)),
+        (name, background) => name.or(background).map(str::to_owned),
+    }
+}
+
+// the names Google Calendar gives the event colours, which the `colors` resource doesn't have
+fn event_color_name(color_id: &str) -> Option<&'static str> {
+    const NAMES: [&str; 11] = [
+        "Lavender",
+        "Sage",
+        "Grape",
+        "Flamingo",
+        "Banana",
+        "Tangerine",
+        "Peacock",
+        "Graphite",
+        "Blueberry",
+        "Basil",
+        "Tomato",
+    ];
+    NAMES
+        .get(color_id.parse::<usize>().ok()?.checked_sub(1)?)
+        .copied()
+}
+
+// `Name <email>`, noting when it's the authenticated user
+fn render_person(
+    display_name: &Option<String>,
+    email: &Option<String>,
+    is_self: Option<bool>,
+) -> String {
+    let mut str = match (display_name, email) {
+        (Some(name), Some(email)) => format!("{name} <{email}>"),
+        (Some(name), None) => name.clone(),
+        (None, Some(email)) => email.clone(),
+        (None, None) => String::new(),
+    };
+    if is_self == Some(true) {
+        str.push_str(" (self)");
+    }
+    str.trim().to_owned()
+}
+
 fn render_event(event: &Event, prefix: String, with_properties: bool) -> String {
     // HEADLINE
     let mut str = prefix;
-    if let Some(summary) = &event.summary {
-        str.push_str(summary.trim());
-    } else {
-        str.push_str("Untitled Event");
+    str.push_str(&render_headline(
+        event,
+        render_options().calendar_format,
+        render_options().headline_link,
+    ));
+    if let Some(tags) = render_tags(event) {
+        str.push(' ');
+        str.push_str(tags);
     }
     str.push('\n');
 
@@ -383,13 +966,33 @@ fn render_event(event: &Event, prefix: String, with_properties: bool) -> String
             };
         }
         print_property!(id);
-        print_property!(etag);
+        print_property!(etag, escape_etag(etag));
         print_property!(created, Timestamp::from(*created).deactivate());
         print_property!(updated, Timestamp::from(*updated).deactivate());
         print_property!(html_link);
+        // an occurrence of a recurring event has its own id, so edits to it patch just this
+        // occurrence rather than the whole series
+        print_property!(recurring_event_id);
+        print_property!(
+            original_start_time,
+            Timestamp::from(original_start_time.clone()).deactivate()
+        );
         print_property!(visibility);
+        print_property!(color_id);
+        let color = (event.color_id.as_deref()).and_then(|id| event_color(id, EVENT_COLORS.get()));
+        if let Some(color) = color {
+            str.push_str(&format!(":color: {}\n", color));
+        }
         print_property!(status);
         print_property!(location);
+        print_property!(
+            organizer,
+            render_person(&organizer.display_name, &organizer.email, organizer.self_)
+        );
+        print_property!(
+            creator,
+            render_person(&creator.display_name, &creator.email, creator.self_)
+        );
         str.push_str(":END:\n");
     }
 
@@ -407,15 +1010,143 @@ fn render_event(event: &Event, prefix: String, with_properties: bool) -> String
         }
         (_, _) => unreachable!(),
     }
+    if render_options().map_links {
+        if let Some(location) = &event.location {
+            str.push_str(&map_link(location));
+            str.push('\n');
+        }
+    }
     if let Some(description) = &event.description {
         str.push('\n');
-        str.push_str(description);
+        match render_options().html_descriptions {
+            HtmlDescriptions::Raw => str.push_str(description),
+            HtmlDescriptions::Strip => str.push_str(&html_to_org(description, false)),
+            HtmlDescriptions::Org => str.push_str(&html_to_org(description, true)),
+        }
         str.push('\n');
     }
 
     str
 }
 
+impl OrgCalendar {
+    /// The calendar's events as a JSON array, the same ones in the same order as the org file.
+    pub fn to_json(&self) -> String {
+        let handle = self.0.handle();
+        let meta = handle.meta().expect("meta not found");
+        let read_ref = handle.read().unwrap();
+        let events = read_ref
+            .iter()
+            .filter_map(|(_, events)| events.get_one())
+            .filter(|event| is_shown(&event.0, meta.calendar().id.as_deref()))
+            .sorted_by_key(|event| {
+                (
+                    event.0.start.as_ref().cloned().map(Timestamp::from),
+                    event.0.end.as_ref().cloned().map(Timestamp::from),
+                )
+            })
+            .map(|event| &event.0)
+            .collect_vec();
+        let mut json = serde_json::to_string_pretty(&events).expect("Failed to serialize events");
+        json.push('\n');
+        json
+    }
+
+    pub fn to_ics(&self) -> String {
+        let handle = self.0.handle();
+        let meta = handle.meta().expect("meta not found");
+        let read_ref = handle.read().unwrap();
+        let mut str = String::new();
+        push_ics_line(&mut str, "BEGIN:VCALENDAR");
+        push_ics_line(&mut str, "VERSION:2.0");
+        push_ics_line(&mut str, "PRODID:-//orgmode-google-fuse//EN");
+        if let Some(summary) = &meta.calendar().summary {
+            push_ics_line(
+                &mut str,
+                &format!("X-WR-CALNAME:{}", escape_ics_text(summary)),
+            );
+        }
+        for event in read_ref
+            .iter()
+            .filter_map(|(_, events)| events.get_one())
+            .sorted_by_key(|event| {
+                (
+                    event.0.start.as_ref().cloned().map(Timestamp::from),
+                    event.0.end.as_ref().cloned().map(Timestamp::from),
+                )
+            })
+        {
+            // the same events as the org file
+            if !is_shown(&event.0, meta.calendar().id.as_deref()) {
+                continue;
+            }
+            render_ics_event(&mut str, &event.0);
+        }
+        push_ics_line(&mut str, "END:VCALENDAR");
+        str
+    }
+}
+
+fn render_ics_event(str: &mut String, event: &Event) {
+    push_ics_line(str, "BEGIN:VEVENT");
+    if let Some(uid) = event.i_cal_uid.as_ref().or(event.id.as_ref()) {
+        push_ics_line(str, &format!("UID:{}", uid));
+    }
+    // required on every event, so without a last change it's when the event was made, or now
+    let stamp = event.updated.or(event.created).unwrap_or_else(Utc::now);
+    push_ics_line(str, &format!("DTSTAMP:{}", stamp.format("%Y%m%dT%H%M%SZ")));
+    if let Some(start) = &event.start {
+        push_ics_line(str, &format!("DTSTART{}", ics_date_value(start.clone())));
+    }
+    if let Some(end) = &event.end {
+        push_ics_line(str, &format!("DTEND{}", ics_date_value(end.clone())));
+    }
+    if let Some(summary) = &event.summary {
+        push_ics_line(str, &format!("SUMMARY:{}", escape_ics_text(summary.trim())));
+    }
+    if let Some(description) = &event.description {
+        push_ics_line(
+            str,
+            &format!("DESCRIPTION:{}", escape_ics_text(description)),
+        );
+    }
+    push_ics_line(str, "END:VEVENT");
+}
+
+// the value part of a DTSTART/DTEND property, including the leading parameter or colon
+fn ics_date_value(edt: EventDateTime) -> String {
+    match Timestamp::from(edt) {
+        Timestamp::ActiveDate(date) | Timestamp::InactiveDate(date) => {
+            format!(";VALUE=DATE:{}", date.format("%Y%m%d"))
+        }
+        Timestamp::ActiveDateTime(datetime) | Timestamp::InactiveDateTime(datetime) => {
+            format!(":{}", datetime.with_timezone(&Utc).format("%Y%m%dT%H%M%SZ"))
+        }
+    }
+}
+
+fn escape_ics_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+// RFC 5545 content lines are CRLF-terminated and folded at 75 octets
+fn push_ics_line(str: &mut String, line: &str) {
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > 75 {
+            str.push_str("\r\n ");
+            len = 1;
+        }
+        str.push(c);
+        len += c.len_utf8();
+    }
+    str.push_str("\r\n");
+}
+
 // the methods provided by orgize don't work if a time is not specified
 fn start_to_chrono(ts: &orgize::ast::Timestamp) -> Option<chrono::NaiveDateTime> {
     match ts.start_to_chrono() {
@@ -484,4 +1215,788 @@ Description
         );
         assert_eq!(trailing.trim(), "Description");
     }
+
+    #[test]
+    fn render_organizer_and_creator() {
+        use google_calendar3::api::{Event, EventCreator, EventDateTime, EventOrganizer};
+
+        let date = EventDateTime {
+            date: chrono::NaiveDate::from_ymd_opt(1970, 1, 1),
+            ..EventDateTime::default()
+        };
+        let event = Event {
+            summary: Some("Title".to_owned()),
+            start: Some(date.clone()),
+            end: Some(date),
+            organizer: Some(EventOrganizer {
+                display_name: Some("Jo Bloggs".to_owned()),
+                email: Some("jo@example.com".to_owned()),
+                self_: Some(true),
+                ..EventOrganizer::default()
+            }),
+            creator: Some(EventCreator {
+                email: Some("assistant@example.com".to_owned()),
+                ..EventCreator::default()
+            }),
+            ..Event::default()
+        };
+        let rendered = super::render_event(&event, "* ".to_owned(), true);
+        assert!(rendered.contains("\n:organizer: Jo Bloggs <jo@example.com> (self)\n"));
+        assert!(rendered.contains("\n:creator: assistant@example.com\n"));
+
+        let event = Event {
+            organizer: None,
+            creator: None,
+            ..event
+        };
+        let rendered = super::render_event(&event, "* ".to_owned(), true);
+        assert!(!rendered.contains(":organizer:"));
+        assert!(!rendered.contains(":creator:"));
+    }
+
+    #[test]
+    fn round_trip_tentative() {
+        use google_calendar3::api::{Event, EventDateTime};
+
+        let date = EventDateTime {
+            date: chrono::NaiveDate::from_ymd_opt(1970, 1, 1),
+            ..EventDateTime::default()
+        };
+        let event = Event {
+            summary: Some("Team sync".to_owned()),
+            start: Some(date.clone()),
+            end: Some(date),
+            status: Some("tentative".to_owned()),
+            ..Event::default()
+        };
+        let rendered = super::render_event(&event, "* ".to_owned(), true);
+        assert!(rendered.starts_with("* MAYBE Team sync\n"));
+
+        let org = Org::parse(&rendered);
+        let parsed = super::OrgCalendar::parse_event(&org.first_node::<Headline>().unwrap());
+        assert_eq!(parsed.summary.as_deref(), Some("Team sync"));
+        assert_eq!(parsed.status.as_deref(), Some("tentative"));
+
+        // dropping the keyword confirms the event
+        let org = Org::parse(rendered.replacen("MAYBE ", "", 1));
+        let parsed = super::OrgCalendar::parse_event(&org.first_node::<Headline>().unwrap());
+        assert_eq!(parsed.summary.as_deref(), Some("Team sync"));
+        assert_eq!(parsed.status.as_deref(), Some("confirmed"));
+    }
+
+    #[test]
+    fn round_trip_id_and_etag() {
+        use google_calendar3::api::{Event, EventDateTime};
+
+        let date = EventDateTime {
+            date: chrono::NaiveDate::from_ymd_opt(1970, 1, 1),
+            ..EventDateTime::default()
+        };
+        for etag in [
+            "\"3412345678901234\"",
+            "W/\"MTcwNDA2NzIwMDAwMA==/abc+def\"",
+            " \"padded\" ",
+            "\"tab\tinside\"",
+        ] {
+            let event = Event {
+                id: Some("abc_123@google.com".to_owned()),
+                etag: Some(etag.to_owned()),
+                summary: Some("Team sync".to_owned()),
+                start: Some(date.clone()),
+                end: Some(date.clone()),
+                ..Event::default()
+            };
+            let rendered = super::render_event(&event, "* ".to_owned(), true);
+            let org = Org::parse(&rendered);
+            let parsed = super::OrgCalendar::parse_event(&org.first_node::<Headline>().unwrap());
+            assert_eq!(parsed.id, event.id);
+            assert_eq!(parsed.etag.as_deref(), Some(etag), "rendered as {rendered}");
+        }
+    }
+
+    #[test]
+    fn to_json_skips_cancelled() {
+        use google_calendar3::api::{CalendarListEntry, Event, Events};
+
+        let event = |id: &str, status: &str| Event {
+            id: Some(id.to_owned()),
+            summary: Some(id.to_owned()),
+            status: Some(status.to_owned()),
+            ..Event::default()
+        };
+        let calendar = super::OrgCalendar::from((
+            CalendarListEntry::default(),
+            Events {
+                items: Some(vec![
+                    event("kept", "confirmed"),
+                    event("cancelled", "cancelled"),
+                ]),
+                ..Events::default()
+            },
+        ));
+        let events: Vec<Event> = serde_json::from_str(&calendar.to_json()).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].summary.as_deref(), Some("kept"));
+    }
+
+    #[test]
+    fn calendar_description_as_comments() {
+        use crate::org::MaybeIdMap;
+
+        let rendered = super::render_calendar_description("Team rota\n\n* not an event\n").unwrap();
+        assert_eq!(rendered, "# Team rota\n#\n# * not an event\n");
+        let org = Org::parse(format!(
+            "{rendered}\n* Standup\n:PROPERTIES:\n:id: a\n:END:\n"
+        ));
+        assert_eq!(MaybeIdMap::from(&org).len(), 1);
+        assert_eq!(super::render_calendar_description(" \n"), None);
+    }
+
+    #[test]
+    fn event_category() {
+        let rendered = super::with_category(
+            "* Standup\n:PROPERTIES:\n:id: a\n:END:\n".to_owned(),
+            &crate::org::category("Team  rota"),
+        );
+        assert_eq!(
+            rendered,
+            "* Standup\n:PROPERTIES:\n:CATEGORY: Team_rota\n:id: a\n:END:\n"
+        );
+        let org = Org::parse(&rendered);
+        let headline = org.first_node::<orgize::ast::Headline>().unwrap();
+        assert_eq!(
+            super::text_from_property_drawer!(headline, "CATEGORY").as_deref(),
+            Some("Team_rota")
+        );
+    }
+
+    #[test]
+    fn earliest_created() {
+        use std::{sync::atomic::Ordering, time::SystemTime};
+
+        use chrono::{DateTime, Utc};
+        use google_calendar3::api::{CalendarListEntry, Event, Events};
+
+        use crate::org::MetaPendingContainer;
+
+        let events = |created: &[i64]| Events {
+            items: Some(
+                created
+                    .iter()
+                    .map(|&secs| Event {
+                        id: Some(secs.to_string()),
+                        created: DateTime::<Utc>::from_timestamp(secs, 0),
+                        ..Event::default()
+                    })
+                    .collect(),
+            ),
+            ..Events::default()
+        };
+        let created = |calendar: &super::OrgCalendar| {
+            calendar.with_meta(|m| m.created().load(Ordering::Acquire))
+        };
+        let at = |secs| SystemTime::from(DateTime::<Utc>::from_timestamp(secs, 0).unwrap());
+        let calendar = super::OrgCalendar::from((CalendarListEntry::default(), events(&[20, 10])));
+        assert_eq!(created(&calendar), at(10));
+        calendar.sync(events(&[30]), SystemTime::now());
+        assert_eq!(created(&calendar), at(10));
+        calendar.sync(events(&[5]), SystemTime::now());
+        assert_eq!(created(&calendar), at(5));
+    }
+
+    #[test]
+    fn color_properties() {
+        use std::collections::HashMap;
+
+        use google_calendar3::api::{
+            CalendarListEntry, ColorDefinition, Event, EventDateTime, Events,
+        };
+
+        use crate::org::ToOrg;
+
+        let day = |d| EventDateTime {
+            date: chrono::NaiveDate::from_ymd_opt(2024, 1, d),
+            ..EventDateTime::default()
+        };
+        let calendar = super::OrgCalendar::from((
+            CalendarListEntry {
+                background_color: Some("#9fe1e7".to_owned()),
+                foreground_color: Some("#000000".to_owned()),
+                ..CalendarListEntry::default()
+            },
+            Events {
+                items: Some(vec![Event {
+                    id: Some("event".to_owned()),
+                    color_id: Some("11".to_owned()),
+                    start: Some(day(1)),
+                    end: Some(day(2)),
+                    ..Event::default()
+                }]),
+                ..Events::default()
+            },
+        ));
+        let rendered = calendar.to_org_string();
+        assert!(rendered.starts_with(
+            ":PROPERTIES:\n:background_color: #9fe1e7\n:foreground_color: #000000\n:END:\n"
+        ));
+        assert!(rendered.contains(":color_id: 11\n:color: Tomato\n"));
+
+        // with the colours fetched, the colour itself follows its name
+        let colors = HashMap::from([(
+            "11".to_owned(),
+            ColorDefinition {
+                background: Some("#dc2127".to_owned()),
+                foreground: Some("#1d1d1d".to_owned()),
+            },
+        )]);
+        assert_eq!(
+            super::event_color("11", Some(&colors)).as_deref(),
+            Some("Tomato #dc2127")
+        );
+        assert_eq!(super::event_color("12", Some(&colors)), None);
+    }
+
+    #[test]
+    fn ics_dtstamp() {
+        use google_calendar3::api::Event;
+
+        let ics = |event: &Event| {
+            let mut str = String::new();
+            super::render_ics_event(&mut str, event);
+            str
+        };
+        let created = chrono::DateTime::from_timestamp(86400, 0);
+        let event = Event {
+            created,
+            ..Event::default()
+        };
+        assert!(ics(&event).contains("DTSTAMP:19700102T000000Z\r\n"));
+        assert!(ics(&Event::default()).contains("DTSTAMP:"));
+    }
+
+    #[test]
+    fn pending_inserts_deduplicated() {
+        use google_calendar3::api::{CalendarListEntry, Event, Events};
+
+        use crate::{org::MetaPendingContainer, write::CalendarEventInsert};
+
+        let calendar = super::OrgCalendar::from((CalendarListEntry::default(), Events::default()));
+        let insert = |transparency: &str| CalendarEventInsert::Insert {
+            event: Box::new(Event {
+                summary: Some("Party".to_owned()),
+                status: Some("confirmed".to_owned()),
+                transparency: Some(transparency.to_owned()),
+                ..Event::default()
+            }),
+        };
+        calendar.push_pending_insert(insert("opaque"));
+        calendar.push_pending_insert(insert("opaque"));
+        assert_eq!(calendar.with_pending(|p| p.0.len()), 1);
+        calendar.push_pending_insert(insert("transparent"));
+        assert_eq!(calendar.with_pending(|p| p.0.len()), 2);
+    }
+
+    #[test]
+    fn instance_properties() {
+        use google_calendar3::api::{Event, EventDateTime};
+
+        let day = |d| EventDateTime {
+            date: chrono::NaiveDate::from_ymd_opt(2024, 1, d),
+            ..EventDateTime::default()
+        };
+        let event = Event {
+            id: Some("series_20240102".to_owned()),
+            recurring_event_id: Some("series".to_owned()),
+            original_start_time: Some(day(2)),
+            start: Some(day(3)),
+            end: Some(day(4)),
+            ..Event::default()
+        };
+        let rendered = super::render_event(&event, "* ".to_owned(), true);
+        assert!(rendered.contains(
+            ":id: series_20240102\n:recurring_event_id: series\n:original_start_time: [2024-01-02 Tue]\n"
+        ));
+    }
+
+    #[test]
+    fn range_across_dst() {
+        use chrono::{TimeZone, Utc};
+        use google_calendar3::api::EventDateTime;
+
+        use super::Timestamp;
+
+        // 00:30 GMT to 02:30 BST, as Google gives it for an event set in London, and the
+        // repeated 01:30 when the clocks go back
+        let edt = |h, m, day, month| EventDateTime {
+            date_time: Some(Utc.with_ymd_and_hms(2024, month, day, h, m, 0).unwrap()),
+            time_zone: Some("Europe/London".to_owned()),
+            ..EventDateTime::default()
+        };
+        for (start, end) in [
+            (edt(0, 30, 31, 3), edt(1, 30, 31, 3)),
+            (edt(0, 30, 27, 10), edt(1, 30, 27, 10)),
+        ] {
+            let instants = (start.date_time.unwrap(), end.date_time.unwrap());
+            let (start, end) = (Timestamp::from(start), Timestamp::from(end));
+            assert!(start < end);
+            // the instants themselves, rather than their UTC wall-clock times read in London
+            match (start, end) {
+                (Timestamp::ActiveDateTime(start), Timestamp::ActiveDateTime(end)) => {
+                    assert_eq!((start.to_utc(), end.to_utc()), instants)
+                }
+                _ => panic!("expected times"),
+            }
+        }
+    }
+
+    #[test]
+    fn declined_by_self() {
+        use google_calendar3::api::{Event, EventAttendee};
+
+        let attendee = |email: &str, is_self: bool, response: &str| EventAttendee {
+            email: Some(email.to_owned()),
+            self_: is_self.then_some(true),
+            response_status: Some(response.to_owned()),
+            ..EventAttendee::default()
+        };
+        let event = |attendees| Event {
+            attendees: Some(attendees),
+            ..Event::default()
+        };
+        let me = "me@example.com";
+        assert!(super::is_declined(
+            &event(vec![
+                attendee("you@example.com", false, "accepted"),
+                attendee(me, true, "declined"),
+            ]),
+            None
+        ));
+        // someone else declining doesn't hide it
+        assert!(!super::is_declined(
+            &event(vec![
+                attendee("you@example.com", false, "declined"),
+                attendee(me, true, "tentative"),
+            ]),
+            Some(me)
+        ));
+        // without the `self` flag, the calendar's address identifies the user
+        assert!(super::is_declined(
+            &event(vec![attendee(me, false, "declined")]),
+            Some(me)
+        ));
+        assert!(!super::is_declined(&Event::default(), Some(me)));
+    }
+
+    #[test]
+    fn sync_bumps_updated_on_change() {
+        use std::{sync::atomic::Ordering, time::UNIX_EPOCH};
+
+        use google_calendar3::api::{CalendarListEntry, Event, Events};
+
+        use crate::org::MetaPendingContainer;
+
+        let events = |etag: &str| Events {
+            items: Some(vec![Event {
+                id: Some("event".to_owned()),
+                etag: Some(etag.to_owned()),
+                ..Event::default()
+            }]),
+            ..Events::default()
+        };
+        let updated = |calendar: &super::OrgCalendar| {
+            calendar.with_meta(|m| m.updated().load(Ordering::Acquire))
+        };
+        let calendar = super::OrgCalendar::from((CalendarListEntry::default(), events("1")));
+        calendar.with_meta(|m| m.updated().store(UNIX_EPOCH, Ordering::Release));
+        calendar.sync(events("1"), UNIX_EPOCH);
+        assert_eq!(updated(&calendar), UNIX_EPOCH);
+        // even though Google's own modification time didn't move
+        calendar.sync(events("2"), UNIX_EPOCH);
+        assert!(updated(&calendar) > UNIX_EPOCH);
+    }
+
+    #[test]
+    fn sync_cancelled() {
+        use std::time::SystemTime;
+
+        use google_calendar3::api::{CalendarListEntry, Event, EventDateTime, Events};
+
+        use crate::org::ToOrg;
+
+        let date = EventDateTime {
+            date: chrono::NaiveDate::from_ymd_opt(1970, 1, 1),
+            ..EventDateTime::default()
+        };
+        let event = |id: &str, etag: &str, summary: &str, status: &str| Event {
+            id: Some(id.to_owned()),
+            etag: Some(etag.to_owned()),
+            summary: Some(summary.to_owned()),
+            status: Some(status.to_owned()),
+            start: Some(date.clone()),
+            end: Some(date.clone()),
+            ..Event::default()
+        };
+        let events = |items| Events {
+            items: Some(items),
+            ..Events::default()
+        };
+        let calendar = super::OrgCalendar::from((
+            CalendarListEntry::default(),
+            events(vec![event("party", "1", "Party", "confirmed")]),
+        ));
+        calendar.sync(
+            events(vec![
+                event("party", "2", "Birthday party", "confirmed"),
+                event("picnic", "1", "Picnic", "cancelled"),
+            ]),
+            SystemTime::now(),
+        );
+        assert_eq!(calendar.event_ids(), ["party"]);
+        let rendered = calendar.to_org_string();
+        assert!(rendered.contains("* Birthday party\n"), "{rendered}");
+        assert!(!rendered.contains("* Party\n"), "{rendered}");
+
+        calendar.sync(
+            events(vec![event("party", "3", "Birthday party", "cancelled")]),
+            SystemTime::now(),
+        );
+        assert!(calendar.event_ids().is_empty());
+        assert!(calendar.event_to_org_string("party").is_none());
+        assert!(!calendar.to_org_string().contains("Birthday party"));
+    }
+
+    #[test]
+    fn sync_busy() {
+        use std::time::SystemTime;
+
+        use chrono::{TimeZone, Utc};
+        use google_calendar3::api::{CalendarListEntry, TimePeriod};
+
+        let period = |start, end| TimePeriod {
+            start: Some(Utc.with_ymd_and_hms(2024, 7, 1, start, 0, 0).unwrap()),
+            end: Some(Utc.with_ymd_and_hms(2024, 7, 1, end, 0, 0).unwrap()),
+        };
+        let entry = CalendarListEntry {
+            access_role: Some("freeBusyReader".to_owned()),
+            ..CalendarListEntry::default()
+        };
+        assert!(super::is_free_busy(&entry));
+        let calendar = super::OrgCalendar::from((
+            entry,
+            super::busy_events(vec![period(9, 10), period(14, 15)]),
+        ));
+        assert!(calendar.is_free_busy() && !calendar.is_writable());
+        let mut ids = calendar.event_ids();
+        ids.sort();
+        assert_eq!(ids, ["busy1719824400", "busy1719842400"]);
+        let morning = calendar.event_to_org_string("busy1719824400").unwrap();
+        assert!(morning.starts_with("* Busy\n"));
+
+        // the afternoon block is freed, and the morning one runs on
+        calendar.sync_busy(vec![period(9, 11)], SystemTime::now());
+        assert_eq!(calendar.event_ids(), ["busy1719824400"]);
+        assert_ne!(
+            calendar.event_to_org_string("busy1719824400").unwrap(),
+            morning
+        );
+    }
+
+    #[test]
+    fn reader_access_role() {
+        use google_calendar3::api::{CalendarListEntry, Events};
+
+        use crate::org::ToOrg;
+
+        let calendar = super::OrgCalendar::from((
+            CalendarListEntry {
+                access_role: Some("reader".to_owned()),
+                ..CalendarListEntry::default()
+            },
+            Events::default(),
+        ));
+        assert!(!calendar.is_writable());
+        assert!(calendar
+            .to_org_string()
+            .starts_with(":PROPERTIES:\n:access_role: reader\n:END:\n"));
+
+        let calendar = super::OrgCalendar::from((CalendarListEntry::default(), Events::default()));
+        assert!(calendar.is_writable());
+    }
+
+    #[test]
+    fn round_trip_tags() {
+        use std::collections::HashMap;
+
+        use google_calendar3::api::{Event, EventDateTime, EventExtendedProperties};
+
+        let date = EventDateTime {
+            date: chrono::NaiveDate::from_ymd_opt(1970, 1, 1),
+            ..EventDateTime::default()
+        };
+        let event = Event {
+            summary: Some("Team sync".to_owned()),
+            start: Some(date.clone()),
+            end: Some(date),
+            extended_properties: Some(EventExtendedProperties {
+                private: Some(HashMap::from([(
+                    super::ORG_TAGS_PROPERTY.to_owned(),
+                    ":work:urgent:".to_owned(),
+                )])),
+                shared: None,
+            }),
+            ..Event::default()
+        };
+        let rendered = super::render_event(&event, "* ".to_owned(), true);
+        assert!(rendered.starts_with("* Team sync :work:urgent:\n"));
+
+        let org = Org::parse(&rendered);
+        let parsed = super::OrgCalendar::parse_event(&org.first_node::<Headline>().unwrap());
+        assert_eq!(parsed.summary.as_deref(), Some("Team sync"));
+        assert_eq!(super::render_tags(&parsed), Some(":work:urgent:"));
+
+        // removing the tags clears them rather than leaving them untouched
+        let org = Org::parse(rendered.replacen(" :work:urgent:", "", 1));
+        let parsed = super::OrgCalendar::parse_event(&org.first_node::<Headline>().unwrap());
+        assert_eq!(super::render_tags(&parsed), None);
+        assert_eq!(
+            parsed.extended_properties.unwrap().private.unwrap()[super::ORG_TAGS_PROPERTY],
+            ""
+        );
+    }
+
+    #[test]
+    fn agenda_joins_tags() {
+        use std::collections::HashMap;
+
+        use google_calendar3::api::{
+            CalendarListEntry, Event, EventDateTime, EventExtendedProperties, Events,
+        };
+
+        let date = EventDateTime {
+            date: chrono::NaiveDate::from_ymd_opt(1970, 1, 1),
+            ..EventDateTime::default()
+        };
+        let event = |id: &str, tags: Option<&str>| Event {
+            id: Some(id.to_owned()),
+            summary: Some(id.to_owned()),
+            start: Some(date.clone()),
+            end: Some(date.clone()),
+            extended_properties: tags.map(|tags| EventExtendedProperties {
+                private: Some(HashMap::from([(
+                    super::ORG_TAGS_PROPERTY.to_owned(),
+                    tags.to_owned(),
+                )])),
+                shared: None,
+            }),
+            ..Event::default()
+        };
+        let calendar = super::OrgCalendar::from((
+            CalendarListEntry {
+                summary: Some("Personal".to_owned()),
+                ..CalendarListEntry::default()
+            },
+            Events {
+                items: Some(vec![
+                    event("Sync", Some(":work:urgent:")),
+                    event("Lunch", None),
+                ]),
+                ..Events::default()
+            },
+        ));
+        let agenda = super::agenda_to_org_string(std::iter::once(&calendar));
+        assert!(agenda.contains("* Sync :work:urgent:Personal:\n"));
+        assert!(agenda.contains("* Lunch :Personal:\n"));
+
+        // so that org reads all of them as tags
+        let org = Org::parse(&agenda);
+        let sync = org
+            .document()
+            .headlines()
+            .find(|headline| headline.title_raw().trim() == "Sync")
+            .unwrap();
+        assert_eq!(
+            sync.tags().map(|tag| tag.to_string()).collect::<Vec<_>>(),
+            ["work", "urgent", "Personal"]
+        );
+    }
+
+    #[test]
+    fn round_trip_headline_formats() {
+        use google_calendar3::api::{Event, EventDateTime};
+
+        use super::{parse_headline, render_headline, CalendarFormat};
+
+        let start = chrono::NaiveDate::from_ymd_opt(1970, 1, 1)
+            .unwrap()
+            .and_hms_opt(10, 0, 0)
+            .unwrap()
+            .and_local_timezone(chrono::Local)
+            .unwrap()
+            .to_utc();
+        let event = Event {
+            summary: Some("Team sync".to_owned()),
+            location: Some("Room 1".to_owned()),
+            start: Some(EventDateTime {
+                date_time: Some(start),
+                ..EventDateTime::default()
+            }),
+            ..Event::default()
+        };
+        for (format, expected) in [
+            (CalendarFormat::Summary, "Team sync"),
+            (CalendarFormat::Time, "10:00 Team sync"),
+            (CalendarFormat::Location, "Team sync @ Room 1"),
+            (CalendarFormat::Full, "10:00 Team sync @ Room 1"),
+        ] {
+            let title = render_headline(&event, format, false);
+            assert_eq!(title, expected);
+            assert_eq!(
+                parse_headline(&title, Some("Room 1"), format, false),
+                ("Team sync".to_owned(), false)
+            );
+        }
+        assert_eq!(
+            parse_headline(
+                "MAYBE 10:00 Team sync @ Room 1",
+                Some("Room 1"),
+                CalendarFormat::Full,
+                false
+            ),
+            ("Team sync".to_owned(), true)
+        );
+        // only the configured parts are stripped
+        assert_eq!(
+            parse_headline(
+                "10:00 Team sync @ Room 1",
+                Some("Room 1"),
+                CalendarFormat::Summary,
+                false
+            ),
+            ("10:00 Team sync @ Room 1".to_owned(), false)
+        );
+    }
+
+    #[test]
+    fn round_trip_headline_link() {
+        use google_calendar3::api::Event;
+
+        use super::{parse_headline, render_headline, CalendarFormat};
+
+        let event = Event {
+            summary: Some("Team sync".to_owned()),
+            location: Some("Room 1".to_owned()),
+            html_link: Some("https://www.google.com/calendar/event?eid=abc".to_owned()),
+            ..Event::default()
+        };
+        let title = render_headline(&event, CalendarFormat::Location, true);
+        assert_eq!(
+            title,
+            "[[https://www.google.com/calendar/event?eid=abc][Team sync]] @ Room 1"
+        );
+        assert_eq!(
+            parse_headline(&title, Some("Room 1"), CalendarFormat::Location, true),
+            ("Team sync".to_owned(), false)
+        );
+        // a retitled headline keeps its new description
+        assert_eq!(
+            parse_headline(
+                "[[https://www.google.com/calendar/event?eid=abc][Standup]]",
+                None,
+                CalendarFormat::Summary,
+                true
+            ),
+            ("Standup".to_owned(), false)
+        );
+        // brackets in the summary would end the link early
+        let event = Event {
+            summary: Some("Team sync [remote]".to_owned()),
+            ..event
+        };
+        assert_eq!(
+            render_headline(&event, CalendarFormat::Summary, true),
+            "Team sync [remote]"
+        );
+    }
+
+    #[test]
+    fn map_link() {
+        assert_eq!(
+            super::map_link("10 Downing St, London [main door]"),
+            "[[https://maps.google.com/?q=10%20Downing%20St%2C%20London%20%5Bmain%20door%5D]\
+             [10 Downing St, London main door]]"
+        );
+
+        // the link isn't mistaken for part of the description
+        let raw = format!(
+            "* Title\n:PROPERTIES:\n:location: Room 1\n:END:\n<1970-01-01>--<1970-01-01>\n{}\n\nDescription\n",
+            super::map_link("Room 1")
+        );
+        let org = Org::parse(raw);
+        let event = super::OrgCalendar::parse_event(&org.first_node::<Headline>().unwrap());
+        assert_eq!(event.description.as_deref(), Some("Description"));
+        assert_eq!(event.location.as_deref(), Some("Room 1"));
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn round_trip_event(
+            summary in "[a-z0-9]([a-zA-Z0-9 ,.!?']{0,30}[a-zA-Z0-9.!?])?",
+            description in proptest::option::of("[a-z0-9]([a-zA-Z0-9 ,.!?'\n]{0,60}[a-zA-Z0-9.!?])?"),
+            status in proptest::option::of(proptest::sample::select(vec!["confirmed", "tentative"])),
+            all_day: bool,
+            // minutes since 2000-01-01, up to 2040
+            start in 0i64..40 * 365 * 24 * 60,
+            length in 1i64..5 * 24 * 60,
+        ) {
+            use chrono::{DateTime, NaiveDate, TimeDelta, TimeZone, Utc};
+            use google_calendar3::api::{Event, EventDateTime};
+
+            use crate::org::{timestamp::display_tz_name, ByETag};
+
+            let epoch = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+            let start = epoch + TimeDelta::minutes(start);
+            let end = start + TimeDelta::minutes(length);
+            let at = |naive: chrono::NaiveDateTime| {
+                if all_day {
+                    EventDateTime { date: Some(naive.date()), ..EventDateTime::default() }
+                } else {
+                    EventDateTime {
+                        date_time: Some(Utc.from_utc_datetime(&naive)),
+                        time_zone: display_tz_name(),
+                        ..EventDateTime::default()
+                    }
+                }
+            };
+            // a wall-clock time repeated by DST is read back as its first occurrence
+            let repeated = |naive| {
+                let local = DateTime::<Utc>::from_naive_utc_and_offset(naive, Utc)
+                    .with_timezone(&chrono::Local)
+                    .naive_local();
+                chrono::Local.from_local_datetime(&local).single().is_none()
+            };
+            proptest::prop_assume!(all_day || !(repeated(start) || repeated(end)));
+            let event = Event {
+                id: Some("abc_123".to_owned()),
+                etag: Some("\"3412345678901234\"".to_owned()),
+                summary: Some(summary),
+                description,
+                status: status.map(str::to_owned),
+                start: Some(at(start)),
+                end: Some(at(end)),
+                ..Event::default()
+            };
+            let rendered = super::render_event(&event, "* ".to_owned(), true);
+            let org = Org::parse(&rendered);
+            let parsed = super::OrgCalendar::parse_event(&org.first_node::<Headline>().unwrap());
+            proptest::prop_assert_eq!(&parsed.summary, &event.summary, "rendered as {}", rendered);
+            proptest::prop_assert_eq!(&parsed.description, &event.description, "rendered as {}", rendered);
+            proptest::prop_assert_eq!(&parsed.status, &event.status);
+            let parts = |at: &Option<EventDateTime>| {
+                at.as_ref().map(|at| (at.date, at.date_time, at.time_zone.clone()))
+            };
+            proptest::prop_assert_eq!(parts(&parsed.start), parts(&event.start));
+            proptest::prop_assert_eq!(parts(&parsed.end), parts(&event.end));
+            proptest::prop_assert!(ByETag(parsed) == ByETag(event));
+        }
+    }
 }
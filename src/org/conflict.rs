@@ -1,30 +1,67 @@
-const CONFLICT_START: &str = "<<<<<<< remote (read only)";
-const CONFLICT_MIDDLE: &str = "=======";
-const CONFLICT_END: &str = ">>>>>>> local";
+use crate::org::render_options;
+
+pub(super) const CONFLICT_START: &str = "<<<<<<< remote (read only)";
+pub(super) const CONFLICT_MIDDLE: &str = "=======";
+pub(super) const CONFLICT_END: &str = ">>>>>>> local";
+
+/// The lines around and between the two sides of a conflict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Markers<'a> {
+    pub(crate) start: &'a str,
+    pub(crate) middle: &'a str,
+    pub(crate) end: &'a str,
+}
+
+impl Markers<'_> {
+    /// Whether the markers can be told apart, as reading a conflict back needs.
+    pub(crate) fn are_distinct(&self) -> bool {
+        self.start != self.middle && self.middle != self.end && self.start != self.end
+    }
+
+    fn contains(&self, line: &str) -> bool {
+        [self.start, self.middle, self.end].contains(&line)
+    }
+}
 
 pub(crate) fn push_conflict_str(str: &mut String, remote: &str, local: &str) {
-    str.push_str(CONFLICT_START);
+    push_conflict(render_options().conflict_markers(), str, remote, local);
+}
+
+fn push_conflict(markers: Markers, str: &mut String, remote: &str, local: &str) {
+    if remote
+        .lines()
+        .chain(local.lines())
+        .any(|line| markers.contains(line))
+    {
+        // the conflict won't read back as it was written
+        tracing::warn!("Conflict contains a line that's one of its markers");
+    }
+    str.push_str(markers.start);
     str.push('\n');
     str.push_str(remote);
-    str.push_str(CONFLICT_MIDDLE);
+    str.push_str(markers.middle);
     str.push('\n');
     str.push_str(local);
-    str.push_str(CONFLICT_END);
+    str.push_str(markers.end);
     str.push('\n');
 }
 
 pub(crate) fn read_conflict_local(str: &str) -> String {
+    read_local(render_options().conflict_markers(), str)
+}
+
+fn read_local(markers: Markers, str: &str) -> String {
     let mut kept = String::new();
     let mut lines = str.lines();
     while let Some(line) = lines.next() {
-        if line == CONFLICT_START {
+        if line == markers.start {
             for line in lines.by_ref() {
-                if line == CONFLICT_MIDDLE {
+                if line == markers.middle {
                     break;
                 }
             }
             for line in lines.by_ref() {
-                if line == CONFLICT_END {
+                if line == markers.end {
                     break;
                 }
                 kept.push_str(line);
@@ -37,3 +74,47 @@ pub(crate) fn read_conflict_local(str: &str) -> String {
     }
     kept
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{push_conflict, push_conflict_str, read_conflict_local, read_local, Markers};
+
+    #[test]
+    fn round_trip_conflict() {
+        let remote = "* COMMENT remote title\n:PROPERTIES:\n:id: abc\n:END:\n";
+        let local = "* local title\nlocal notes\n";
+        let mut str = "* before\n".to_owned();
+        push_conflict_str(&mut str, remote, local);
+        str.push_str("* after\n");
+        assert_eq!(
+            read_conflict_local(&str),
+            "* before\n* local title\nlocal notes\n* after\n"
+        );
+    }
+
+    #[test]
+    fn round_trip_deleted() {
+        let mut str = String::new();
+        push_conflict_str(&mut str, "* COMMENT deleted remotely\n", "");
+        assert_eq!(read_conflict_local(&str), "");
+    }
+
+    #[test]
+    fn custom_markers() {
+        let markers = Markers {
+            start: "# remote",
+            middle: "# ---",
+            end: "# local",
+        };
+        let mut str = String::new();
+        push_conflict(markers, &mut str, "* remote\n", "* <<<<<<< local\n");
+        assert_eq!(str, "# remote\n* remote\n# ---\n* <<<<<<< local\n# local\n");
+        assert_eq!(read_local(markers, &str), "* <<<<<<< local\n");
+        assert!(markers.are_distinct());
+        assert!(!Markers {
+            end: "# remote",
+            ..markers
+        }
+        .are_distinct());
+    }
+}
@@ -0,0 +1,207 @@
+/// How HTML in event descriptions (common in invites) is shown.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum HtmlDescriptions {
+    /// as Google has it, tags and all
+    #[default]
+    Raw,
+    /// as plain text, keeping only the text of links
+    Strip,
+    /// as org, with links as org links
+    Org,
+}
+
+/// Convert an HTML `description` to plain text, turning links into org links if `links`.
+///
+/// Line breaks and paragraphs become newlines, entities are decoded and every other tag is dropped;
+/// a `<` which doesn't start a tag is kept as it is, so plain-text descriptions pass through.
+pub(crate) fn html_to_org(html: &str, links: bool) -> String {
+    let mut str = String::new();
+    // the target of the link being rendered, and where its text starts in `str`
+    let mut link: Option<(String, usize)> = None;
+    let mut rest = html;
+    while let Some(start) = rest.find(['<', '&']) {
+        str.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if rest.starts_with('&') {
+            let (decoded, len) = decode_entity(rest).unwrap_or(("&".into(), 1));
+            str.push_str(&decoded);
+            rest = &rest[len..];
+            continue;
+        }
+        let Some((tag, len)) = parse_tag(rest) else {
+            str.push('<');
+            rest = &rest[1..];
+            continue;
+        };
+        rest = &rest[len..];
+        match tag.name.as_str() {
+            "br" => str.push('\n'),
+            "p" | "div" | "ul" | "ol" | "tr" if tag.closing => str.push('\n'),
+            // a block starts on a line of its own, whether or not whatever came before was closed
+            "p" | "div" | "ul" | "ol" | "tr" => start_line(&mut str),
+            "li" if !tag.closing => {
+                start_line(&mut str);
+                str.push_str("- ");
+            }
+            "li" => str.push('\n'),
+            "a" if !tag.closing => link = tag.href.map(|href| (href, str.len())),
+            "a" => {
+                if let Some((href, start)) = link.take().filter(|_| links) {
+                    let text = str.split_off(start);
+                    if text.is_empty() || text == href {
+                        str.push_str(&format!("[[{}]]", href));
+                    } else {
+                        str.push_str(&format!("[[{}][{}]]", href, text.replace(['[', ']'], "")));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    str.push_str(rest);
+
+    // at most one blank line between paragraphs
+    let mut collapsed = String::new();
+    let mut newlines = 0;
+    for c in str.trim().chars() {
+        if c == '\n' {
+            newlines += 1;
+            if newlines > 2 {
+                continue;
+            }
+        } else {
+            newlines = 0;
+        }
+        collapsed.push(c);
+    }
+    collapsed
+}
+
+fn start_line(str: &mut String) {
+    if !str.is_empty() && !str.ends_with('\n') {
+        str.push('\n');
+    }
+}
+
+struct Tag {
+    name: String,
+    closing: bool,
+    href: Option<String>,
+}
+
+// the tag at the start of `str`, and its length
+fn parse_tag(str: &str) -> Option<(Tag, usize)> {
+    let inner = str.strip_prefix('<')?;
+    let (closing, inner) = match inner.strip_prefix('/') {
+        Some(inner) => (true, inner),
+        None => (false, inner),
+    };
+    if !inner.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let end = inner.find('>')?;
+    let inner = &inner[..end];
+    let name_end = inner
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(inner.len());
+    let href = inner
+        .find("href=")
+        .map(|at| &inner[at + "href=".len()..])
+        .and_then(|value| {
+            let value = value.trim_start();
+            match value.chars().next()? {
+                quote @ ('"' | '\'') => value[1..].split(quote).next(),
+                _ => value.split_whitespace().next(),
+            }
+        })
+        .map(decode_entities);
+    Some((
+        Tag {
+            name: inner[..name_end].to_ascii_lowercase(),
+            closing,
+            href,
+        },
+        "<".len() + usize::from(closing) + end + ">".len(),
+    ))
+}
+
+fn decode_entities(str: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = str;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let (entity, len) = decode_entity(rest).unwrap_or(("&".into(), 1));
+        decoded.push_str(&entity);
+        rest = &rest[len..];
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+// the character of the entity at the start of `str`, and the entity's length
+fn decode_entity(str: &str) -> Option<(String, usize)> {
+    let end = str.get(..12).unwrap_or(str).find(';')?;
+    let name = &str[1..end];
+    let decoded = match name {
+        "nbsp" => ' ',
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        _ => {
+            let code = match name.strip_prefix('#')? {
+                hex if hex.starts_with(['x', 'X']) => u32::from_str_radix(&hex[1..], 16).ok()?,
+                dec => dec.parse().ok()?,
+            };
+            char::from_u32(code)?
+        }
+    };
+    Some((decoded.to_string(), end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::html_to_org;
+
+    #[test]
+    fn links_and_entities() {
+        let html = "Join&nbsp;us<br>at <a href=\"https://example.com/?a=1&amp;b=2\">the \
+                    <b>site</b></a> &amp; bring &lt;snacks&gt;&#33;<p>See you</p><p>x < y</p>";
+        assert_eq!(
+            html_to_org(html, true),
+            "Join us\nat [[https://example.com/?a=1&b=2][the site]] & bring <snacks>!\n\
+             See you\nx < y"
+        );
+        assert_eq!(
+            html_to_org(html, false),
+            "Join us\nat the site & bring <snacks>!\nSee you\nx < y"
+        );
+    }
+
+    #[test]
+    fn unclosed_blocks() {
+        assert_eq!(
+            html_to_org("Agenda:<ul><li>one<li>two</ul><div>Thanks</div>", false),
+            "Agenda:\n- one\n- two\nThanks"
+        );
+    }
+
+    #[test]
+    fn bare_link() {
+        assert_eq!(
+            html_to_org(
+                "<a href='https://meet.example.com'>https://meet.example.com</a>",
+                true
+            ),
+            "[[https://meet.example.com]]"
+        );
+    }
+
+    #[test]
+    fn plain_text_unchanged() {
+        let text = "Bring A&B's notes\n\n1 < 2 > 0";
+        assert_eq!(html_to_org(text, true), text);
+    }
+}
@@ -1,27 +1,31 @@
 use std::collections::{HashMap, HashSet};
-use std::sync::atomic::Ordering;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{
     hash::Hash,
     sync::{Arc, Mutex},
 };
 
 use atomic_time::AtomicSystemTime;
-use chrono::Local;
+use chrono::{Local, NaiveDate};
 use evmap::{ReadHandle, ReadHandleFactory, WriteHandle};
-use google_tasks1::api::{Task, TaskList, Tasks};
+use google_tasks1::api::{Task, TaskLinks, TaskList, Tasks};
+use indexmap::IndexSet;
 use itertools::Itertools;
 use orgize::ast::Headline;
 use orgize::export::{from_fn_with_ctx, Container, Event};
 use orgize::Org;
 
 use crate::org::conflict::push_conflict_str;
-use crate::org::timestamp::Timestamp;
-use crate::org::{Diff, MetaPendingContainer, Move};
+use crate::org::timestamp::{display_date, from_display_tz, Timestamp};
+use crate::org::{
+    add_tag, bump_updated, escape_etag, list_tag, parse_org, render_file_keywords, render_options,
+    unescape_etag, Diff, MetaPendingContainer, Move, Rebase,
+};
 use crate::streaming::{digit_stream_to_string, streaming_add, string_to_digit_stream};
 use crate::write::{TaskInsert, TaskModify, TaskWrite, WriteCommand};
 
-use super::{def_org_meta, text_from_property_drawer, ByETag, Id, ToOrg};
+use super::{def_org_meta, text_from_property_drawer, ByETag, Id, RenderCache, ToOrg, WriteErrors};
 
 impl PartialEq for ByETag<Task> {
     fn eq(&self, other: &Self) -> bool {
@@ -42,7 +46,10 @@ def_org_meta! {
     TaskListMeta {
         tasklist: TaskList,
         updated: AtomicSystemTime,
-        pending: (HashSet<TaskInsert>, HashMap<String, TaskModify>)
+        synced: AtomicSystemTime,
+        // deleted upstream, so no longer shown
+        removed: AtomicBool,
+        pending: (IndexSet<TaskInsert>, HashMap<String, TaskModify>)
     }
 }
 
@@ -50,72 +57,195 @@ def_org_meta! {
 pub(crate) struct OrgTaskList(
     ReadHandleFactory<Id, Box<ByETag<Task>>, TaskListMeta>,
     #[allow(clippy::type_complexity)] Arc<Mutex<WriteHandle<Id, Box<ByETag<Task>>, TaskListMeta>>>,
+    Arc<RenderCache>,
+    Arc<WriteErrors>,
 );
 
 impl OrgTaskList {
     pub fn sync(&self, ts: Tasks, updated: SystemTime) {
         let mut guard = self.1.lock().unwrap();
+        let mut changed = false;
         for mut t in ts.items.unwrap_or_default() {
             bump_position(&mut t);
             let Some(id) = &t.id else {
                 tracing::warn!("Task without id found: {:?}", t);
                 continue;
             };
+            // tasks updated just before the last sync are fetched again
+            changed |= match guard.get_one(id) {
+                Some(v) => v.0.etag != t.etag,
+                None => t.deleted != Some(true),
+            };
             if guard.contains_key(id) {
                 // Update existing task
                 match t.deleted {
                     Some(true) => {
-                        tracing::info!("Removing task: {id}");
+                        tracing::info!(task_id = %id, "Removing task");
                         guard.empty(id.clone());
                     }
                     _ => {
-                        tracing::info!("Updating task: {id}");
+                        tracing::info!(task_id = %id, "Updating task");
                         guard.update(id.clone(), Box::new(ByETag(t)));
                     }
                 }
+            } else if t.deleted == Some(true) {
+                // deleted before we ever saw it
+                continue;
             } else {
                 // Add new task
-                tracing::info!("Adding new task: {id}");
+                tracing::info!(task_id = %id, "Adding new task");
                 guard.insert(id.clone(), Box::new(ByETag(t)));
             }
         }
-        guard
-            .meta()
-            .unwrap()
-            .updated()
-            .store(updated, Ordering::Release);
+        if changed {
+            bump_updated(guard.meta().unwrap().updated(), updated);
+        }
         guard.refresh();
+        self.2.invalidate();
+    }
+
+    /// Whether the tasklist has gone from Google since it was loaded.
+    pub fn is_removed(&self) -> bool {
+        self.with_meta(|m| m.removed().load(Ordering::Acquire))
+    }
+
+    pub fn mark_removed(&self) {
+        self.with_meta(|m| m.removed().store(true, Ordering::Release));
+    }
+
+    /// Respace the local positions of the children of `parent` (other than `exclude`) evenly,
+    /// preserving their order.
+    ///
+    /// The new positions no longer line up with Google's, so the next sync re-lists the whole
+    /// tasklist to restore the real ones.
+    pub(crate) fn rebalance_positions(&self, parent: Option<&str>, exclude: &str) {
+        let siblings: Vec<_> = self
+            .read()
+            .read()
+            .expect("tasklist read handle destroyed")
+            .iter()
+            .filter_map(|(id, tasks)| tasks.get_one().map(|task| (id.clone(), task.0.clone())))
+            .filter(|(id, task)| id != exclude && task.parent.as_deref() == parent)
+            .sorted_by(|(_, x), (_, y)| x.position.cmp(&y.position))
+            .collect();
+        tracing::debug!(
+            "Rebalancing positions of {} tasks under {:?}",
+            siblings.len(),
+            parent
+        );
+        let step = 10u128.pow(POSITION_LEN as u32) / (siblings.len() as u128 + 1);
+        for (i, (id, mut task)) in siblings.into_iter().enumerate() {
+            task.position = Some(format!(
+                "{:0>width$}",
+                (i as u128 + 1) * step,
+                width = POSITION_LEN
+            ));
+            self.update_id(&id, task);
+        }
+        self.with_meta(|m| m.synced().store(UNIX_EPOCH, Ordering::Release));
     }
 
     pub fn parse_task(headline: &Headline) -> Task {
+        let section = section_from_headline(headline);
+        let (links, notes) = links_and_notes(&section);
         Task {
             completed: headline
                 .closed()
                 .and_then(|p| p.start_to_chrono())
-                .map(|dt| dt.and_local_timezone(Local).unwrap().to_rfc3339()),
-            due: headline
-                .deadline()
-                .and_then(|p| p.start_to_chrono())
-                .map(|dt| dt.and_local_timezone(Local).unwrap().to_rfc3339()),
-            notes: headline.section().map(|s| s.raw().trim().to_owned()),
+                .map(|dt| from_display_tz(dt).to_rfc3339()),
+            due: match render_options().task_due_as {
+                TaskDueAs::Deadline => headline.deadline().or_else(|| headline.scheduled()),
+                TaskDueAs::Scheduled => headline.scheduled().or_else(|| headline.deadline()),
+            }
+            .and_then(|p| due_from_timestamp(&p)),
+            links,
+            // an empty body is sent as no notes, which a patch of a task with notes turns into
+            // clearing them (see `process_tasklist_write`)
+            notes: Some(
+                match text_from_property_drawer!(headline, "Effort")
+                    .filter(|_| render_options().parse_effort)
+                {
+                    Some(effort) => join_effort(&effort, notes),
+                    None => notes.to_owned(),
+                },
+            )
+            .filter(|notes| !notes.is_empty()),
             status: if headline.is_done() {
                 Some("completed".to_owned())
             } else {
                 Some("needsAction".to_owned())
             },
-            title: Some(headline.title_raw()),
-            etag: text_from_property_drawer!(headline, "etag"),
+            title: Some(strip_statistics_cookie(&headline.title_raw()).to_owned()),
+            etag: text_from_property_drawer!(headline, "etag").map(|etag| unescape_etag(&etag)),
             id: text_from_property_drawer!(headline, "id"),
             ..Task::default()
         }
     }
 
-    pub fn generate_commands(
-        tasklist_id: &str,
-        diff: Diff,
-        tx_wcmd: &tokio::sync::mpsc::UnboundedSender<WriteCommand>,
-        new_org: &Org,
-    ) -> bool {
+    /// Insert each top-level headline of `org` as a new task at the top of the list, which is
+    /// where Google puts tasks inserted without a predecessor.
+    pub fn capture(&self, org: &Org, tx_wcmd: &tokio::sync::mpsc::Sender<WriteCommand>) -> bool {
+        let tasklist_id = self.with_meta(|m| m.tasklist().id.clone()).unwrap();
+        let first = self
+            .read()
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|(id, tasks)| tasks.get_one().map(|task| (id, task)))
+            .filter(|(_, task)| task.0.parent.is_none())
+            .min_by_key(|(_, task)| task.0.position.clone())
+            .map(|(id, _)| id.clone());
+        let mut did_write = false;
+        for headline in org.document().headlines() {
+            let task = OrgTaskList::parse_task(&headline);
+            tracing::info!(%tasklist_id, ?task, "Capturing task");
+            tx_wcmd
+                .blocking_send(WriteCommand::Task {
+                    tasklist_id: tasklist_id.clone(),
+                    cmd: TaskWrite::Insert(TaskInsert::Insert {
+                        task: Box::new(task),
+                        new_parent: None,
+                        new_predecessor: None,
+                        new_successor: first.clone(),
+                    }),
+                })
+                .expect("Failed to send task insert command");
+            did_write = true;
+        }
+        did_write
+    }
+
+    /// Rebase the tasks changed in `diff` onto their live versions (see [`Rebase::merge`]). Those
+    /// whose edits conflict are dropped from it and kept pending, so that the file shows them as
+    /// conflicts.
+    pub fn rebase(&self, diff: &mut Diff, rebase: &Rebase) {
+        diff.changed.retain(|id, headline| {
+            match rebase.merge(
+                id,
+                OrgTaskList::parse_task(headline),
+                OrgTaskList::parse_task,
+            ) {
+                Ok(None) => true,
+                Ok(Some(task)) => {
+                    tracing::info!(task_id = %id, "Rebasing edit of task onto changes made since");
+                    *headline = parse_org(render_task(&task, "* ".to_owned(), true, None))
+                        .first_node::<Headline>()
+                        .expect("rendered task has no headline");
+                    true
+                }
+                Err(task) => {
+                    tracing::warn!(task_id = %id, "Edit of task conflicts with changes made since");
+                    let task = Box::new(task);
+                    self.push_pending_modify(id.to_string(), TaskModify::Patch { task });
+                    false
+                }
+            }
+        });
+    }
+
+    /// The write commands which make the tasklist match the edits in `diff`, which took it to
+    /// `new_org`.
+    pub fn generate_commands(tasklist_id: &str, diff: Diff, new_org: &Org) -> Vec<WriteCommand> {
         let Diff {
             added,
             removed,
@@ -123,19 +253,16 @@ impl OrgTaskList {
             moves,
         } = diff;
 
-        let mut did_write = false;
+        let mut commands = Vec::new();
         for id in removed.map().keys() {
-            tracing::info!("Removing task with id {:?}", id);
-            tx_wcmd
-                .send(WriteCommand::Task {
-                    tasklist_id: tasklist_id.to_owned(),
-                    cmd: TaskWrite::Modify {
-                        task_id: id.to_string(),
-                        modification: TaskModify::Delete,
-                    },
-                })
-                .expect("Failed to send task delete command");
-            did_write = true;
+            tracing::info!(%tasklist_id, task_id = %id, "Removing task");
+            commands.push(WriteCommand::Task {
+                tasklist_id: tasklist_id.to_owned(),
+                cmd: TaskWrite::Modify {
+                    task_id: id.to_string(),
+                    modification: TaskModify::Delete,
+                },
+            });
         }
         for Move {
             id: from,
@@ -144,37 +271,31 @@ impl OrgTaskList {
             after: succ,
         } in moves
         {
-            tracing::info!("Moving task {from:?} between ({pred:?}, {succ:?})");
-            tx_wcmd
-                .send(WriteCommand::Task {
-                    tasklist_id: tasklist_id.to_owned(),
-                    cmd: TaskWrite::Move {
-                        task_id: from.to_string(),
-                        new_parent: parent.map(|x| x.to_string()),
-                        new_predecessor: pred.map(|x| x.to_string()),
-                        new_successor: succ.map(|x| x.to_string()),
-                    },
-                })
-                .expect("Failed to send task move command");
-            did_write = true;
+            tracing::info!(%tasklist_id, task_id = %from, ?pred, ?succ, "Moving task");
+            commands.push(WriteCommand::Task {
+                tasklist_id: tasklist_id.to_owned(),
+                cmd: TaskWrite::Move {
+                    task_id: from.to_string(),
+                    new_parent: parent.map(|x| x.to_string()),
+                    new_predecessor: pred.map(|x| x.to_string()),
+                    new_successor: succ.map(|x| x.to_string()),
+                },
+            });
         }
         for (id, updated) in changed {
             let task = OrgTaskList::parse_task(&updated).into();
-            tracing::info!("Modifying task with id {:?}: {:?}", id, task);
-            tx_wcmd
-                .send(WriteCommand::Task {
-                    tasklist_id: tasklist_id.to_owned(),
-                    cmd: TaskWrite::Modify {
-                        task_id: id.to_string(),
-                        modification: TaskModify::Patch { task },
-                    },
-                })
-                .expect("Failed to send task modify command");
-            did_write = true;
+            tracing::info!(%tasklist_id, task_id = %id, ?task, "Modifying task");
+            commands.push(WriteCommand::Task {
+                tasklist_id: tasklist_id.to_owned(),
+                cmd: TaskWrite::Modify {
+                    task_id: id.to_string(),
+                    modification: TaskModify::Patch { task },
+                },
+            });
         }
         for headline in added.fresh().sorted_by_key(|h| h.start()).rev() {
             let task = OrgTaskList::parse_task(headline).into();
-            tracing::info!("Adding new task: {:?}", task);
+            tracing::info!(%tasklist_id, ?task, "Adding new task");
             // TODO: currently, we can only add subtasks to tasks which are
             // already on the server (they have ids)
             let mut new_parent = None;
@@ -215,21 +336,18 @@ impl OrgTaskList {
                 }
             });
             new_org.traverse(&mut handler);
-            tx_wcmd
-                .send(WriteCommand::Task {
-                    tasklist_id: tasklist_id.to_owned(),
-                    cmd: TaskWrite::Insert(TaskInsert::Insert {
-                        task,
-                        new_parent,
-                        new_predecessor,
-                        new_successor,
-                    }),
-                })
-                .expect("Failed to send task insert command");
-            did_write = true;
+            commands.push(WriteCommand::Task {
+                tasklist_id: tasklist_id.to_owned(),
+                cmd: TaskWrite::Insert(TaskInsert::Insert {
+                    task,
+                    new_parent,
+                    new_predecessor,
+                    new_successor,
+                }),
+            });
         }
 
-        did_write
+        commands
     }
 }
 
@@ -245,7 +363,7 @@ impl MetaPendingContainer for OrgTaskList {
 
     fn with_pending<T>(
         &self,
-        f: impl FnOnce(&(HashSet<Self::Insert>, HashMap<Id, Self::Modify>)) -> T,
+        f: impl FnOnce(&(IndexSet<Self::Insert>, HashMap<Id, Self::Modify>)) -> T,
     ) -> T {
         self.with_meta(|m| f(m.pending()))
     }
@@ -260,13 +378,23 @@ impl MetaPendingContainer for OrgTaskList {
         self.1.lock().unwrap()
     }
 
+    fn render_cache(&self) -> &RenderCache {
+        &self.2
+    }
+
+    fn write_errors(&self) -> &WriteErrors {
+        &self.3
+    }
+
     fn update_pending(
         meta: &Self::Meta,
-        pending: (HashSet<Self::Insert>, HashMap<Id, Self::Modify>),
+        pending: (IndexSet<Self::Insert>, HashMap<Id, Self::Modify>),
     ) -> Self::Meta {
         (
             meta.tasklist().clone(),
             AtomicSystemTime::new(meta.updated().load(Ordering::Acquire)),
+            AtomicSystemTime::new(meta.synced().load(Ordering::Acquire)),
+            AtomicBool::new(meta.removed().load(Ordering::Acquire)),
             pending,
         )
             .into()
@@ -285,86 +413,125 @@ impl From<(TaskList, Tasks)> for OrgTaskList {
                 })
                 .unwrap_or(std::time::UNIX_EPOCH),
         );
-        let (rh, mut wh) = evmap::with_meta((ts.0, updated, Default::default()).into());
+        let synced = AtomicSystemTime::new(SystemTime::now());
+        let (rh, mut wh) = evmap::with_meta(
+            (
+                ts.0,
+                updated,
+                synced,
+                AtomicBool::new(false),
+                Default::default(),
+            )
+                .into(),
+        );
         wh.extend(ts.1.items.unwrap_or_default().into_iter().map(|mut task| {
             let id = task.id.clone().unwrap_or_default();
             bump_position(&mut task);
             (id, Box::new(ByETag(task)))
         }));
         wh.refresh();
-        Self(rh.factory(), Arc::new(Mutex::new(wh)))
+        Self(
+            rh.factory(),
+            Arc::new(Mutex::new(wh)),
+            Default::default(),
+            Default::default(),
+        )
     }
 }
 
+// Google positions are 20-digit decimal strings
+pub(crate) const POSITION_LEN: usize = 20;
+
 pub(crate) fn bump_position(task: &mut Task) {
     // increment Task position to free up 00000000000000000000
     if let Some(p) = task.position.iter_mut().next() {
-        *p = digit_stream_to_string(streaming_add(
-            string_to_digit_stream(&*p),
-            std::iter::chain(std::iter::repeat_n(0, 19), std::iter::once(1)),
-        ));
+        let bumped = string_to_digit_stream(p).map(|digits| {
+            digit_stream_to_string(streaming_add(
+                digits,
+                std::iter::chain(std::iter::repeat_n(0, POSITION_LEN - 1), std::iter::once(1)),
+            ))
+        });
+        match bumped {
+            Ok(bumped) => *p = bumped,
+            Err(e) => tracing::warn!(error = %e, position = ?p, "Not bumping task position"),
+        }
     }
 }
 
-impl ToOrg for OrgTaskList {
-    fn to_org_string(&self) -> String {
+impl OrgTaskList {
+    fn render_org_string(&self) -> String {
         let handle = self.0.handle();
         let meta = handle.meta().expect("meta not found");
         let pending = meta.pending();
         let read_ref = handle.read().unwrap();
 
+        // (done, total) direct subtasks of each task with any
+        let mut children = HashMap::<&str, (usize, usize)>::new();
+        for task in read_ref.iter().filter_map(|(_, tasks)| tasks.get_one()) {
+            if let Some(parent) = task.0.parent.as_deref() {
+                let (done, total) = children.entry(parent).or_default();
+                *done += usize::from(task.0.status.as_deref() == Some("completed"));
+                *total += 1;
+            }
+        }
+
+        let left_out = left_out(read_ref.iter().filter_map(|(_, tasks)| tasks.get_one()));
+
         // statefully insert pending edits in-place
         let mut inserts: Vec<_> = pending.0.iter().collect();
         inserts.reverse();
+        // those with nothing to follow (e.g. captured into an empty list) go at the top, where
+        // Google puts a task inserted without a predecessor
+        let mut top = String::new();
+        let is = inserts.extract_if(
+            ..,
+            |TaskInsert::Insert {
+                 new_parent,
+                 new_predecessor,
+                 ..
+             }| new_parent.is_none() && new_predecessor.is_none(),
+        );
+        for TaskInsert::Insert { task, .. } in is {
+            push_conflict_str(
+                &mut top,
+                "",
+                &render_task(task, "* ".to_owned(), false, None),
+            );
+        }
         let str = read_ref
             .iter()
-            .sorted_by_key(|(id, tasks)| {
-                let task = tasks
-                    .get_one()
-                    .unwrap_or_else(|| panic!("No tasks found for id: {id}"));
+            // a key can briefly have no values between an `empty` and its `refresh`
+            .filter_map(|(id, tasks)| tasks.get_one().map(|task| (id, task)))
+            .sorted_by_key(|(_, task)| {
                 format!(
                     "{}{}",
                     task.0
                         .parent
                         .as_ref()
-                        .and_then(|id| {
-                            let parent = read_ref[id]
-                                .get_one()
-                                .unwrap_or_else(|| panic!("No tasks found for id: {id}"));
-                            parent.0.position.clone()
-                        })
+                        .and_then(|id| read_ref.get_one(id))
+                        .and_then(|parent| parent.0.position.clone())
                         .unwrap_or_default(),
                     task.0.position.as_deref().unwrap_or_default(),
                 )
             })
-            .map(|(id, tasks)| {
-                let task = tasks
-                    .get_one()
-                    .unwrap_or_else(|| panic!("No tasks found for id: {id}"));
+            .map(|(id, task)| {
                 let level = if task.0.parent.is_some() { "**" } else { "*" };
-                let mut str = String::new();
-                match pending.1.get(id) {
-                    Some(TaskModify::Patch { task: new_task }) => {
-                        push_conflict_str(
-                            &mut str,
-                            &render_task(&task.0, format!("{level} COMMENT "), true),
-                            &render_task(new_task, format!("{level} "), false),
-                        );
-                    }
-                    Some(TaskModify::Delete) => {
-                        push_conflict_str(
-                            &mut str,
-                            &render_task(&task.0, format!("{level} COMMENT "), true),
-                            "",
-                        );
-                    }
-                    None => str.push_str(&render_task(&task.0, format!("{level} "), true)),
-                }
+                let stats = children.get(id.as_str()).copied();
+                // tasks left out still place the pending inserts after them
+                let mut str = if left_out.contains(id.as_str()) {
+                    String::new()
+                } else {
+                    render_entry(&task.0, pending.1.get(id), level, stats)
+                };
                 let is = inserts.extract_if(.., |TaskInsert::Insert { new_parent, .. }| {
                     new_parent.as_ref() == Some(id)
                 });
                 for TaskInsert::Insert { task, .. } in is {
-                    push_conflict_str(&mut str, "", &render_task(task, "** ".to_owned(), false));
+                    push_conflict_str(
+                        &mut str,
+                        "",
+                        &render_task(task, "** ".to_owned(), false, None),
+                    );
                 }
                 let is = inserts.extract_if(
                     ..,
@@ -373,18 +540,544 @@ impl ToOrg for OrgTaskList {
                      }| new_predecessor.as_ref() == Some(id),
                 );
                 for TaskInsert::Insert { task, .. } in is {
-                    push_conflict_str(&mut str, "", &render_task(task, "* ".to_owned(), false));
+                    push_conflict_str(
+                        &mut str,
+                        "",
+                        &render_task(task, "* ".to_owned(), false, None),
+                    );
                 }
                 str
             })
+            .filter(|str| !str.is_empty())
             .collect::<Vec<_>>()
             .join("\n");
-        assert_eq!(inserts.len(), 0, "leftover pending inserts not rendered");
-        str
+        let str = [top, str]
+            .into_iter()
+            .filter(|str| !str.is_empty())
+            .join("\n");
+        if !inserts.is_empty() {
+            // placed after a task that's since gone, so there's nowhere to show them
+            tracing::warn!(
+                count = inserts.len(),
+                "Pending task inserts left out of the file"
+            );
+        }
+        let header = meta
+            .tasklist()
+            .title
+            .as_deref()
+            .map(render_file_keywords)
+            .into_iter()
+            .chain(self.3.render())
+            .collect::<String>();
+        match header.as_str() {
+            "" => str,
+            header if str.is_empty() => header.to_owned(),
+            header => format!("{}\n{}", header, str),
+        }
+    }
+}
+
+impl OrgTaskList {
+    /// The ids of the tasklist's tasks.
+    pub fn task_ids(&self) -> Vec<Id> {
+        let handle = self.0.handle();
+        let read_ref = handle.read().unwrap();
+        let left_out = left_out(read_ref.iter().filter_map(|(_, tasks)| tasks.get_one()));
+        read_ref
+            .iter()
+            .filter(|(id, tasks)| tasks.get_one().is_some() && !left_out.contains(id.as_str()))
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// The number of tasks the tasklist's file shows.
+    pub fn task_count(&self) -> usize {
+        self.task_ids().len()
+    }
+
+    /// The task `id` on its own, as a top-level headline without its subtasks.
+    pub fn task_to_org_string(&self, id: &str) -> Option<String> {
+        let handle = self.0.handle();
+        let meta = handle.meta().expect("meta not found");
+        let read_ref = handle.read().unwrap();
+        let task = read_ref.get_one(id)?;
+        if left_out(read_ref.iter().filter_map(|(_, tasks)| tasks.get_one())).contains(id) {
+            return None;
+        }
+        let subtasks = read_ref
+            .iter()
+            .filter_map(|(_, tasks)| tasks.get_one())
+            .filter(|task| task.0.parent.as_deref() == Some(id))
+            .fold((0, 0), |(done, total), task| {
+                let completed = task.0.status.as_deref() == Some("completed");
+                (done + usize::from(completed), total + 1)
+            });
+        Some(render_entry(
+            &task.0,
+            meta.pending().1.get(id),
+            "*",
+            Some(subtasks).filter(|&(_, total)| total > 0),
+        ))
+    }
+
+    /// The tasklist's tasks as a JSON array, in the same order as the org file.
+    pub fn to_json(&self) -> String {
+        let handle = self.0.handle();
+        let read_ref = handle.read().unwrap();
+        let left_out = left_out(read_ref.iter().filter_map(|(_, tasks)| tasks.get_one()));
+        let tasks = read_ref
+            .iter()
+            .filter_map(|(id, tasks)| tasks.get_one().filter(|_| !left_out.contains(id.as_str())))
+            .sorted_by_key(|task| {
+                format!(
+                    "{}{}",
+                    task.0
+                        .parent
+                        .as_ref()
+                        .and_then(|id| read_ref.get_one(id))
+                        .and_then(|parent| parent.0.position.clone())
+                        .unwrap_or_default(),
+                    task.0.position.as_deref().unwrap_or_default(),
+                )
+            })
+            .map(|task| &task.0)
+            .collect_vec();
+        let mut json = serde_json::to_string_pretty(&tasks).expect("Failed to serialize tasks");
+        json.push('\n');
+        json
+    }
+}
+
+impl ToOrg for OrgTaskList {
+    fn to_org(&self) -> Org {
+        parse_tasks(&self.to_org_string())
+    }
+
+    fn to_org_string(&self) -> String {
+        self.2.get_or_render(|| match render_options().task_style {
+            TaskStyle::Headline => self.render_org_string(),
+            TaskStyle::Checkbox => headlines_to_checkboxes(&self.render_org_string()),
+        })
+    }
+}
+
+/// How tasks are laid out in a tasklist's file.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum TaskStyle {
+    /// `* TODO Title`, subtasks as subheadlines
+    #[default]
+    Headline,
+    /// `- [ ] Title`, subtasks as nested items; ticking the box completes the task
+    Checkbox,
+}
+
+const OPEN_CHECKBOX: &str = "- [ ]";
+const TICKED_CHECKBOX: &str = "- [X]";
+
+/// Parse the text of a tasklist's file, laid out as `--task-style` says, as headlines.
+pub(crate) fn parse_tasks(text: &str) -> Org {
+    match render_options().task_style {
+        TaskStyle::Headline => parse_org(text),
+        TaskStyle::Checkbox => parse_org(checkboxes_to_headlines(text)),
     }
 }
 
-fn render_task(task: &Task, prefix: String, with_properties: bool) -> String {
+// whether `text` is a checkbox item, once any commas escaping it are dropped
+fn is_checkbox(text: &str) -> bool {
+    let text = text.trim_start_matches(',');
+    [OPEN_CHECKBOX, TICKED_CHECKBOX, "- [x]"]
+        .into_iter()
+        .any(|checkbox| {
+            text.strip_prefix(checkbox)
+                .is_some_and(|title| title.starts_with([' ', '\n']) || title.is_empty())
+        })
+}
+
+// each headline as a checkbox item, indented two spaces per level, with what's under it indented
+// to line up with its title; anything before the first headline is left as it is. A line of a
+// body which is itself a checkbox item (as in a checklist in a task's notes) gets a comma before
+// it, like org escapes lines in blocks, so that it isn't read back as a subtask
+fn headlines_to_checkboxes(org: &str) -> String {
+    let (todo, done) = (
+        render_options().todo_keyword(),
+        render_options().done_keyword(),
+    );
+    let mut str = String::new();
+    let mut indent = None;
+    for line in org.split_inclusive('\n') {
+        let stars = line.len() - line.trim_start_matches('*').len();
+        if let Some(title) = line[stars..].strip_prefix(' ').filter(|_| stars > 0) {
+            let keyword = |keyword| {
+                let title = title.strip_prefix(keyword)?;
+                (title.starts_with([' ', '\n']) || title.is_empty()).then_some(title)
+            };
+            let (checkbox, title) = match (keyword(done), keyword(todo)) {
+                (Some(title), _) => (TICKED_CHECKBOX, title),
+                (None, Some(title)) => (OPEN_CHECKBOX, title),
+                (None, None) => (OPEN_CHECKBOX, title),
+            };
+            let title = title.strip_prefix(' ').unwrap_or(title);
+            let level = "  ".repeat(stars - 1);
+            str.push_str(&format!("{level}{checkbox} {title}"));
+            indent = Some(level + "  ");
+        } else if let Some(indent) = indent.as_ref().filter(|_| !line.trim().is_empty()) {
+            let rest = line.trim_start_matches(' ');
+            str.push_str(indent);
+            if is_checkbox(rest) {
+                str.push_str(&line[..line.len() - rest.len()]);
+                str.push(',');
+                str.push_str(rest);
+            } else {
+                str.push_str(line);
+            }
+        } else {
+            str.push_str(line);
+        }
+    }
+    str
+}
+
+// the reverse of `headlines_to_checkboxes`: every checkbox item at an even indentation no deeper
+// than one level below the one before is a task, with what's indented under it its body, less a
+// comma escaping any of its lines
+fn checkboxes_to_headlines(text: &str) -> String {
+    let (todo, done) = (
+        render_options().todo_keyword(),
+        render_options().done_keyword(),
+    );
+    let mut str = String::new();
+    let mut indent: Option<usize> = None;
+    for line in text.split_inclusive('\n') {
+        let rest = line.trim_start_matches(' ');
+        let spaces = line.len() - rest.len();
+        let item = [
+            (OPEN_CHECKBOX, todo),
+            (TICKED_CHECKBOX, done),
+            ("- [x]", done),
+        ]
+        .into_iter()
+        .find_map(|(checkbox, keyword)| {
+            let title = rest.strip_prefix(checkbox)?;
+            (title.starts_with([' ', '\n']) || title.is_empty()).then_some((keyword, title))
+        })
+        .filter(|_| spaces % 2 == 0 && indent.is_none_or(|indent| spaces <= indent + 2));
+        if let Some((keyword, title)) = item {
+            let title = title.strip_prefix(' ').unwrap_or(title);
+            str.push_str(&format!("{} {keyword} {title}", "*".repeat(spaces / 2 + 1)));
+            indent = Some(spaces);
+        } else if let Some(indent) = indent {
+            let body = &line[spaces.min(indent + 2)..];
+            match rest.strip_prefix(',').filter(|_| is_checkbox(rest)) {
+                Some(unescaped) => {
+                    str.push_str(&body[..body.len() - rest.len()]);
+                    str.push_str(unescaped);
+                }
+                None => str.push_str(body),
+            }
+        } else {
+            str.push_str(line);
+        }
+    }
+    str
+}
+
+/// Planning keyword a task's due date is rendered with. Google Tasks has only the one date, so
+/// this is purely a matter of how it shows up in agendas.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum TaskDueAs {
+    /// `SCHEDULED: <2024-01-01 Mon>`
+    Scheduled,
+    /// `DEADLINE: <2024-01-01 Mon>`
+    #[default]
+    Deadline,
+}
+
+impl TaskDueAs {
+    fn keyword(self) -> &'static str {
+        match self {
+            TaskDueAs::Scheduled => "SCHEDULED",
+            TaskDueAs::Deadline => "DEADLINE",
+        }
+    }
+}
+
+/// The tasks of every tasklist due on `day` and not yet done, each rendered as a top-level
+/// headline tagged by its tasklist, with its due date.
+pub(crate) fn due_headlines<'a>(
+    tasklists: impl Iterator<Item = &'a OrgTaskList>,
+    day: NaiveDate,
+) -> Vec<(Option<Timestamp<Local>>, String)> {
+    tasklists
+        .flat_map(|tl| {
+            let handle = tl.0.handle();
+            let meta = handle.meta().expect("meta not found");
+            let tag = meta.tasklist().title.as_deref().map(list_tag);
+            let read_ref = handle.read().unwrap();
+            let left_out = left_out(read_ref.iter().filter_map(|(_, tasks)| tasks.get_one()));
+            read_ref
+                .iter()
+                .filter_map(|(id, tasks)| tasks.get_one().map(|task| (id, task)))
+                .filter(|(id, task)| {
+                    !left_out.contains(id.as_str()) && task.0.status.as_deref() != Some("completed")
+                })
+                .filter_map(|(_, task)| {
+                    let due = task.0.due.as_deref().and_then(due_to_timestamp)?;
+                    let due_on = match due {
+                        Timestamp::ActiveDate(date) | Timestamp::InactiveDate(date) => date,
+                        Timestamp::ActiveDateTime(datetime)
+                        | Timestamp::InactiveDateTime(datetime) => display_date(&datetime),
+                    };
+                    (due_on == day).then(|| {
+                        let mut str = render_task(&task.0, "* ".to_owned(), true, None);
+                        if let Some(tag) = &tag {
+                            add_tag(&mut str, tag);
+                        }
+                        (Some(due), str)
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+// Google Tasks stores dues as RFC3339, but only the date part is meaningful and it is sent as
+// midnight UTC; rendering that in the local timezone would show a spurious time (or even the
+// wrong day)
+fn due_to_timestamp(due: &str) -> Option<Timestamp<Local>> {
+    let dt = chrono::DateTime::parse_from_rfc3339(due).ok()?;
+    if dt.naive_utc().time() == chrono::NaiveTime::MIN {
+        Some(Timestamp::from(dt.naive_utc().date()))
+    } else {
+        Some(Timestamp::from(dt.with_timezone(&Local)))
+    }
+}
+
+// the inverse of `due_to_timestamp`: Google ignores the time of a due, so keep only the date
+// (as written, before any timezone conversion) and send it as midnight UTC
+fn due_from_timestamp(ts: &orgize::ast::Timestamp) -> Option<String> {
+    let date = chrono::NaiveDate::from_ymd_opt(
+        ts.year_start()?.parse().ok()?,
+        ts.month_start()?.parse().ok()?,
+        ts.day_start()?.parse().ok()?,
+    )?;
+    if ts.hour_start().is_some() {
+        tracing::warn!(
+            "Google Tasks doesn't store times of due dates, keeping only the date {}",
+            date
+        );
+    }
+    Some(
+        date.and_time(chrono::NaiveTime::MIN)
+            .and_utc()
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+    )
+}
+
+// everything between the PROPERTIES drawer and the next headline, keeping blank lines between
+// paragraphs and the indentation of the first line
+fn section_from_headline(headline: &Headline) -> String {
+    headline
+        .section()
+        .map(|s| {
+            s.raw()
+                .trim_start_matches(['\r', '\n'])
+                .trim_end()
+                .to_owned()
+        })
+        .unwrap_or_default()
+}
+
+const LINKS_DRAWER: &str = ":LINKS:";
+
+// Google doesn't let links be edited, so they're rendered in a drawer of their own at the top of
+// the section rather than mixed in with the notes
+fn render_links(links: &[TaskLinks]) -> String {
+    let mut str = String::new();
+    str.push_str(LINKS_DRAWER);
+    str.push('\n');
+    for link in links {
+        let Some(url) = &link.link else {
+            continue;
+        };
+        str.push_str("[[");
+        str.push_str(&url.replace('[', "%5B").replace(']', "%5D"));
+        if let Some(description) = link.description.as_deref().filter(|d| !d.is_empty()) {
+            str.push_str("][");
+            str.push_str(&description.replace(['[', ']'], ""));
+        }
+        str.push_str("]]\n");
+    }
+    str.push_str(":END:\n");
+    str
+}
+
+// splits a leading LINKS drawer off the notes; an empty body clears the notes
+fn links_and_notes(section: &str) -> (Option<Vec<TaskLinks>>, &str) {
+    let Some(drawer) = section
+        .strip_prefix(LINKS_DRAWER)
+        .and_then(|rest| rest.strip_prefix('\n'))
+    else {
+        return (None, section);
+    };
+    let mut links = Vec::new();
+    let mut end = section.len() - drawer.len();
+    for line in drawer.split_inclusive('\n') {
+        end += line.len();
+        let line = line.trim();
+        if line.eq_ignore_ascii_case(":END:") {
+            return (Some(links), section[end..].trim_start_matches(['\r', '\n']));
+        }
+        if let Some(link) = line
+            .strip_prefix("[[")
+            .and_then(|line| line.strip_suffix("]]"))
+        {
+            let (link, description) = match link.split_once("][") {
+                Some((link, description)) => (link, Some(description.to_owned())),
+                None => (link, None),
+            };
+            links.push(TaskLinks {
+                link: Some(link.to_owned()),
+                description,
+                type_: None,
+            });
+        }
+    }
+    // an unterminated drawer is just part of the notes
+    (None, section)
+}
+
+const EFFORT_PREFIX: &str = "Effort:";
+
+// the estimate on an `Effort: H:MM` line of `notes`, and the notes without that line
+fn split_effort(notes: &str) -> Option<(&str, String)> {
+    let lines = notes.split_inclusive('\n').collect::<Vec<_>>();
+    let (at, effort) = lines.iter().enumerate().find_map(|(at, line)| {
+        let effort = line.trim().strip_prefix(EFFORT_PREFIX)?.trim();
+        let (hours, minutes) = effort.split_once(':')?;
+        let is_effort = !hours.is_empty()
+            && hours.bytes().all(|b| b.is_ascii_digit())
+            && minutes.len() == 2
+            && minutes.bytes().all(|b| b.is_ascii_digit());
+        is_effort.then_some((at, effort))
+    })?;
+    let rest = lines[..at].concat() + &lines[at + 1..].concat();
+    Some((effort, rest.trim_end_matches('\n').to_owned()))
+}
+
+// the notes to write back for an `:Effort:` property of `effort`
+fn join_effort(effort: &str, notes: &str) -> String {
+    if notes.is_empty() {
+        format!("{EFFORT_PREFIX} {effort}")
+    } else {
+        format!("{EFFORT_PREFIX} {effort}\n{notes}")
+    }
+}
+
+// the cookie is derived from the subtasks, so isn't part of the title sent to Google
+fn strip_statistics_cookie(title: &str) -> &str {
+    let Some((rest, cookie)) = title.trim_end().rsplit_once(' ') else {
+        return title;
+    };
+    let is_cookie = cookie
+        .strip_prefix('[')
+        .and_then(|cookie| cookie.strip_suffix(']'))
+        .and_then(|cookie| cookie.split_once('/'))
+        .is_some_and(|(done, total)| {
+            [done, total]
+                .iter()
+                .all(|n| n.chars().all(|c| c.is_ascii_digit()))
+        });
+    if is_cookie {
+        rest.trim_end()
+    } else {
+        title
+    }
+}
+
+// the IDs of the tasks left out of the file: hidden ones without `--show-hidden` and completed
+// ones without `--show-completed`; not those with subtasks which aren't, which would otherwise
+// lose their parent
+fn left_out<'a>(tasks: impl Iterator<Item = &'a Box<ByETag<Task>>>) -> HashSet<&'a str> {
+    let options = render_options();
+    left_out_of(tasks, options.show_hidden, options.show_completed)
+}
+
+fn left_out_of<'a>(
+    tasks: impl Iterator<Item = &'a Box<ByETag<Task>>>,
+    show_hidden: bool,
+    show_completed: bool,
+) -> HashSet<&'a str> {
+    let is_left_out = |task: &Task| {
+        !show_hidden && task.hidden == Some(true)
+            || !show_completed && task.status.as_deref() == Some("completed")
+    };
+    let tasks = tasks.collect_vec();
+    let parents = tasks
+        .iter()
+        .filter(|task| !is_left_out(&task.0))
+        .filter_map(|task| task.0.parent.as_deref())
+        .collect::<HashSet<_>>();
+    tasks
+        .into_iter()
+        .filter(|task| is_left_out(&task.0))
+        .filter_map(|task| task.0.id.as_deref())
+        .filter(|id| !parents.contains(id))
+        .collect()
+}
+
+// the RFC 3339 `updated` of a task as an inactive timestamp, or as it is if it doesn't parse
+fn updated_timestamp(updated: &str) -> String {
+    match chrono::DateTime::parse_from_rfc3339(updated) {
+        Ok(dt) => Timestamp::from(dt.with_timezone(&Local))
+            .deactivate()
+            .to_org_string(),
+        Err(_) => updated.to_owned(),
+    }
+}
+
+// `task` as it's shown in its tasklist's file, as a conflict if it has an edit pending
+fn render_entry(
+    task: &Task,
+    pending: Option<&TaskModify>,
+    level: &str,
+    stats: Option<(usize, usize)>,
+) -> String {
+    let mut str = String::new();
+    match pending {
+        Some(TaskModify::Patch { task: new_task }) => {
+            push_conflict_str(
+                &mut str,
+                &render_task(task, format!("{level} COMMENT "), true, stats),
+                &render_task(new_task, format!("{level} "), false, stats),
+            );
+        }
+        Some(TaskModify::Delete) => {
+            push_conflict_str(
+                &mut str,
+                &render_task(task, format!("{level} COMMENT "), true, stats),
+                "",
+            );
+        }
+        None => str.push_str(&render_task(task, format!("{level} "), true, stats)),
+    }
+    str
+}
+
+/// Render `task` as a headline, with a `[done/total]` statistics cookie if it has `children`.
+fn render_task(
+    task: &Task,
+    prefix: String,
+    with_properties: bool,
+    children: Option<(usize, usize)>,
+) -> String {
+    let notes = task.notes.as_deref().unwrap_or_default();
+    let (effort, notes) =
+        match split_effort(notes).filter(|_| with_properties && render_options().parse_effort) {
+            Some((effort, notes)) => (Some(effort), notes),
+            None => (None, notes.to_owned()),
+        };
+
     // HEADLINE
     let mut str = prefix;
     let mut planning = String::new();
@@ -394,23 +1087,28 @@ fn render_task(task: &Task, prefix: String, with_properties: bool) -> String {
         .and_then(|str| chrono::DateTime::parse_from_rfc3339(str).ok())
         .map(|dt| dt.with_timezone(&Local))
     {
+        str.push_str(render_options().done_keyword());
+        str.push(' ');
         planning.push_str("CLOSED: ");
         planning.push_str(&Timestamp::from(*done).deactivate().to_org_string());
     } else {
-        str.push_str("TODO ");
-        if let Some(due) = &task
-            .due
-            .as_ref()
-            .and_then(|str| chrono::DateTime::parse_from_rfc3339(str).ok())
-            .map(|dt| dt.with_timezone(&Local))
-        {
-            planning.push_str("DEADLINE: ");
-            planning.push_str(&Timestamp::from(*due).to_org_string());
+        str.push_str(render_options().todo_keyword());
+        str.push(' ');
+        if let Some(due) = task.due.as_deref().and_then(due_to_timestamp) {
+            planning.push_str(render_options().task_due_as.keyword());
+            planning.push_str(": ");
+            planning.push_str(&due.to_org_string());
         }
     }
     if let Some(title) = &task.title {
         str.push_str(title);
     }
+    if let Some((done, total)) = children {
+        str.push_str(&format!(" [{}/{}]", done, total));
+    }
+    if task.hidden == Some(true) {
+        str.push_str(" :ARCHIVE:");
+    }
     str.push('\n');
 
     // PLANNING
@@ -424,35 +1122,683 @@ fn render_task(task: &Task, prefix: String, with_properties: bool) -> String {
         str.push_str(":PROPERTIES:");
         str.push('\n');
         macro_rules! print_property {
-            ($p:ident) => {
+            ($p:ident, $e:expr) => {
                 if let Some($p) = &task.$p {
                     str.push_str(":");
                     str.push_str(stringify!($p));
                     str.push_str(": ");
-                    str.push_str(&$p.to_org_string());
+                    str.push_str(&$e.to_org_string());
                     str.push('\n');
                 }
             };
+            ($p:ident) => {
+                print_property!($p, $p);
+            };
         }
-        print_property!(etag);
+        print_property!(etag, escape_etag(etag));
         print_property!(id);
-        print_property!(updated);
+        print_property!(updated, updated_timestamp(updated));
         print_property!(self_link);
         print_property!(web_view_link);
-        if let Some(links) = &task.links {
-            str.push_str(&format!(":links: {:?}", links));
+        if let Some(info) = &task.assignment_info {
+            // where the task was assigned from, in Docs or a Chat space; never read back
+            for (key, value) in [
+                ("surface_type", info.surface_type.as_deref()),
+                ("link_to_task", info.link_to_task.as_deref()),
+                (
+                    "space",
+                    info.space_info.as_ref().and_then(|i| i.space.as_deref()),
+                ),
+                (
+                    "drive_file_id",
+                    info.drive_resource_info
+                        .as_ref()
+                        .and_then(|i| i.drive_file_id.as_deref()),
+                ),
+            ] {
+                if let Some(value) = value {
+                    str.push_str(&format!(":{}: {}\n", key, value));
+                }
+            }
+        }
+        if let Some(effort) = effort {
+            str.push_str(":Effort: ");
+            str.push_str(effort);
             str.push('\n');
         }
+        if render_options().debug_properties {
+            // the order among siblings, as kept locally; never read back
+            print_property!(position);
+        }
         str.push_str(":END:");
         str.push('\n');
     }
 
     // SECTION
-    if let Some(notes) = &task.notes {
+    if let Some(links) = task.links.as_deref().filter(|links| !links.is_empty()) {
+        str.push_str(&render_links(links));
+    }
+    if !notes.is_empty() {
         str.push('\n');
-        str.push_str(notes);
+        str.push_str(&notes);
         str.push('\n');
     }
 
     str
 }
+
+#[cfg(test)]
+mod tests {
+    use google_tasks1::api::{Task, TaskLinks};
+    use orgize::{ast::Headline, Org};
+
+    use super::OrgTaskList;
+
+    #[test]
+    fn render_due_date() {
+        let task = Task {
+            title: Some("Title".to_owned()),
+            due: Some("2024-01-01T00:00:00.000Z".to_owned()),
+            ..Task::default()
+        };
+        assert_eq!(
+            super::render_task(&task, "* ".to_owned(), false, None),
+            "* TODO Title\nDEADLINE: <2024-01-01 Mon>\n"
+        );
+    }
+
+    #[test]
+    fn render_updated_timestamp() {
+        use chrono::{Local, TimeZone, Utc};
+
+        let task = Task {
+            title: Some("Title".to_owned()),
+            updated: Some("2024-01-01T12:00:00.000Z".to_owned()),
+            ..Task::default()
+        };
+        let updated = Local.from_utc_datetime(
+            &Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0)
+                .unwrap()
+                .naive_utc(),
+        );
+        assert_eq!(
+            super::render_task(&task, "* ".to_owned(), true, None),
+            format!(
+                "* TODO Title\n:PROPERTIES:\n:updated: {}\n:END:\n",
+                updated.format("[%Y-%m-%d %a %H:%M]")
+            )
+        );
+    }
+
+    #[test]
+    fn render_assignment_info() {
+        use google_tasks1::api::{AssignmentInfo, SpaceInfo};
+
+        let task = Task {
+            title: Some("Title".to_owned()),
+            assignment_info: Some(AssignmentInfo {
+                surface_type: Some("SPACE".to_owned()),
+                space_info: Some(SpaceInfo {
+                    space: Some("spaces/abc".to_owned()),
+                }),
+                ..AssignmentInfo::default()
+            }),
+            ..Task::default()
+        };
+        assert_eq!(
+            super::render_task(&task, "* ".to_owned(), true, None),
+            "* TODO Title\n:PROPERTIES:\n:surface_type: SPACE\n:space: spaces/abc\n:END:\n"
+        );
+        let task = Task {
+            assignment_info: None,
+            ..task
+        };
+        assert_eq!(
+            super::render_task(&task, "* ".to_owned(), true, None),
+            "* TODO Title\n:PROPERTIES:\n:END:\n"
+        );
+    }
+
+    #[test]
+    fn hidden_tasks_left_out() {
+        use crate::org::ByETag;
+
+        let task = |id: &str, parent: Option<&str>, hidden| {
+            Box::new(ByETag(Task {
+                id: Some(id.to_owned()),
+                title: Some(id.to_owned()),
+                parent: parent.map(str::to_owned),
+                hidden: Some(hidden),
+                ..Task::default()
+            }))
+        };
+        let tasks = [
+            task("hidden", None, true),
+            task("hidden parent", None, true),
+            task("child", Some("hidden parent"), false),
+            task("shown", None, false),
+        ];
+        // a hidden task is still shown when it's the parent of one that isn't
+        assert_eq!(
+            super::left_out(tasks.iter()),
+            ["hidden"].into_iter().collect()
+        );
+        assert_eq!(
+            super::render_task(&tasks[1].0, "* ".to_owned(), false, Some((0, 1))),
+            "* TODO hidden parent [0/1] :ARCHIVE:\n"
+        );
+    }
+
+    #[test]
+    fn completed_tasks_left_out() {
+        use std::collections::HashSet;
+
+        use google_tasks1::api::{TaskList, Tasks};
+
+        use crate::org::ToOrg;
+
+        let task = |id: &str, parent: Option<&str>, status: &str, etag: &str| Task {
+            id: Some(id.to_owned()),
+            etag: Some(etag.to_owned()),
+            title: Some(id.to_owned()),
+            parent: parent.map(str::to_owned),
+            status: Some(status.to_owned()),
+            completed: (status == "completed").then(|| "2024-01-01T12:00:00.000Z".to_owned()),
+            ..Task::default()
+        };
+        let tasklist = OrgTaskList::from((
+            TaskList::default(),
+            Tasks {
+                items: Some(vec![
+                    task("done", None, "completed", "1"),
+                    task("done parent", None, "completed", "1"),
+                    task("child", Some("done parent"), "needsAction", "1"),
+                    task("open", None, "needsAction", "1"),
+                ]),
+                ..Tasks::default()
+            },
+        ));
+        let rendered = tasklist.to_org_string();
+        assert!(!rendered.contains("* DONE done\n"));
+        // a completed task is still shown when it's the parent of one that isn't
+        assert!(rendered.contains("* DONE done parent [0/1]\n"));
+        assert!(rendered.contains("* TODO open\n"));
+
+        // as is one completed since the tasklist was listed
+        tasklist.sync(
+            Tasks {
+                items: Some(vec![task("open", None, "completed", "2")]),
+                ..Tasks::default()
+            },
+            std::time::SystemTime::now(),
+        );
+        assert!(!tasklist.to_org_string().contains("open"));
+
+        let handle = tasklist.0.handle();
+        let read_ref = handle.read().unwrap();
+        let tasks = || read_ref.iter().filter_map(|(_, tasks)| tasks.get_one());
+        assert_eq!(super::left_out_of(tasks(), false, true), HashSet::new());
+        assert_eq!(super::left_out_of(tasks(), false, false).len(), 2);
+    }
+
+    #[test]
+    fn done_keyword_round_trip() {
+        let task = Task {
+            title: Some("Title".to_owned()),
+            completed: Some("2024-01-01T12:00:00.000Z".to_owned()),
+            status: Some("completed".to_owned()),
+            ..Task::default()
+        };
+        let rendered = super::render_task(&task, "* ".to_owned(), true, None);
+        assert!(rendered.starts_with("* DONE Title\nCLOSED: ["));
+        let org = crate::org::parse_org(&rendered);
+        let parsed = OrgTaskList::parse_task(&org.first_node::<Headline>().unwrap());
+        assert_eq!(parsed.status.as_deref(), Some("completed"));
+        assert_eq!(parsed.title.as_deref(), Some("Title"));
+    }
+
+    #[test]
+    fn effort_in_notes() {
+        use super::{join_effort, split_effort};
+
+        assert_eq!(
+            split_effort("Call first\nEffort: 1:30\nBring the forms\n"),
+            Some(("1:30", "Call first\nBring the forms".to_owned()))
+        );
+        assert_eq!(split_effort("Effort: 0:45"), Some(("0:45", String::new())));
+        assert_eq!(split_effort("Effort: a lot"), None);
+        assert_eq!(split_effort("Effort: 1:5"), None);
+        assert_eq!(
+            join_effort("1:30", "Call first"),
+            "Effort: 1:30\nCall first"
+        );
+        assert_eq!(join_effort("0:45", ""), "Effort: 0:45");
+    }
+
+    #[test]
+    fn parse_scheduled_as_due() {
+        for planning in ["DEADLINE", "SCHEDULED"] {
+            let org = Org::parse(format!(
+                "* TODO Title\n{planning}: <2024-01-01 Mon 12:00>\n:PROPERTIES:\n:END:\n"
+            ));
+            let task = OrgTaskList::parse_task(&org.first_node::<Headline>().unwrap());
+            assert!(task.due.is_some(), "{planning} not read as the due date");
+        }
+    }
+
+    #[test]
+    fn parse_edited_deadline() {
+        for deadline in ["<2024-01-01 Mon>", "<2024-01-01 Mon 23:30>"] {
+            let org = Org::parse(format!(
+                "* TODO Title\nDEADLINE: {deadline}\n:PROPERTIES:\n:END:\n"
+            ));
+            let task = OrgTaskList::parse_task(&org.first_node::<Headline>().unwrap());
+            assert_eq!(
+                task.due.as_deref(),
+                Some("2024-01-01T00:00:00.000Z"),
+                "{deadline}"
+            );
+        }
+    }
+
+    #[test]
+    fn round_trip_notes() {
+        let notes = "  indented first paragraph\n\nsecond paragraph\nwith two lines";
+        let task = Task {
+            title: Some("Title".to_owned()),
+            notes: Some(notes.to_owned()),
+            ..Task::default()
+        };
+        let org = Org::parse(format!(
+            "{}\n** TODO Child\n",
+            super::render_task(&task, "* ".to_owned(), true, None)
+        ));
+        let headline: Headline = org.first_node().unwrap();
+        assert_eq!(
+            OrgTaskList::parse_task(&headline).notes.as_deref(),
+            Some(notes)
+        );
+    }
+
+    #[test]
+    fn round_trip_links() {
+        let task = Task {
+            title: Some("Title".to_owned()),
+            notes: Some("Notes".to_owned()),
+            links: Some(vec![TaskLinks {
+                link: Some("mailto:someone@example.com".to_owned()),
+                description: Some("Email from someone".to_owned()),
+                type_: Some("email".to_owned()),
+            }]),
+            ..Task::default()
+        };
+        let rendered = super::render_task(&task, "* ".to_owned(), true, None);
+        assert!(
+            rendered.contains("\n[[mailto:someone@example.com][Email from someone]]\n"),
+            "{rendered}"
+        );
+        let org = Org::parse(rendered);
+        let parsed = OrgTaskList::parse_task(&org.first_node::<Headline>().unwrap());
+        assert_eq!(parsed.notes.as_deref(), Some("Notes"));
+        let links = parsed.links.unwrap();
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].link.as_deref(), Some("mailto:someone@example.com"));
+        assert_eq!(links[0].description.as_deref(), Some("Email from someone"));
+    }
+
+    #[test]
+    fn cleared_notes() {
+        let org = Org::parse("* TODO Title\n:PROPERTIES:\n:id: a\n:END:\n");
+        let headline: Headline = org.first_node().unwrap();
+        assert_eq!(OrgTaskList::parse_task(&headline).notes, None);
+    }
+
+    #[test]
+    fn render_skips_valueless_ids() {
+        use google_tasks1::api::{TaskList, Tasks};
+
+        use crate::org::{MetaPendingContainer, ToOrg};
+
+        let task = |id: &str| Task {
+            id: Some(id.to_owned()),
+            title: Some(id.to_owned()),
+            position: Some("00000000000000000001".to_owned()),
+            ..Task::default()
+        };
+        let tasklist = OrgTaskList::from((
+            TaskList::default(),
+            Tasks {
+                items: Some(vec![task("kept"), task("cleared")]),
+                ..Tasks::default()
+            },
+        ));
+        {
+            let mut guard = tasklist.write();
+            guard.clear("cleared".to_owned());
+            guard.refresh();
+        }
+        let org = tasklist.to_org_string();
+        assert!(org.contains("* TODO kept\n"));
+        assert!(!org.contains("cleared"));
+    }
+
+    #[test]
+    fn render_cache_invalidated_by_writes() {
+        use google_tasks1::api::{TaskList, Tasks};
+
+        use crate::org::{MetaPendingContainer, ToOrg};
+
+        let task = |id: &str| Task {
+            id: Some(id.to_owned()),
+            title: Some(id.to_owned()),
+            position: Some("00000000000000000001".to_owned()),
+            ..Task::default()
+        };
+        let tasklist = OrgTaskList::from((
+            TaskList::default(),
+            Tasks {
+                items: Some(vec![task("first")]),
+                ..Tasks::default()
+            },
+        ));
+        let before = tasklist.to_org_string();
+        assert_eq!(tasklist.to_org_string(), before);
+        tasklist.add_id("second", task("second"));
+        let after = tasklist.to_org_string();
+        assert!(after.contains("* TODO first\n"));
+        assert!(after.contains("* TODO second\n"));
+    }
+
+    #[test]
+    fn pending_inserts_deduplicated() {
+        use google_tasks1::api::{TaskList, Tasks};
+
+        use crate::{org::MetaPendingContainer, write::TaskInsert};
+
+        let tasklist = OrgTaskList::from((TaskList::default(), Tasks::default()));
+        let insert = || TaskInsert::Insert {
+            task: Box::new(Task {
+                title: Some("Title".to_owned()),
+                ..Task::default()
+            }),
+            new_parent: None,
+            new_predecessor: Some("predecessor".to_owned()),
+            new_successor: None,
+        };
+        tasklist.push_pending_insert(insert());
+        tasklist.push_pending_insert(insert());
+        assert_eq!(tasklist.with_pending(|p| p.0.len()), 1);
+    }
+
+    #[test]
+    fn pending_inserts_kept_in_order() {
+        use google_tasks1::api::{TaskList, Tasks};
+
+        use crate::{org::MetaPendingContainer, write::TaskInsert};
+
+        let tasklist = OrgTaskList::from((TaskList::default(), Tasks::default()));
+        let insert = |title: &str| TaskInsert::Insert {
+            task: Box::new(Task {
+                title: Some(title.to_owned()),
+                ..Task::default()
+            }),
+            new_parent: None,
+            new_predecessor: Some("predecessor".to_owned()),
+            new_successor: None,
+        };
+        // flushing sends them in this order, each placed against the ones sent before it
+        let titles = ["c", "a", "b", "e", "d"];
+        for title in titles {
+            tasklist.push_pending_insert(insert(title));
+        }
+        let pending = tasklist.with_pending(|p| {
+            p.0.iter()
+                .map(|TaskInsert::Insert { task, .. }| task.title.clone().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(pending, titles);
+    }
+
+    #[test]
+    fn capture_at_top() {
+        use google_tasks1::api::{TaskList, Tasks};
+
+        use crate::write::{TaskInsert, TaskWrite, WriteCommand};
+
+        let task = |id: &str, position: &str| Task {
+            id: Some(id.to_owned()),
+            title: Some(id.to_owned()),
+            position: Some(position.to_owned()),
+            ..Task::default()
+        };
+        let tasklist = OrgTaskList::from((
+            TaskList {
+                id: Some("inbox".to_owned()),
+                ..TaskList::default()
+            },
+            Tasks {
+                items: Some(vec![
+                    task("second", "00000000000000000002"),
+                    task("first", "00000000000000000001"),
+                ]),
+                ..Tasks::default()
+            },
+        ));
+        let (tx_wcmd, mut rx_wcmd) = tokio::sync::mpsc::channel(1024);
+        assert!(tasklist.capture(&Org::parse("* TODO Buy milk\n"), &tx_wcmd));
+        let Ok(WriteCommand::Task {
+            tasklist_id,
+            cmd:
+                TaskWrite::Insert(TaskInsert::Insert {
+                    task,
+                    new_parent: None,
+                    new_predecessor: None,
+                    new_successor,
+                }),
+        }) = rx_wcmd.try_recv()
+        else {
+            panic!("expected a top-level insert");
+        };
+        assert_eq!(tasklist_id, "inbox");
+        assert_eq!(task.title.as_deref(), Some("Buy milk"));
+        assert_eq!(new_successor.as_deref(), Some("first"));
+        assert!(rx_wcmd.try_recv().is_err());
+    }
+
+    #[test]
+    fn failed_capture_rendered() {
+        use google_tasks1::api::{TaskList, Tasks};
+
+        use crate::{
+            org::{MetaPendingContainer, ToOrg},
+            write::{TaskWrite, WriteCommand},
+        };
+
+        let tasklist = OrgTaskList::from((
+            TaskList {
+                id: Some("inbox".to_owned()),
+                ..TaskList::default()
+            },
+            Tasks::default(),
+        ));
+        let (tx_wcmd, mut rx_wcmd) = tokio::sync::mpsc::channel(1024);
+        assert!(tasklist.capture(&Org::parse("* TODO Buy milk\n"), &tx_wcmd));
+        let Ok(WriteCommand::Task {
+            cmd: TaskWrite::Insert(insert),
+            ..
+        }) = rx_wcmd.try_recv()
+        else {
+            panic!("expected an insert");
+        };
+        // kept pending, as when Google couldn't be reached
+        tasklist.push_pending_insert(insert);
+        assert!(tasklist.to_org_string().contains("* TODO Buy milk\n"));
+    }
+
+    #[test]
+    fn render_file_keywords() {
+        use google_tasks1::api::{TaskList, Tasks};
+
+        use crate::org::{MaybeIdMap, ToOrg};
+
+        let tasklist = OrgTaskList::from((
+            TaskList {
+                title: Some("My  Tasks".to_owned()),
+                ..TaskList::default()
+            },
+            Tasks {
+                items: Some(vec![Task {
+                    id: Some("task".to_owned()),
+                    title: Some("Title".to_owned()),
+                    position: Some("00000000000000000001".to_owned()),
+                    ..Task::default()
+                }]),
+                ..Tasks::default()
+            },
+        ));
+        let rendered = tasklist.to_org_string();
+        assert!(rendered.starts_with("#+TITLE: My  Tasks\n#+CATEGORY: My_Tasks\n\n* TODO Title\n"));
+        // the keywords aren't mistaken for an entry when the file is written back
+        assert_eq!(MaybeIdMap::from(&Org::parse(&rendered)).len(), 1);
+    }
+
+    #[test]
+    fn checkbox_style_round_trip() {
+        use super::{checkboxes_to_headlines, headlines_to_checkboxes};
+
+        let org = "#+TITLE: Chores\n\n* TODO Clean [1/2]\nDEADLINE: <2024-01-01 Mon>\n\
+                   :PROPERTIES:\n:id: a\n:END:\n\nKitchen first\n\n** DONE Sweep\n\
+                   CLOSED: [2024-01-01 Mon 10:00]\n** TODO Mop\n* TODO Shop\n";
+        let checkboxes = headlines_to_checkboxes(org);
+        assert_eq!(
+            checkboxes,
+            "#+TITLE: Chores\n\n- [ ] Clean [1/2]\n  DEADLINE: <2024-01-01 Mon>\n  \
+             :PROPERTIES:\n  :id: a\n  :END:\n\n  Kitchen first\n\n  - [X] Sweep\n    \
+             CLOSED: [2024-01-01 Mon 10:00]\n  - [ ] Mop\n- [ ] Shop\n"
+        );
+        assert_eq!(checkboxes_to_headlines(&checkboxes), org);
+
+        // ticking a box completes the task
+        let ticked = checkboxes.replace("- [ ] Mop", "- [x] Mop");
+        let org = Org::parse(checkboxes_to_headlines(&ticked));
+        let mop = org
+            .document()
+            .headlines()
+            .flat_map(|headline| headline.headlines().collect::<Vec<_>>())
+            .find(|headline| headline.title_raw() == "Mop")
+            .unwrap();
+        assert_eq!(
+            OrgTaskList::parse_task(&mop).status.as_deref(),
+            Some("completed")
+        );
+    }
+
+    #[test]
+    fn checkbox_style_notes_with_checklist() {
+        use super::{checkboxes_to_headlines, headlines_to_checkboxes};
+
+        let org = "* TODO Shop\n:PROPERTIES:\n:id: a\n:END:\n\n- [ ] milk\n- [X] eggs\n  \
+                   - [x] free range\n,- [ ] not a task\n** TODO Bake\n";
+        let checkboxes = headlines_to_checkboxes(org);
+        assert_eq!(
+            checkboxes,
+            "- [ ] Shop\n  :PROPERTIES:\n  :id: a\n  :END:\n\n  ,- [ ] milk\n  ,- [X] eggs\n    \
+             ,- [x] free range\n  ,,- [ ] not a task\n  - [ ] Bake\n"
+        );
+        // so saving the file unchanged makes no new tasks
+        assert_eq!(checkboxes_to_headlines(&checkboxes), org);
+    }
+
+    #[test]
+    fn statistics_cookie() {
+        use google_tasks1::api::{TaskList, Tasks};
+
+        use crate::org::ToOrg;
+
+        let task = |id: &str, parent: Option<&str>, status: &str| Task {
+            id: Some(id.to_owned()),
+            title: Some(id.to_owned()),
+            parent: parent.map(str::to_owned),
+            position: Some(format!("0000000000000000000{}", id.len())),
+            status: Some(status.to_owned()),
+            ..Task::default()
+        };
+        let tasklist = OrgTaskList::from((
+            TaskList::default(),
+            Tasks {
+                items: Some(vec![
+                    task("parent", None, "needsAction"),
+                    task("a", Some("parent"), "completed"),
+                    task("bb", Some("parent"), "needsAction"),
+                    task("ccc", Some("parent"), "needsAction"),
+                ]),
+                ..Tasks::default()
+            },
+        ));
+        let rendered = tasklist.to_org_string();
+        assert!(rendered.starts_with("* TODO parent [1/3]\n"));
+        assert!(rendered.contains("** TODO bb\n"));
+
+        // the cookie isn't written back as part of the title
+        let org = Org::parse(&rendered);
+        let headline = org.first_node::<Headline>().unwrap();
+        let task = OrgTaskList::parse_task(&headline);
+        assert_eq!(task.title.as_deref(), Some("parent"));
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn round_trip_task(
+            title in "[a-z0-9]([a-zA-Z0-9 ,.!?']{0,30}[a-zA-Z0-9.!?])?",
+            notes in proptest::option::of("[a-z0-9]([a-zA-Z0-9 ,.!?'\n]{0,60}[a-zA-Z0-9.!?])?"),
+            completed: bool,
+            // days, or for completion minutes, since 2000-01-01, up to 2040
+            due in proptest::option::of(0i64..40 * 365),
+            at in 0i64..40 * 365 * 24 * 60,
+        ) {
+            use chrono::{DateTime, NaiveDate, SecondsFormat, TimeDelta, TimeZone, Utc};
+
+            use crate::org::ByETag;
+
+            let epoch = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+            let completed_at = Utc.from_utc_datetime(&(epoch + TimeDelta::minutes(at)));
+            // a wall-clock time repeated by DST is read back as its first occurrence
+            let local = completed_at.with_timezone(&chrono::Local).naive_local();
+            proptest::prop_assume!(
+                !completed || chrono::Local.from_local_datetime(&local).single().is_some()
+            );
+            let task = Task {
+                id: Some("task".to_owned()),
+                etag: Some("\"LTEyMzQ1Njc4OQ\"".to_owned()),
+                title: Some(title),
+                notes,
+                status: Some(if completed { "completed" } else { "needsAction" }.to_owned()),
+                completed: completed
+                    .then(|| completed_at.to_rfc3339_opts(SecondsFormat::Millis, true)),
+                // the due date of a completed task isn't shown
+                due: due.filter(|_| !completed).map(|days| {
+                    Utc.from_utc_datetime(&(epoch + TimeDelta::days(days)))
+                        .to_rfc3339_opts(SecondsFormat::Millis, true)
+                }),
+                ..Task::default()
+            };
+            let rendered = super::render_task(&task, "* ".to_owned(), true, None);
+            let org = Org::parse(&rendered);
+            let parsed = OrgTaskList::parse_task(&org.first_node::<Headline>().unwrap());
+            proptest::prop_assert_eq!(&parsed.title, &task.title, "rendered as {}", rendered);
+            proptest::prop_assert_eq!(
+                parsed.notes.as_deref().unwrap_or_default(),
+                task.notes.as_deref().unwrap_or_default(),
+                "rendered as {}",
+                rendered
+            );
+            proptest::prop_assert_eq!(&parsed.status, &task.status);
+            proptest::prop_assert_eq!(&parsed.due, &task.due);
+            let instant = |at: &Option<String>| {
+                at.as_deref().map(|at| DateTime::parse_from_rfc3339(at).unwrap())
+            };
+            proptest::prop_assert_eq!(instant(&parsed.completed), instant(&task.completed));
+            proptest::prop_assert!(ByETag(parsed) == ByETag(task));
+        }
+    }
+}
@@ -1,6 +1,62 @@
-use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, TimeDelta, TimeZone};
 
-use crate::org::ToOrg;
+use crate::org::{render_options, ToOrg};
+
+/// Format `datetime` in the display timezone (`--tz`, or the system's).
+pub(crate) fn format_in_display_tz<Tz: TimeZone>(datetime: &DateTime<Tz>, fmt: &str) -> String {
+    format_in(datetime, render_options().tz, fmt)
+}
+
+fn format_in<Tz: TimeZone>(
+    datetime: &DateTime<Tz>,
+    tz: Option<chrono_tz::Tz>,
+    fmt: &str,
+) -> String {
+    match tz {
+        Some(tz) => datetime.with_timezone(&tz).format(fmt).to_string(),
+        None => datetime.with_timezone(&Local).format(fmt).to_string(),
+    }
+}
+
+/// The date `datetime` falls on in the display timezone.
+pub(crate) fn display_date<Tz: TimeZone>(datetime: &DateTime<Tz>) -> NaiveDate {
+    match render_options().tz {
+        Some(tz) => datetime.with_timezone(&tz).date_naive(),
+        None => datetime.with_timezone(&Local).date_naive(),
+    }
+}
+
+/// Interpret a wall-clock time read from an org file in the display timezone.
+pub(crate) fn from_display_tz(naive: NaiveDateTime) -> DateTime<FixedOffset> {
+    from_tz(naive, render_options().tz)
+}
+
+fn from_tz(naive: NaiveDateTime, tz: Option<chrono_tz::Tz>) -> DateTime<FixedOffset> {
+    match tz {
+        Some(tz) => resolve_local(naive, &tz).fixed_offset(),
+        None => resolve_local(naive, &Local).fixed_offset(),
+    }
+}
+
+/// The instant a wall-clock time in `tz` stands for, even across a DST transition: the earlier
+/// of a repeated time, or an hour later for a time skipped by the clocks going forward.
+pub(crate) fn resolve_local<Tz: TimeZone>(naive: NaiveDateTime, tz: &Tz) -> DateTime<Tz> {
+    tz.from_local_datetime(&naive)
+        .earliest()
+        .or_else(|| {
+            tz.from_local_datetime(&(naive + TimeDelta::hours(1)))
+                .earliest()
+        })
+        .unwrap_or_else(|| tz.from_utc_datetime(&naive))
+}
+
+/// IANA name of the display timezone, as sent to Google alongside wall-clock times.
+pub(crate) fn display_tz_name() -> Option<String> {
+    match render_options().tz {
+        Some(tz) => Some(tz.name().to_owned()),
+        None => iana_time_zone::get_timezone().ok(),
+    }
+}
 
 impl ToOrg for NaiveDate {
     fn to_org_string(&self) -> String {
@@ -10,9 +66,7 @@ impl ToOrg for NaiveDate {
 
 impl<Tz: TimeZone> ToOrg for DateTime<Tz> {
     fn to_org_string(&self) -> String {
-        self.with_timezone(&Local)
-            .format("%Y-%m-%d %a %H:%M")
-            .to_string()
+        format_in_display_tz(self, "%Y-%m-%d %a %H:%M")
     }
 }
 
@@ -62,9 +116,7 @@ impl Ord for Timestamp<Local> {
         );
         let self_inner = match self {
             Timestamp::ActiveDate(date) | Timestamp::InactiveDate(date) => {
-                NaiveDateTime::from(*date)
-                    .and_local_timezone(Local)
-                    .unwrap()
+                resolve_local(NaiveDateTime::from(*date), &Local)
             }
             Timestamp::ActiveDateTime(datetime) | Timestamp::InactiveDateTime(datetime) => {
                 *datetime
@@ -72,9 +124,7 @@ impl Ord for Timestamp<Local> {
         };
         let other_inner = match other {
             Timestamp::ActiveDate(date) | Timestamp::InactiveDate(date) => {
-                NaiveDateTime::from(*date)
-                    .and_local_timezone(Local)
-                    .unwrap()
+                resolve_local(NaiveDateTime::from(*date), &Local)
             }
             Timestamp::ActiveDateTime(datetime) | Timestamp::InactiveDateTime(datetime) => {
                 *datetime
@@ -136,3 +186,54 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, TimeZone, Utc};
+
+    #[test]
+    fn display_tz_override() {
+        let utc = Utc.with_ymd_and_hms(2024, 7, 1, 12, 0, 0).unwrap();
+        let london = Some(chrono_tz::Europe::London);
+        assert_eq!(
+            super::format_in(&utc, london, "%Y-%m-%d %a %H:%M"),
+            "2024-07-01 Mon 13:00"
+        );
+        let naive = NaiveDate::from_ymd_opt(2024, 7, 1)
+            .unwrap()
+            .and_hms_opt(13, 0, 0)
+            .unwrap();
+        assert_eq!(super::from_tz(naive, london), utc);
+    }
+
+    #[test]
+    fn wall_clock_across_dst() {
+        let london = Some(chrono_tz::Europe::London);
+        let at = |month, day, hour, min| {
+            NaiveDate::from_ymd_opt(2024, month, day)
+                .unwrap()
+                .and_hms_opt(hour, min, 0)
+                .unwrap()
+        };
+        // 01:30 on 31 March doesn't happen in London; it's read as 02:30 BST
+        assert_eq!(
+            super::from_tz(at(3, 31, 1, 30), london),
+            Utc.with_ymd_and_hms(2024, 3, 31, 1, 30, 0).unwrap()
+        );
+        // 01:30 on 27 October happens twice; the first, in BST, is meant
+        assert_eq!(
+            super::from_tz(at(10, 27, 1, 30), london),
+            Utc.with_ymd_and_hms(2024, 10, 27, 0, 30, 0).unwrap()
+        );
+        // midnight is skipped in Santiago when its clocks go forward
+        let santiago = chrono_tz::America::Santiago;
+        let midnight = NaiveDate::from_ymd_opt(2024, 9, 8)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        assert_eq!(
+            super::resolve_local(midnight, &santiago),
+            Utc.with_ymd_and_hms(2024, 9, 8, 4, 0, 0).unwrap()
+        );
+    }
+}
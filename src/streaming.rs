@@ -1,6 +1,19 @@
-pub(crate) fn string_to_digit_stream(str: &str) -> impl Iterator<Item = u8> + '_ {
-    str.bytes()
-        .map(|b| char::from(b).to_digit(10).unwrap() as u8)
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct NonDigit(pub(crate) char);
+
+impl std::fmt::Display for NonDigit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "non-decimal character {:?} in position", self.0)
+    }
+}
+
+impl std::error::Error for NonDigit {}
+
+pub(crate) fn string_to_digit_stream(str: &str) -> Result<impl Iterator<Item = u8> + '_, NonDigit> {
+    if let Some(c) = str.chars().find(|c| !c.is_ascii_digit()) {
+        return Err(NonDigit(c));
+    }
+    Ok(str.bytes().map(|b| b - b'0'))
 }
 
 pub(crate) fn digit_stream_to_string(digits: impl Iterator<Item = u8>) -> String {
@@ -19,10 +32,13 @@ pub(crate) fn streaming_midpoint(
     streaming_halve(streaming_add(x, y))
 }
 
+/// Add two decimal fractions digit by digit, most significant first; the shorter operand is
+/// treated as if padded with trailing zeros.
 pub(crate) fn streaming_add(
-    mut x: impl Iterator<Item = u8>,
-    mut y: impl Iterator<Item = u8>,
+    x: impl Iterator<Item = u8>,
+    y: impl Iterator<Item = u8>,
 ) -> impl Iterator<Item = u8> {
+    let (mut x, mut y) = (x.fuse(), y.fuse());
     let mut prev = None;
     let mut nines = 0;
     let mut overflown = false;
@@ -36,32 +52,32 @@ pub(crate) fn streaming_add(
             }
         } else {
             loop {
-                match (x.next(), y.next()) {
-                    (Some(x), Some(y)) => match x + y {
-                        0..9 => {
-                            overflown = false;
-                            let res = prev.replace(x + y);
-                            if res.is_none() {
-                                // first iteration
-                                continue;
-                            } else {
-                                break res;
-                            }
-                        }
-                        9 => {
-                            nines += 1;
-                        }
-                        10..19 => {
-                            overflown = true;
-                            break Some(prev.replace((x + y) % 10).unwrap_or_default() + 1);
-                        }
-                        _ => unreachable!(),
-                    },
+                let sum = match (x.next(), y.next()) {
                     (None, None) => {
                         overflown = false;
                         break prev.take();
                     }
-                    _ => unreachable!("x and y must have the same length"),
+                    (x, y) => x.unwrap_or_default() + y.unwrap_or_default(),
+                };
+                match sum {
+                    0..9 => {
+                        overflown = false;
+                        let res = prev.replace(sum);
+                        if res.is_none() {
+                            // first iteration
+                            continue;
+                        } else {
+                            break res;
+                        }
+                    }
+                    9 => {
+                        nines += 1;
+                    }
+                    10..19 => {
+                        overflown = true;
+                        break Some(prev.replace(sum % 10).unwrap_or_default() + 1);
+                    }
+                    _ => unreachable!(),
                 }
             }
         }
@@ -95,6 +111,15 @@ mod tests {
 
     use proptest::prelude::*;
 
+    #[test]
+    fn test_non_digit_position() {
+        assert_eq!(
+            string_to_digit_stream("0000a000").err(),
+            Some(NonDigit('a'))
+        );
+        assert!(string_to_digit_stream("").is_ok());
+    }
+
     proptest! {
         #[test]
         fn test_streaming_add(x in 0u32..10000, y in 0u32..10000) {
@@ -104,8 +129,8 @@ mod tests {
             let y_str = format!("{:0>5}", y);
             println!("   y: {}", y_str);
             let digits = streaming_add(
-                string_to_digit_stream(&x_str),
-                string_to_digit_stream(&y_str),
+                string_to_digit_stream(&x_str).unwrap(),
+                string_to_digit_stream(&y_str).unwrap(),
             );
             let res = digit_stream_to_string(digits);
             println!(" x+y: {}", res);
@@ -114,12 +139,31 @@ mod tests {
             prop_assert_eq!(res, real_str);
         }
 
+        #[test]
+        fn test_streaming_add_unequal(x in 0u32..1000, y in 0u32..100000) {
+            // as fractions, so the shorter operand is padded with zeros on the right
+            let x_str = format!("{:0>4}", x);
+            let y_str = format!("{:0>6}", y);
+            let digits = streaming_add(
+                string_to_digit_stream(&x_str).unwrap(),
+                string_to_digit_stream(&y_str).unwrap(),
+            );
+            let res = digit_stream_to_string(digits);
+            let real_str = format!("{:0>6}", x * 100 + y);
+            prop_assert_eq!(&res, &real_str);
+            let digits = streaming_add(
+                string_to_digit_stream(&y_str).unwrap(),
+                string_to_digit_stream(&x_str).unwrap(),
+            );
+            prop_assert_eq!(digit_stream_to_string(digits), real_str);
+        }
+
         #[test]
         fn test_streaming_halve(x in 0u32..20000) {
             println!();
             let x_str = format!("{:0>5}", x);
             println!("   x: {}", x_str);
-            let digits = streaming_halve(string_to_digit_stream(&x_str));
+            let digits = streaming_halve(string_to_digit_stream(&x_str).unwrap());
             let res = digit_stream_to_string(digits);
             println!(" x/2: {}", res);
             let real_str = format!("{:0>5}{}", x / 2, if x % 2 == 0 { "" } else { "5" });
@@ -135,8 +179,8 @@ mod tests {
             let y_str = format!("{:0>5}", init + diff);
             println!("      y: {}", x_str);
             let digits = streaming_midpoint(
-                string_to_digit_stream(&x_str),
-                string_to_digit_stream(&y_str),
+                string_to_digit_stream(&x_str).unwrap(),
+                string_to_digit_stream(&y_str).unwrap(),
             );
             let res = digit_stream_to_string(digits);
             println!("(x+y)/2: {}", res);
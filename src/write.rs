@@ -1,19 +1,31 @@
-use std::{sync::atomic::Ordering, time::SystemTime};
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::SystemTime,
+};
 
+use futures::{stream, StreamExt};
 use google_calendar3::api::{Event, EventDateTime};
 use google_tasks1::api::Task;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::{
     client,
-    org::{calendar::OrgCalendar, tasklist::OrgTaskList, MetaPendingContainer},
+    journal::Journal,
+    org::{
+        calendar::OrgCalendar,
+        tasklist::{OrgTaskList, POSITION_LEN},
+        MetaPendingContainer,
+    },
     streaming::{digit_stream_to_string, streaming_midpoint, string_to_digit_stream},
     update_calendar, update_tasklist,
 };
 
 // trick vim into reloading
 const TOUCH_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+// requests in flight at once when flushing pending writes, to stay clear of rate limits
+const MAX_CONCURRENT_WRITES: usize = 8;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) enum WriteCommand {
     CalendarEvent {
         calendar_id: String,
@@ -37,7 +49,17 @@ pub(crate) enum WriteCommand {
     },
 }
 
-#[derive(Debug, Clone)]
+impl WriteCommand {
+    /// Whether this command changes remote state (as opposed to syncing or touching).
+    pub(crate) fn is_write(&self) -> bool {
+        matches!(
+            self,
+            WriteCommand::CalendarEvent { .. } | WriteCommand::Task { .. }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) enum CalendarEventWrite {
     Insert(CalendarEventInsert),
     Modify {
@@ -46,7 +68,7 @@ pub(crate) enum CalendarEventWrite {
     },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) enum CalendarEventInsert {
     Insert { event: Box<Event> },
 }
@@ -76,7 +98,7 @@ impl PartialEq for CalendarEventInsert {
                     && event1.color_id == event2.color_id
                     && event1.location == event2.location
                     && event1.status == event2.status
-                    && event1.status == event2.transparency
+                    && event1.transparency == event2.transparency
             }
         }
     }
@@ -112,13 +134,13 @@ impl std::hash::Hash for CalendarEventInsert {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) enum CalendarEventModify {
     Patch { event: Box<Event> },
     Delete,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) enum TaskWrite {
     Insert(TaskInsert),
     Modify {
@@ -133,7 +155,7 @@ pub(crate) enum TaskWrite {
     },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) enum TaskInsert {
     Insert {
         task: Box<Task>,
@@ -197,60 +219,143 @@ impl std::hash::Hash for TaskInsert {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) enum TaskModify {
     Patch { task: Box<Task> },
     Delete,
 }
 
+// stand-in for the id the server would assign to an insert
+fn dry_run_id() -> String {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    format!("dry-run-{}", NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+// what the server would return after patching `old` with the fields set in `patch`
+fn apply_patch<T: Serialize + DeserializeOwned>(old: &T, patch: &T) -> T {
+    let mut new = serde_json::to_value(old).expect("Failed to serialize");
+    if let (Some(new), serde_json::Value::Object(patch)) = (
+        new.as_object_mut(),
+        serde_json::to_value(patch).expect("Failed to serialize"),
+    ) {
+        new.extend(patch.into_iter().filter(|(_, v)| !v.is_null()));
+    }
+    serde_json::from_value(new).expect("Failed to deserialize patched value")
+}
+
 async fn process_calendar_write(
-    client: &client::GoogleClient,
+    client: &impl client::GoogleApi,
     calendar: &OrgCalendar,
     cmd: CalendarEventWrite,
 ) {
     let calendar_id = calendar.with_meta(|m| m.calendar().id.clone()).unwrap();
+    if !calendar.is_writable() {
+        // the server would refuse it every time, so don't keep it pending
+        tracing::error!(%calendar_id, ?cmd, "Dropping write to read-only calendar");
+        return;
+    }
     match cmd {
         CalendarEventWrite::Insert(CalendarEventInsert::Insert { event }) => {
-            if let Ok(new) = client.insert_event(&calendar_id, *event.clone()).await {
-                let id = new
-                    .id
-                    .clone()
-                    .expect("Server returned inserted event with no id");
-                tracing::debug!("Inserted event with id: {}", id);
-                calendar.add_id(&id, new);
+            let result = if client.dry_run() {
+                tracing::info!(%calendar_id, ?event, "Dry run: would insert event");
+                Ok(Event {
+                    id: Some(dry_run_id()),
+                    ..*event.clone()
+                })
             } else {
-                calendar.push_pending_insert(CalendarEventInsert::Insert { event });
+                client.insert_event(&calendar_id, *event.clone()).await
+            };
+            let title = event_title(&event);
+            match result {
+                Ok(new) => {
+                    let id = new
+                        .id
+                        .clone()
+                        .expect("Server returned inserted event with no id");
+                    tracing::debug!(%calendar_id, event_id = %id, "Inserted event");
+                    calendar.write_errors().clear(&title);
+                    calendar.add_id(&id, new);
+                }
+                Err(e) => {
+                    tracing::error!(%calendar_id, error = %e, "Failed to insert event; saving");
+                    calendar.write_errors().record(&title, &title, &e);
+                    calendar.push_pending_insert(CalendarEventInsert::Insert { event });
+                }
             }
         }
         CalendarEventWrite::Modify {
             event_id,
             modification: CalendarEventModify::Patch { event },
         } => {
-            if let Ok(new) = client
-                .patch_event(&calendar_id, &event_id, *event.clone())
-                .await
-            {
-                tracing::debug!("Updated event with id: {}", event_id);
-                calendar.update_id(&event_id, new);
+            let result = if client.dry_run() {
+                tracing::info!(%calendar_id, %event_id, ?event, "Dry run: would patch event");
+                let old = calendar.get_id(&event_id).map(|old| old.0);
+                Ok(apply_patch(&old.unwrap_or_default(), &event))
             } else {
-                calendar.push_pending_modify(event_id, CalendarEventModify::Patch { event });
+                client
+                    .patch_event(&calendar_id, &event_id, *event.clone())
+                    .await
+            };
+            match result {
+                Ok(new) => {
+                    tracing::debug!(%calendar_id, %event_id, "Updated event");
+                    calendar.write_errors().clear(&event_id);
+                    calendar.update_id(&event_id, new);
+                }
+                Err(e) => {
+                    tracing::error!(
+                        %calendar_id,
+                        %event_id,
+                        error = %e,
+                        "Failed to update event; saving"
+                    );
+                    let title = calendar.get_id(&event_id).map(|old| event_title(&old.0));
+                    calendar.write_errors().record(
+                        &event_id,
+                        title.as_deref().unwrap_or(&event_id),
+                        &e,
+                    );
+                    calendar.push_pending_modify(event_id, CalendarEventModify::Patch { event });
+                }
             }
         }
         CalendarEventWrite::Modify {
             event_id,
             modification: CalendarEventModify::Delete,
         } => {
-            if let Ok(()) = client.delete_event(&calendar_id, &event_id).await {
-                calendar.delete_id(&event_id);
+            let result = if client.dry_run() {
+                tracing::info!(%calendar_id, %event_id, "Dry run: would delete event");
+                Ok(())
             } else {
-                calendar.push_pending_modify(event_id, CalendarEventModify::Delete);
+                client.delete_event(&calendar_id, &event_id).await
+            };
+            match result {
+                Ok(()) => {
+                    calendar.write_errors().clear(&event_id);
+                    calendar.delete_id(&event_id);
+                }
+                Err(e) => {
+                    tracing::error!(
+                        %calendar_id,
+                        %event_id,
+                        error = %e,
+                        "Failed to delete event; saving"
+                    );
+                    let title = calendar.get_id(&event_id).map(|old| event_title(&old.0));
+                    calendar.write_errors().record(
+                        &event_id,
+                        title.as_deref().unwrap_or(&event_id),
+                        &e,
+                    );
+                    calendar.push_pending_modify(event_id, CalendarEventModify::Delete);
+                }
             }
         }
     }
 }
 
 async fn process_tasklist_write(
-    client: &client::GoogleClient,
+    client: &impl client::GoogleApi,
     tasklist: &OrgTaskList,
     cmd: TaskWrite,
 ) {
@@ -262,37 +367,60 @@ async fn process_tasklist_write(
             new_predecessor,
             new_successor,
         }) => {
-            if let Ok(mut new) = client
-                .insert_task(
-                    &tasklist_id,
-                    *task.clone(),
-                    new_parent.as_deref(),
-                    new_predecessor.as_deref(),
-                )
-                .await
-            {
-                let position = create_position(
-                    new.id.as_ref().unwrap(),
-                    &new_parent,
-                    &new_predecessor,
-                    &new_successor,
-                    tasklist,
+            let result = if client.dry_run() {
+                tracing::info!(
+                    %tasklist_id,
+                    ?new_parent,
+                    ?new_predecessor,
+                    ?task,
+                    "Dry run: would insert task"
                 );
-                new.position = position;
-                let id = new
-                    .id
-                    .clone()
-                    .expect("Server returned inserted task with no id");
-                tracing::debug!("Inserted task with id: {}", id);
-                tasklist.add_id(&id, new);
+                Ok(Task {
+                    id: Some(dry_run_id()),
+                    parent: new_parent.clone(),
+                    ..*task.clone()
+                })
             } else {
-                tracing::error!("Failed to insert task; saving");
-                tasklist.push_pending_insert(TaskInsert::Insert {
-                    task,
-                    new_parent,
-                    new_predecessor,
-                    new_successor,
-                });
+                client
+                    .insert_task(
+                        &tasklist_id,
+                        *task.clone(),
+                        new_parent.as_deref(),
+                        new_predecessor.as_deref(),
+                    )
+                    .await
+            };
+            let title = task_title(&task);
+            match result {
+                Ok(mut new) => {
+                    let position = create_position(
+                        new.id.as_ref().unwrap(),
+                        &new_parent,
+                        &new_predecessor,
+                        &new_successor,
+                        tasklist,
+                    );
+                    // fall back to the server's position when there is nothing to place it
+                    // against
+                    new.position = position.or(new.position);
+                    let id = new
+                        .id
+                        .clone()
+                        .expect("Server returned inserted task with no id");
+                    tracing::debug!(%tasklist_id, task_id = %id, "Inserted task");
+                    tasklist.write_errors().clear(&title);
+                    tasklist.add_id(&id, new);
+                }
+                Err(e) => {
+                    tracing::error!(%tasklist_id, error = %e, "Failed to insert task; saving");
+                    tasklist.write_errors().record(&title, &title, &e);
+                    tasklist.push_pending_insert(TaskInsert::Insert {
+                        task,
+                        new_parent,
+                        new_predecessor,
+                        new_successor,
+                    });
+                }
             }
         }
         TaskWrite::Move {
@@ -301,70 +429,187 @@ async fn process_tasklist_write(
             new_predecessor,
             new_successor,
         } => {
-            if let Ok(mut new) = client
-                .move_task(
-                    &tasklist_id,
-                    &task_id,
-                    new_parent.as_deref(),
-                    new_predecessor.as_deref(),
-                )
-                .await
-            {
-                tracing::debug!("Moved task with id: {}", task_id);
-                let position = create_position(
-                    &task_id,
-                    &new_parent,
-                    &new_predecessor,
-                    &new_successor,
-                    tasklist,
+            let result = if client.dry_run() {
+                tracing::info!(
+                    %tasklist_id,
+                    %task_id,
+                    ?new_parent,
+                    ?new_predecessor,
+                    "Dry run: would move task"
                 );
-                new.position = position;
-                tasklist.update_id(&task_id, new);
+                let old = tasklist.get_id(&task_id).map(|old| old.0);
+                Ok(Task {
+                    parent: new_parent.clone(),
+                    ..old.unwrap_or_default()
+                })
             } else {
-                tracing::error!("Failed to move task with id: {}", task_id);
-                // TODO: push a move operation to pending modifies; this probably isn't worth
-                // rendering as a conflict
+                client
+                    .move_task(
+                        &tasklist_id,
+                        &task_id,
+                        new_parent.as_deref(),
+                        new_predecessor.as_deref(),
+                    )
+                    .await
+            };
+            match result {
+                Ok(mut new) => {
+                    tracing::debug!(%tasklist_id, %task_id, "Moved task");
+                    let position = create_position(
+                        &task_id,
+                        &new_parent,
+                        &new_predecessor,
+                        &new_successor,
+                        tasklist,
+                    );
+                    new.position = position;
+                    tasklist.update_id(&task_id, new);
+                }
+                Err(e) => {
+                    tracing::error!(%tasklist_id, %task_id, error = %e, "Failed to move task");
+                    // TODO: push a move operation to pending modifies; this probably isn't worth
+                    // rendering as a conflict
+                }
             }
         }
         TaskWrite::Modify {
             task_id,
-            modification: TaskModify::Patch { task },
+            modification: TaskModify::Patch { mut task },
         } => {
-            if let Ok(mut new) = client
-                .patch_task(&tasklist_id, &task_id, *task.clone())
-                .await
-            {
-                new.position = task.position;
-                tracing::debug!("Updated task with id: {}", task_id);
-                tasklist.update_id(&task_id, new);
+            // a patch leaves out what it has no value for, so emptying a task's body has to
+            // clear its notes explicitly
+            let had_notes = tasklist
+                .get_id(&task_id)
+                .is_some_and(|old| old.0.notes.as_deref().is_some_and(|n| !n.is_empty()));
+            if task.notes.is_none() && had_notes {
+                task.notes = Some(String::new());
+            }
+            let result = if client.dry_run() {
+                tracing::info!(%tasklist_id, %task_id, ?task, "Dry run: would patch task");
+                let old = tasklist.get_id(&task_id).map(|old| old.0);
+                Ok(apply_patch(&old.unwrap_or_default(), &task))
             } else {
-                tracing::error!("Failed to update task with id: {}; saving", task_id);
-                tasklist.push_pending_modify(task_id, TaskModify::Patch { task });
+                client
+                    .patch_task(&tasklist_id, &task_id, *task.clone())
+                    .await
+            };
+            match result {
+                Ok(mut new) => {
+                    new.position = task.position;
+                    tracing::debug!(%tasklist_id, %task_id, "Updated task");
+                    tasklist.write_errors().clear(&task_id);
+                    tasklist.update_id(&task_id, new);
+                }
+                Err(e) => {
+                    tracing::error!(
+                        %tasklist_id,
+                        %task_id,
+                        error = %e,
+                        "Failed to update task; saving"
+                    );
+                    let title = tasklist.get_id(&task_id).map(|old| task_title(&old.0));
+                    tasklist.write_errors().record(
+                        &task_id,
+                        title.as_deref().unwrap_or(&task_id),
+                        &e,
+                    );
+                    tasklist.push_pending_modify(task_id, TaskModify::Patch { task });
+                }
             }
         }
         TaskWrite::Modify {
             task_id,
             modification: TaskModify::Delete,
         } => {
-            if let Ok(()) = client.delete_task(&tasklist_id, &task_id).await {
-                tasklist.delete_id(&task_id);
+            let result = if client.dry_run() {
+                tracing::info!(%tasklist_id, %task_id, "Dry run: would delete task");
+                Ok(())
             } else {
-                tracing::error!("Failed to delete task with id: {}; saving", task_id);
-                tasklist.push_pending_modify(task_id, TaskModify::Delete);
+                client.delete_task(&tasklist_id, &task_id).await
+            };
+            match result {
+                Ok(()) => {
+                    tasklist.write_errors().clear(&task_id);
+                    tasklist.delete_id(&task_id);
+                }
+                Err(e) => {
+                    tracing::error!(
+                        %tasklist_id,
+                        %task_id,
+                        error = %e,
+                        "Failed to delete task; saving"
+                    );
+                    let title = tasklist.get_id(&task_id).map(|old| task_title(&old.0));
+                    tasklist.write_errors().record(
+                        &task_id,
+                        title.as_deref().unwrap_or(&task_id),
+                        &e,
+                    );
+                    tasklist.push_pending_modify(task_id, TaskModify::Delete);
+                }
             }
         }
     }
 }
 
+// how a write's item is named in the error shown if it keeps failing
+fn event_title(event: &Event) -> String {
+    format!(
+        "\"{}\"",
+        event.summary.as_deref().unwrap_or("Untitled Event")
+    )
+}
+
+fn task_title(task: &Task) -> String {
+    format!("\"{}\"", task.title.as_deref().unwrap_or_default())
+}
+
+// rebalance siblings once a midpoint grows this many digits past Google's positions
+const MAX_POSITION_GROWTH: usize = 4;
+
 fn create_position(
     task_id: &String,
     new_parent: &Option<String>,
     new_predecessor: &Option<String>,
     new_successor: &Option<String>,
     tasklist: &OrgTaskList,
+) -> Option<String> {
+    let position = midpoint_position(
+        task_id,
+        new_parent,
+        new_predecessor,
+        new_successor,
+        tasklist,
+    )?;
+    if position.len() <= POSITION_LEN + MAX_POSITION_GROWTH {
+        return Some(position);
+    }
+    tasklist.rebalance_positions(new_parent.as_deref(), task_id);
+    midpoint_position(
+        task_id,
+        new_parent,
+        new_predecessor,
+        new_successor,
+        tasklist,
+    )
+}
+
+fn midpoint_position(
+    task_id: &String,
+    new_parent: &Option<String>,
+    new_predecessor: &Option<String>,
+    new_successor: &Option<String>,
+    tasklist: &OrgTaskList,
 ) -> Option<String> {
     // modify the task to cheat and keep the global order correct; proper indices are
     // restored on the next sync
+    fn digits<'a>(task_id: &str, position: &'a str) -> Option<impl Iterator<Item = u8> + 'a> {
+        string_to_digit_stream(position)
+            .inspect_err(
+                |e| tracing::warn!(%task_id, error = %e, ?position, "Not positioning task"),
+            )
+            .ok()
+    }
     match (
         new_parent.as_deref(),
         new_predecessor.as_deref(),
@@ -375,14 +620,8 @@ fn create_position(
             let p = &tasklist.get_id(pred).expect("Task not found").0.position?;
             let n = &tasklist.get_id(succ).expect("Task not found").0.position?;
             let midpoint = digit_stream_to_string(streaming_midpoint(
-                std::iter::chain(
-                    string_to_digit_stream(p),
-                    std::iter::repeat_n(0, n.len().saturating_sub(p.len())),
-                ),
-                std::iter::chain(
-                    string_to_digit_stream(n),
-                    std::iter::repeat_n(0, p.len().saturating_sub(n.len())),
-                ),
+                digits(task_id, p)?,
+                digits(task_id, n)?,
             ));
             Some(midpoint)
         }
@@ -390,7 +629,7 @@ fn create_position(
             tracing::debug!("Put task {} after {}", task_id, pred);
             let p = &tasklist.get_id(pred).expect("Task not found").0.position?;
             let next = digit_stream_to_string(streaming_midpoint(
-                string_to_digit_stream(p),
+                digits(task_id, p)?,
                 std::iter::repeat_n(9, p.len()),
             ));
             Some(next)
@@ -400,20 +639,100 @@ fn create_position(
             let n = &tasklist.get_id(succ).expect("Task not found").0.position?;
             let prev = digit_stream_to_string(streaming_midpoint(
                 std::iter::repeat_n(0, n.len()),
-                string_to_digit_stream(n),
+                digits(task_id, n)?,
             ));
             Some(prev)
         }
         (None, None, None) => {
-            unreachable!("failed to create new position: must have at least a predecessor or successor or parent");
+            // the only top-level task, e.g. the first capture into an empty list
+            tracing::debug!("Put task {} alone at the top level", task_id);
+            None
         }
     }
 }
 
+/// Process any queued write commands and flush every pending write, giving up after `limit`.
+pub(super) async fn flush_pending(
+    client: &impl client::GoogleApi,
+    calendars: &[OrgCalendar],
+    tasklists: &[OrgTaskList],
+    rx_wcmd: &mut tokio::sync::mpsc::Receiver<WriteCommand>,
+    journal: &Journal,
+    limit: std::time::Duration,
+) {
+    let count_pending = || {
+        calendars
+            .iter()
+            .map(|cal| cal.with_pending(|p| p.0.len() + p.1.len()))
+            .sum::<usize>()
+            + tasklists
+                .iter()
+                .map(|tl| tl.with_pending(|p| p.0.len() + p.1.len()))
+                .sum::<usize>()
+    };
+
+    let mut queued = std::collections::VecDeque::new();
+    while let Ok(wcmd) = rx_wcmd.try_recv() {
+        if wcmd.is_write() {
+            journal.append(&wcmd);
+        }
+        queued.push_back(wcmd);
+    }
+    let before = count_pending() + queued.iter().filter(|wcmd| wcmd.is_write()).count();
+    if before == 0 {
+        return;
+    }
+    tracing::info!("Flushing {} pending writes before exiting…", before);
+
+    let flush = async {
+        while let Some(wcmd) = queued.pop_front() {
+            process_write(client, calendars, tasklists, wcmd).await;
+        }
+        for calendar in calendars {
+            if calendar.with_pending(|p| !(p.0.is_empty() && p.1.is_empty())) {
+                let calendar_id = calendar.with_meta(|m| m.calendar().id.clone()).unwrap();
+                process_write(
+                    client,
+                    calendars,
+                    tasklists,
+                    WriteCommand::SyncCalendar { calendar_id },
+                )
+                .await;
+            }
+        }
+        for tasklist in tasklists {
+            if tasklist.with_pending(|p| !(p.0.is_empty() && p.1.is_empty())) {
+                let tasklist_id = tasklist.with_meta(|m| m.tasklist().id.clone()).unwrap();
+                process_write(
+                    client,
+                    calendars,
+                    tasklists,
+                    WriteCommand::SyncTasklist { tasklist_id },
+                )
+                .await;
+            }
+        }
+    };
+    if tokio::time::timeout(limit, flush).await.is_err() {
+        tracing::warn!("Timed out flushing pending writes");
+    }
+    journal.compact(calendars, tasklists);
+
+    let abandoned = count_pending() + queued.iter().filter(|wcmd| wcmd.is_write()).count();
+    if abandoned == 0 {
+        tracing::info!("Flushed all {} pending writes", before);
+    } else {
+        tracing::error!(
+            "Flushed {} pending writes, abandoned {}",
+            before.saturating_sub(abandoned),
+            abandoned
+        );
+    }
+}
+
 pub(super) async fn process_write(
-    client: &client::GoogleClient,
+    client: &impl client::GoogleApi,
     calendars: &[OrgCalendar],
-    sync_tokens: &mut [(String, Option<String>)],
     tasklists: &[OrgTaskList],
     cmd: WriteCommand,
 ) {
@@ -430,45 +749,51 @@ pub(super) async fn process_write(
                 .iter()
                 .find(|cal| cal.with_meta(|m| m.calendar().id.as_ref() == Some(&calendar_id)))
                 .expect("Calendar not found");
-            let sync_token = sync_tokens
-                .iter_mut()
-                .find(|(id, _)| id == &calendar_id)
-                .and_then(|(_, token)| token.as_mut());
 
             // try to flush our pending writes
             if calendar.with_pending(|p| !(p.0.is_empty() && p.1.is_empty())) {
-                tracing::debug!("Flushing pending writes for calendar {}", calendar_id);
+                tracing::debug!(%calendar_id, "Flushing pending writes for calendar");
                 let old_meta = calendar.clear_pending();
                 let pending = old_meta.pending();
-                for insert in &pending.0 {
-                    process_calendar_write(
-                        client,
-                        calendar,
-                        CalendarEventWrite::Insert(insert.clone()),
-                    )
+                stream::iter(&pending.0)
+                    .for_each_concurrent(MAX_CONCURRENT_WRITES, |insert| {
+                        process_calendar_write(
+                            client,
+                            calendar,
+                            CalendarEventWrite::Insert(insert.clone()),
+                        )
+                    })
                     .await;
-                }
-                for (event_id, modification) in &pending.1 {
-                    process_calendar_write(
-                        client,
-                        calendar,
-                        CalendarEventWrite::Modify {
-                            event_id: event_id.clone(),
-                            modification: modification.clone(),
-                        },
-                    )
+                stream::iter(&pending.1)
+                    .for_each_concurrent(MAX_CONCURRENT_WRITES, |(event_id, modification)| {
+                        process_calendar_write(
+                            client,
+                            calendar,
+                            CalendarEventWrite::Modify {
+                                event_id: event_id.clone(),
+                                modification: modification.clone(),
+                            },
+                        )
+                    })
                     .await;
-                }
             }
 
-            let next_sync_token = update_calendar(client, calendar, sync_token.as_deref())
-                .await
-                .unwrap_or_else(|e| {
-                    tracing::error!("Failed to sync calendar {}: {}", calendar_id, e);
-                    None
-                });
-            if let (Some(sync_token), Some(next_sync_token)) = (sync_token, next_sync_token) {
-                *sync_token = next_sync_token;
+            let sync_token = calendar.with_meta(|m| m.sync_token().lock().unwrap().clone());
+            match update_calendar(client, calendar, sync_token.as_ref()).await {
+                Ok(Some(next_sync_token)) => {
+                    calendar.with_meta(|m| *m.sync_token().lock().unwrap() = Some(next_sync_token));
+                }
+                Ok(None) => {}
+                Err(e) if client::is_not_found(&e) => {
+                    tracing::warn!(%calendar_id, "Calendar was deleted upstream, removing it");
+                    calendar.mark_removed();
+                }
+                Err(e) => {
+                    tracing::error!(%calendar_id, error = %e, "Failed to sync calendar");
+                    if client.classify(&e) == client::ClientError::Auth {
+                        client.reauthenticate().await;
+                    }
+                }
             }
         }
         WriteCommand::TouchCalendar { calendar_id } => {
@@ -496,28 +821,40 @@ pub(super) async fn process_write(
 
             // try to flush our pending writes
             if tasklist.with_pending(|p| !(p.0.is_empty() && p.1.is_empty())) {
-                tracing::debug!("Flushing pending writes for tasklist {}", tasklist_id);
+                tracing::debug!(%tasklist_id, "Flushing pending writes for tasklist");
                 let old_meta = tasklist.clear_pending();
                 let pending = old_meta.pending();
+                // inserts are positioned relative to their siblings, so keep them in order
                 for insert in &pending.0 {
                     process_tasklist_write(client, tasklist, TaskWrite::Insert(insert.clone()))
                         .await;
                 }
-                for (task_id, modification) in &pending.1 {
-                    process_tasklist_write(
-                        client,
-                        tasklist,
-                        TaskWrite::Modify {
-                            task_id: task_id.clone(),
-                            modification: modification.clone(),
-                        },
-                    )
+                stream::iter(&pending.1)
+                    .for_each_concurrent(MAX_CONCURRENT_WRITES, |(task_id, modification)| {
+                        process_tasklist_write(
+                            client,
+                            tasklist,
+                            TaskWrite::Modify {
+                                task_id: task_id.clone(),
+                                modification: modification.clone(),
+                            },
+                        )
+                    })
                     .await;
-                }
             }
 
-            if let Err(e) = update_tasklist(client, tasklist).await {
-                tracing::error!("Failed to sync tasklist {}: {}", tasklist_id, e);
+            match update_tasklist(client, tasklist).await {
+                Ok(()) => {}
+                Err(e) if client::is_not_found(&e) => {
+                    tracing::warn!(%tasklist_id, "Tasklist was deleted upstream, removing it");
+                    tasklist.mark_removed();
+                }
+                Err(e) => {
+                    tracing::error!(%tasklist_id, error = %e, "Failed to sync tasklist");
+                    if client.classify(&e) == client::ClientError::Auth {
+                        client.reauthenticate().await;
+                    }
+                }
             }
         }
         WriteCommand::TouchTasklist { tasklist_id } => {
@@ -532,3 +869,159 @@ pub(super) async fn process_write(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use google_tasks1::api::{Task, TaskList, Tasks};
+    use itertools::Itertools;
+
+    use super::{create_position, MAX_POSITION_GROWTH};
+    use crate::org::{
+        tasklist::{OrgTaskList, POSITION_LEN},
+        MetaPendingContainer,
+    };
+
+    #[test]
+    fn malformed_position() {
+        let task = |id: &str, position: &str| Task {
+            id: Some(id.to_owned()),
+            position: Some(position.to_owned()),
+            ..Task::default()
+        };
+        let tasklist = OrgTaskList::from((
+            TaskList::default(),
+            Tasks {
+                items: Some(vec![
+                    task("good", "00000000000000000001"),
+                    task("bad", "0000000000000000000x"),
+                ]),
+                ..Tasks::default()
+            },
+        ));
+        let new = "new".to_owned();
+        let [good, bad] = [Some("good".to_owned()), Some("bad".to_owned())];
+        assert_eq!(create_position(&new, &None, &good, &bad, &tasklist), None);
+        assert_eq!(create_position(&new, &None, &bad, &None, &tasklist), None);
+        assert_eq!(create_position(&new, &None, &None, &bad, &tasklist), None);
+        assert!(create_position(&new, &None, &good, &None, &tasklist).is_some());
+    }
+
+    #[test]
+    fn bounded_positions() {
+        let task = |id: &str, position: &str| Task {
+            id: Some(id.to_owned()),
+            position: Some(position.to_owned()),
+            ..Task::default()
+        };
+        let tasklist = OrgTaskList::from((
+            TaskList::default(),
+            Tasks {
+                items: Some(vec![
+                    task("first", "00000000000000000001"),
+                    task("last", "00000000000000000002"),
+                ]),
+                ..Tasks::default()
+            },
+        ));
+        // keep inserting directly after `first`, so every midpoint is narrower than the last
+        let first = Some("first".to_owned());
+        let mut successor = Some("last".to_owned());
+        for i in 0..100 {
+            let id = format!("new{i}");
+            let position = create_position(&id, &None, &first, &successor, &tasklist)
+                .expect("failed to create position");
+            assert!(position.len() <= POSITION_LEN + MAX_POSITION_GROWTH);
+            tasklist.add_id(&id, task(&id, &position));
+            successor = Some(id);
+        }
+
+        let order: Vec<_> = tasklist
+            .read()
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, tasks)| (tasks.get_one().unwrap().0.position.clone(), id.clone()))
+            .sorted()
+            .map(|(_, id)| id)
+            .collect();
+        let expected: Vec<_> = std::iter::once("first".to_owned())
+            .chain((0..100).rev().map(|i| format!("new{i}")))
+            .chain(std::iter::once("last".to_owned()))
+            .collect();
+        assert_eq!(order, expected);
+    }
+
+    #[test]
+    fn apply_patch() {
+        let old = Task {
+            id: Some("task".to_owned()),
+            title: Some("old title".to_owned()),
+            notes: Some("notes".to_owned()),
+            ..Task::default()
+        };
+        let patch = Task {
+            title: Some("new title".to_owned()),
+            ..Task::default()
+        };
+        let new = super::apply_patch(&old, &patch);
+        assert_eq!(new.id.as_deref(), Some("task"));
+        assert_eq!(new.title.as_deref(), Some("new title"));
+        assert_eq!(new.notes.as_deref(), Some("notes"));
+    }
+
+    #[test]
+    fn emptied_notes_cleared() {
+        use super::{process_write, TaskModify, TaskWrite, WriteCommand};
+        use crate::client::MockClient;
+
+        let task = |id: &str, notes: Option<&str>| Task {
+            id: Some(id.to_owned()),
+            title: Some(id.to_owned()),
+            notes: notes.map(str::to_owned),
+            ..Task::default()
+        };
+        let tasklist = OrgTaskList::from((
+            TaskList {
+                id: Some("list".to_owned()),
+                ..TaskList::default()
+            },
+            Tasks {
+                items: Some(vec![task("noted", Some("notes")), task("bare", None)]),
+                ..Tasks::default()
+            },
+        ));
+        let client = MockClient::default();
+        for id in ["noted", "bare"] {
+            let wcmd = WriteCommand::Task {
+                tasklist_id: "list".to_owned(),
+                cmd: TaskWrite::Modify {
+                    task_id: id.to_owned(),
+                    modification: TaskModify::Patch {
+                        task: Box::new(task(id, None)),
+                    },
+                },
+            };
+            let tasklists = [tasklist.clone()];
+            futures::executor::block_on(process_write(&client, &[], &tasklists, wcmd));
+        }
+        let notes = client
+            .writes
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|wcmd| match wcmd {
+                WriteCommand::Task {
+                    cmd:
+                        TaskWrite::Modify {
+                            modification: TaskModify::Patch { task },
+                            ..
+                        },
+                    ..
+                } => task.notes,
+                wcmd => panic!("expected a task patch, got {wcmd:?}"),
+            })
+            .collect::<Vec<_>>();
+        // only the task which had notes needs them cleared
+        assert_eq!(notes, [Some(String::new()), None]);
+    }
+}